@@ -0,0 +1,2 @@
+-- The Go backend's column types are not restored.
+SELECT 1;
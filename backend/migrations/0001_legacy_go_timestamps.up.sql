@@ -0,0 +1,32 @@
+-- Tables created by the old Go backend have timestamps without a time
+-- zone. Only columns that still have that type are converted, so this is a
+-- no-op on databases created by this backend.
+DO $$
+DECLARE
+    col RECORD;
+BEGIN
+    FOR col IN
+        SELECT table_name, column_name
+        FROM information_schema.columns
+        WHERE table_schema = current_schema()
+          AND data_type = 'timestamp without time zone'
+          AND (table_name, column_name) IN (
+              ('users', 'created_at'),
+              ('settings', 'updated_at'),
+              ('uploads', 'created_at'),
+              ('uploads', 'expires_at'),
+              ('uploads', 'deleted_at'),
+              ('reverse_share_tokens', 'created_at'),
+              ('reverse_share_tokens', 'expires_at'),
+              ('deletion_logs', 'uploaded_at'),
+              ('deletion_logs', 'deleted_at'),
+              ('deletion_logs', 'expires_at')
+          )
+    LOOP
+        EXECUTE format(
+            'ALTER TABLE %I ALTER COLUMN %I TYPE TIMESTAMP WITH TIME ZONE',
+            col.table_name, col.column_name
+        );
+    END LOOP;
+END
+$$;
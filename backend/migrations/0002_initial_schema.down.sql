@@ -0,0 +1,5 @@
+DROP TABLE IF EXISTS deletion_logs;
+DROP TABLE IF EXISTS reverse_share_tokens;
+DROP TABLE IF EXISTS uploads;
+DROP TABLE IF EXISTS settings;
+DROP TABLE IF EXISTS users;
@@ -0,0 +1,15 @@
+-- One row per stored file of an upload
+CREATE TABLE IF NOT EXISTS upload_files (
+    id SERIAL PRIMARY KEY,
+    upload_id VARCHAR(255) NOT NULL REFERENCES uploads(upload_id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    original_name VARCHAR(500) NOT NULL,
+    stored_name VARCHAR(600) NOT NULL,
+    size BIGINT NOT NULL,
+    mime_type VARCHAR(255) NOT NULL DEFAULT 'application/octet-stream',
+    sha256 VARCHAR(64),
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (upload_id, position)
+);
+
+CREATE INDEX IF NOT EXISTS idx_upload_files_upload_id ON upload_files(upload_id);
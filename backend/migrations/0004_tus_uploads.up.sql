@@ -0,0 +1,16 @@
+-- Resumable (tus) upload sessions
+CREATE TABLE IF NOT EXISTS tus_uploads (
+    id VARCHAR(64) PRIMARY KEY,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+    reverse_token VARCHAR(255),
+    filename VARCHAR(500) NOT NULL,
+    upload_length BIGINT NOT NULL,
+    upload_offset BIGINT NOT NULL DEFAULT 0,
+    email VARCHAR(255),
+    validity VARCHAR(20) NOT NULL DEFAULT '7days',
+    upload_id VARCHAR(255),
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_tus_uploads_updated_at ON tus_uploads(updated_at);
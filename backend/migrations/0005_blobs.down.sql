@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS idx_upload_files_sha256;
+DROP TABLE IF EXISTS blobs;
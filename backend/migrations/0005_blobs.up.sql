@@ -0,0 +1,11 @@
+-- Deduplicated file contents, keyed by SHA-256 and shared between
+-- upload_files rows; the object is deleted once ref_count drops to zero
+CREATE TABLE IF NOT EXISTS blobs (
+    sha256 VARCHAR(64) PRIMARY KEY,
+    storage_key VARCHAR(255) NOT NULL,
+    size BIGINT NOT NULL,
+    ref_count INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_upload_files_sha256 ON upload_files(sha256);
@@ -0,0 +1,2 @@
+ALTER TABLE users DROP COLUMN IF EXISTS storage_quota;
+ALTER TABLE settings DROP COLUMN IF EXISTS default_storage_quota;
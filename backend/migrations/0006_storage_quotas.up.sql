@@ -0,0 +1,3 @@
+-- Storage quotas: a default for everyone, optionally overridden per user
+ALTER TABLE settings ADD COLUMN IF NOT EXISTS default_storage_quota BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS storage_quota BIGINT;
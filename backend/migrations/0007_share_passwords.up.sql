@@ -0,0 +1,2 @@
+-- Optional bcrypt hash guarding a share's downloads
+ALTER TABLE uploads ADD COLUMN IF NOT EXISTS password_hash VARCHAR(255);
@@ -0,0 +1,2 @@
+ALTER TABLE uploads DROP COLUMN IF EXISTS download_count;
+ALTER TABLE uploads DROP COLUMN IF EXISTS max_downloads;
@@ -0,0 +1,3 @@
+-- Download limits; max_downloads NULL means unlimited
+ALTER TABLE uploads ADD COLUMN IF NOT EXISTS max_downloads INTEGER;
+ALTER TABLE uploads ADD COLUMN IF NOT EXISTS download_count INTEGER NOT NULL DEFAULT 0;
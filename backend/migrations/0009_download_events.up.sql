@@ -0,0 +1,13 @@
+-- One row per download response; file_id is NULL for ZIP archives of the
+-- whole upload
+CREATE TABLE IF NOT EXISTS download_events (
+    id BIGSERIAL PRIMARY KEY,
+    upload_id VARCHAR(255) NOT NULL REFERENCES uploads(upload_id) ON DELETE CASCADE,
+    file_id INTEGER REFERENCES upload_files(id) ON DELETE SET NULL,
+    ip_address VARCHAR(255) NOT NULL,
+    user_agent TEXT,
+    bytes_served BIGINT NOT NULL,
+    downloaded_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_download_events_upload_id ON download_events(upload_id, downloaded_at);
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS oidc_logins;
+ALTER TABLE users DROP COLUMN IF EXISTS oidc_subject;
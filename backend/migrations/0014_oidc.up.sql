@@ -0,0 +1,10 @@
+-- Single sign-on: the provider's subject identifier of linked users, and
+-- logins waiting for the provider to redirect back
+ALTER TABLE users ADD COLUMN IF NOT EXISTS oidc_subject VARCHAR(255) UNIQUE;
+
+CREATE TABLE IF NOT EXISTS oidc_logins (
+    state VARCHAR(255) PRIMARY KEY,
+    pkce_verifier VARCHAR(255) NOT NULL,
+    nonce VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,7 @@
+-- Admin overrides of the built-in email templates in mailer.rs
+CREATE TABLE IF NOT EXISTS mail_templates (
+    name VARCHAR(100) PRIMARY KEY,
+    subject TEXT NOT NULL,
+    body TEXT NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
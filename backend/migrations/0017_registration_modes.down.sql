@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS invites;
+DROP TABLE IF EXISTS email_verifications;
+ALTER TABLE users DROP COLUMN IF EXISTS email_verified;
+ALTER TABLE settings DROP COLUMN IF EXISTS registration_mode;
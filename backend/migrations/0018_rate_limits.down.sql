@@ -0,0 +1,10 @@
+ALTER TABLE users
+    DROP COLUMN IF EXISTS locked_until,
+    DROP COLUMN IF EXISTS failed_logins;
+
+ALTER TABLE settings
+    DROP COLUMN IF EXISTS lockout_minutes,
+    DROP COLUMN IF EXISTS lockout_threshold,
+    DROP COLUMN IF EXISTS account_rate_limit,
+    DROP COLUMN IF EXISTS public_rate_limit,
+    DROP COLUMN IF EXISTS auth_rate_limit;
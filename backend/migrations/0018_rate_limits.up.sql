@@ -0,0 +1,11 @@
+-- Brute-force protection: rate limits, and failed logins for lockouts
+ALTER TABLE settings
+    ADD COLUMN IF NOT EXISTS auth_rate_limit INTEGER NOT NULL DEFAULT 10,
+    ADD COLUMN IF NOT EXISTS public_rate_limit INTEGER NOT NULL DEFAULT 120,
+    ADD COLUMN IF NOT EXISTS account_rate_limit INTEGER NOT NULL DEFAULT 20,
+    ADD COLUMN IF NOT EXISTS lockout_threshold INTEGER NOT NULL DEFAULT 5,
+    ADD COLUMN IF NOT EXISTS lockout_minutes INTEGER NOT NULL DEFAULT 1;
+
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS failed_logins INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN IF NOT EXISTS locked_until TIMESTAMP WITH TIME ZONE;
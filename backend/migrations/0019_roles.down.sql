@@ -0,0 +1,6 @@
+-- Owners and admins go back to being admins; moderators and guests become
+-- plain users
+ALTER TABLE users ADD COLUMN IF NOT EXISTS is_admin BOOLEAN DEFAULT FALSE;
+UPDATE users SET is_admin = role IN ('owner', 'admin');
+DROP INDEX IF EXISTS idx_users_single_owner;
+ALTER TABLE users DROP COLUMN IF EXISTS role;
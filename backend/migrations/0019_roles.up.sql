@@ -0,0 +1,20 @@
+-- Roles replace the admin flag: admins keep admin, and the oldest admin
+-- becomes the owner
+ALTER TABLE users ADD COLUMN IF NOT EXISTS role VARCHAR(20) NOT NULL DEFAULT 'user';
+
+DO $$
+BEGIN
+    IF EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_schema = current_schema() AND table_name = 'users' AND column_name = 'is_admin'
+    ) THEN
+        UPDATE users SET role = 'admin' WHERE is_admin = TRUE;
+        UPDATE users SET role = 'owner'
+        WHERE id = (SELECT MIN(id) FROM users WHERE role = 'admin')
+          AND NOT EXISTS (SELECT 1 FROM users WHERE role = 'owner');
+        ALTER TABLE users DROP COLUMN is_admin;
+    END IF;
+END
+$$;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_users_single_owner ON users(role) WHERE role = 'owner';
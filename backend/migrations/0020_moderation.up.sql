@@ -0,0 +1,3 @@
+-- Shares an admin or moderator took down, which their owner cannot bring
+-- back
+ALTER TABLE uploads ADD COLUMN IF NOT EXISTS removed_by_admin BOOLEAN NOT NULL DEFAULT FALSE;
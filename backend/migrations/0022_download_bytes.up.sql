@@ -0,0 +1,3 @@
+-- Bytes sent to downloaders, from which download_count follows: every copy
+-- of the contents that was started counts once
+ALTER TABLE uploads ADD COLUMN IF NOT EXISTS bytes_served BIGINT NOT NULL DEFAULT 0;
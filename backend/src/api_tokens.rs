@@ -0,0 +1,84 @@
+use actix_web::http::Method;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+/// Personal access tokens start with this, which tells them apart from JWTs.
+pub const TOKEN_PREFIX: &str = "rdp_";
+
+pub const SCOPE_UPLOAD: &str = "upload";
+pub const SCOPE_READ: &str = "read";
+pub const SCOPE_DELETE: &str = "delete";
+pub const SCOPE_REVERSE_TOKENS: &str = "reverse-tokens";
+
+pub const SCOPES: &[&str] = &[SCOPE_UPLOAD, SCOPE_READ, SCOPE_DELETE, SCOPE_REVERSE_TOKENS];
+
+/// A personal access token that authenticated the current request, stored in
+/// the request extensions by `middleware::Authentication`.
+#[derive(Debug, Clone)]
+pub struct ApiToken {
+    pub user_id: i32,
+    pub scopes: Vec<String>,
+}
+
+impl ApiToken {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+/// Returns a new token and the hash to store for it.
+pub fn generate_token() -> (String, String) {
+    let token = format!(
+        "{}{}{}",
+        TOKEN_PREFIX,
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    );
+    let hash = hash_token(&token);
+    (token, hash)
+}
+
+/// Tokens are random enough that a plain SHA-256 is a safe way to store them.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// The scope a personal access token needs for a route, by method and
+/// matched route pattern. Routes without one, such as admin routes and token
+/// management, only accept logins.
+pub fn required_scope(method: &Method, pattern: &str) -> Option<&'static str> {
+    match (method.as_str(), pattern) {
+        ("POST", "/api/upload")
+        | ("PUT", "/api/uploads/{id}/availability")
+        | ("PUT", "/api/uploads/{id}/expiration")
+        | ("PUT", "/api/uploads/{id}/password")
+        | ("POST", "/api/tus")
+        | ("HEAD", "/api/tus/{id}")
+        | ("PATCH", "/api/tus/{id}")
+        | ("DELETE", "/api/tus/{id}") => Some(SCOPE_UPLOAD),
+        ("GET", "/api/me") | ("GET", "/api/uploads") | ("GET", "/api/uploads/{id}/activity") => Some(SCOPE_READ),
+        ("DELETE", "/api/uploads/{id}") => Some(SCOPE_DELETE),
+        ("GET", "/api/reverse-tokens") | ("POST", "/api/reverse-tokens") | ("DELETE", "/api/reverse-tokens/{id}") => {
+            Some(SCOPE_REVERSE_TOKENS)
+        }
+        _ => None,
+    }
+}
+
+/// Looks up a live token of a user who is not blocked and records that it
+/// was used.
+pub async fn authenticate(pool: &PgPool, token: &str) -> Result<Option<ApiToken>, sqlx::Error> {
+    let found: Option<(i32, Vec<String>)> = sqlx::query_as(
+        r#"
+        UPDATE api_tokens SET last_used_at = NOW()
+        WHERE token_hash = $1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > NOW())
+          AND user_id IN (SELECT id FROM users WHERE is_blocked IS NOT TRUE)
+        RETURNING user_id, scopes
+        "#,
+    )
+    .bind(hash_token(token))
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(found.map(|(user_id, scopes)| ApiToken { user_id, scopes }))
+}
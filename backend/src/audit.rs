@@ -0,0 +1,75 @@
+use actix_web::HttpRequest;
+use serde_json::{Map, Value};
+use sqlx::PgPool;
+
+use crate::utils;
+
+pub const SETTINGS_UPDATED: &str = "settings.updated";
+pub const USER_BLOCKED: &str = "user.blocked";
+pub const USER_UNBLOCKED: &str = "user.unblocked";
+pub const USER_UNLOCKED: &str = "user.unlocked";
+pub const USER_ROLE_CHANGED: &str = "user.role_changed";
+pub const OWNERSHIP_TRANSFERRED: &str = "ownership.transferred";
+pub const OWNERSHIP_CLAIMED: &str = "ownership.claimed";
+pub const LOGIN: &str = "auth.login";
+pub const LOGIN_FAILED: &str = "auth.login_failed";
+pub const API_TOKEN_CREATED: &str = "api_token.created";
+pub const UPLOAD_DISABLED: &str = "upload.disabled";
+pub const UPLOAD_ENABLED: &str = "upload.enabled";
+pub const UPLOAD_REMOVED: &str = "upload.removed";
+
+/// Splits two JSON objects into the fields that changed, as they were and
+/// as they are. Anything that is not an object is kept whole.
+pub fn diff(before: &Value, after: &Value) -> (Value, Value) {
+    let (Value::Object(old), Value::Object(new)) = (before, after) else {
+        return (before.clone(), after.clone());
+    };
+
+    let mut changed_from = Map::new();
+    let mut changed_to = Map::new();
+    for key in old.keys().chain(new.keys().filter(|k| !old.contains_key(*k))) {
+        let (from, to) = (old.get(key), new.get(key));
+        if from != to {
+            changed_from.insert(key.clone(), from.cloned().unwrap_or(Value::Null));
+            changed_to.insert(key.clone(), to.cloned().unwrap_or(Value::Null));
+        }
+    }
+
+    (Value::Object(changed_from), Value::Object(changed_to))
+}
+
+/// Appends an event to `audit_events`, with the client IP as
+/// `utils::client_ip` resolves it.
+/// `target` names what was acted on, such as `user:5` or `settings`; `before`
+/// and `after` hold what changed, or `Value::Null`. A failure is logged
+/// rather than failing the action it records.
+pub async fn record(
+    pool: &PgPool,
+    req: &HttpRequest,
+    actor_id: Option<i32>,
+    action: &str,
+    target: Option<&str>,
+    before: Value,
+    after: Value,
+) {
+    let ip = utils::client_ip(req);
+
+    let recorded = sqlx::query(
+        r#"
+        INSERT INTO audit_events (actor_id, actor_name, action, target, before, after, ip)
+        VALUES ($1, (SELECT username FROM users WHERE id = $1), $2, $3, $4, $5, $6)
+        "#,
+    )
+    .bind(actor_id)
+    .bind(action)
+    .bind(target)
+    .bind(Some(before).filter(|v| !v.is_null()))
+    .bind(Some(after).filter(|v| !v.is_null()))
+    .bind(ip)
+    .execute(pool)
+    .await;
+
+    if let Err(e) = recorded {
+        log::error!("Failed to record audit event {}: {}", action, e);
+    }
+}
@@ -2,14 +2,46 @@ use bcrypt::{hash, verify, DEFAULT_COST};
 use chrono::{Duration, Utc};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub user_id: i32,
+    /// The row in `sessions` this token belongs to; the token stops working
+    /// once that session is revoked.
+    pub jti: String,
     pub exp: usize,
     pub iat: usize,
 }
 
+/// Grants access to one password-protected upload. Signed with the same
+/// secret as session tokens but with different claims, so neither can be
+/// used in place of the other.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnlockClaims {
+    pub upload_id: String,
+    /// Fingerprint of the share's password hash, see `password_fingerprint`;
+    /// the token stops working once the password is changed.
+    pub password: String,
+    pub exp: usize,
+    pub iat: usize,
+}
+
+/// How long an unlock token stays valid.
+pub const UNLOCK_TOKEN_MINUTES: i64 = 60;
+
+/// Proves the password step of a login for a user with 2FA enabled; only
+/// accepted by `/api/login/2fa`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TwoFactorClaims {
+    pub pending_user_id: i32,
+    pub exp: usize,
+    pub iat: usize,
+}
+
+/// How long a user has to enter their code after the password.
+pub const TWO_FACTOR_TOKEN_MINUTES: i64 = 5;
+
 pub fn hash_password(password: &str) -> Result<String, bcrypt::BcryptError> {
     hash(password, DEFAULT_COST)
 }
@@ -18,12 +50,20 @@ pub fn verify_password(password: &str, hash: &str) -> Result<bool, bcrypt::Bcryp
     verify(password, hash)
 }
 
-pub fn generate_jwt(user_id: i32, secret: &str) -> Result<String, jsonwebtoken::errors::Error> {
+/// Short-lived access token for a session; clients get a new one from
+/// `/api/refresh`.
+pub fn generate_jwt(
+    user_id: i32,
+    session_id: &str,
+    secret: &str,
+    ttl_minutes: i64,
+) -> Result<String, jsonwebtoken::errors::Error> {
     let now = Utc::now();
-    let exp = now + Duration::hours(24);
+    let exp = now + Duration::minutes(ttl_minutes);
 
     let claims = Claims {
         user_id,
+        jti: session_id.to_string(),
         exp: exp.timestamp() as usize,
         iat: now.timestamp() as usize,
     };
@@ -45,6 +85,74 @@ pub fn validate_jwt(token: &str, secret: &str) -> Result<Claims, jsonwebtoken::e
     Ok(token_data.claims)
 }
 
+/// Identifies a password hash without giving it away. Setting a password
+/// always hashes it with a new salt, so even the same password again gets a
+/// different fingerprint.
+pub fn password_fingerprint(password_hash: &str) -> String {
+    hex::encode(&Sha256::digest(password_hash.as_bytes())[..16])
+}
+
+pub fn generate_unlock_token(
+    upload_id: &str,
+    password_hash: &str,
+    secret: &str,
+) -> Result<(String, chrono::DateTime<Utc>), jsonwebtoken::errors::Error> {
+    let now = Utc::now();
+    let exp = now + Duration::minutes(UNLOCK_TOKEN_MINUTES);
+
+    let claims = UnlockClaims {
+        upload_id: upload_id.to_string(),
+        password: password_fingerprint(password_hash),
+        exp: exp.timestamp() as usize,
+        iat: now.timestamp() as usize,
+    };
+
+    let token = encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )?;
+
+    Ok((token, exp))
+}
+
+pub fn validate_unlock_token(token: &str, secret: &str) -> Result<UnlockClaims, jsonwebtoken::errors::Error> {
+    let token_data = decode::<UnlockClaims>(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::default(),
+    )?;
+
+    Ok(token_data.claims)
+}
+
+pub fn generate_two_factor_token(user_id: i32, secret: &str) -> Result<String, jsonwebtoken::errors::Error> {
+    let now = Utc::now();
+    let exp = now + Duration::minutes(TWO_FACTOR_TOKEN_MINUTES);
+
+    let claims = TwoFactorClaims {
+        pending_user_id: user_id,
+        exp: exp.timestamp() as usize,
+        iat: now.timestamp() as usize,
+    };
+
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )
+}
+
+pub fn validate_two_factor_token(token: &str, secret: &str) -> Result<TwoFactorClaims, jsonwebtoken::errors::Error> {
+    let token_data = decode::<TwoFactorClaims>(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::default(),
+    )?;
+
+    Ok(token_data.claims)
+}
+
 pub fn extract_token_from_header(auth_header: Option<&str>) -> Option<String> {
     auth_header
         .and_then(|h| h.strip_prefix("Bearer "))
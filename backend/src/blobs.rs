@@ -0,0 +1,260 @@
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool};
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+use crate::staging::STAGING_DIR;
+use crate::storage::Storage;
+
+/// Storage key of the blob holding the content with the given SHA-256. The
+/// first two hex digits fan the objects out over 256 directories.
+pub fn blob_key(sha256: &str) -> String {
+    format!("blobs/{}/{}", &sha256[..2], sha256)
+}
+
+pub fn is_blob_key(key: &str) -> bool {
+    key.starts_with("blobs/")
+}
+
+/// Takes a reference on a blob, creating its row if needed. Returns `true`
+/// when the caller has to write the contents: either the blob is new, or all
+/// its references were dropped and it is waiting to be purged.
+pub async fn acquire(conn: &mut PgConnection, sha256: &str, size: i64) -> Result<bool, sqlx::Error> {
+    let ref_count: i32 = sqlx::query_scalar(
+        r#"
+        INSERT INTO blobs (sha256, storage_key, size, ref_count)
+        VALUES ($1, $2, $3, 1)
+        ON CONFLICT (sha256) DO UPDATE SET ref_count = blobs.ref_count + 1
+        RETURNING ref_count
+        "#,
+    )
+    .bind(sha256)
+    .bind(blob_key(sha256))
+    .bind(size)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(ref_count == 1)
+}
+
+/// Stores the local file at `source` as the blob for `sha256`, as part of the
+/// transaction on `conn`. The source is consumed either way. Returns the key
+/// if an object was written, so it can be removed again if the transaction
+/// does not commit.
+pub async fn store(
+    conn: &mut PgConnection,
+    storage: &dyn Storage,
+    sha256: &str,
+    size: i64,
+    source: &Path,
+) -> std::io::Result<Option<String>> {
+    let needs_contents = acquire(conn, sha256, size)
+        .await
+        .map_err(std::io::Error::other)?;
+
+    if !needs_contents {
+        tokio::fs::remove_file(source).await.ok();
+        return Ok(None);
+    }
+
+    let key = blob_key(sha256);
+    storage.put(&key, source).await?;
+    Ok(Some(key))
+}
+
+/// Drops the references held by the files of an upload. Returns the hashes
+/// of blobs that are no longer referenced; pass them to `purge` once the
+/// transaction has committed.
+pub async fn release_upload(conn: &mut PgConnection, upload_id: &str) -> Result<Vec<String>, sqlx::Error> {
+    let released: Vec<(String, i32)> = sqlx::query_as(
+        r#"
+        UPDATE blobs b
+        SET ref_count = b.ref_count - f.refs
+        FROM (
+            SELECT sha256, COUNT(*)::INTEGER AS refs
+            FROM upload_files
+            WHERE upload_id = $1 AND sha256 IS NOT NULL AND stored_name LIKE 'blobs/%'
+            GROUP BY sha256
+        ) f
+        WHERE b.sha256 = f.sha256
+        RETURNING b.sha256, b.ref_count
+        "#,
+    )
+    .bind(upload_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(released
+        .into_iter()
+        .filter(|(_, ref_count)| *ref_count <= 0)
+        .map(|(sha256, _)| sha256)
+        .collect())
+}
+
+/// Deletes unreferenced blobs. The row stays locked while the object is
+/// removed, so an upload of the same content waits and then writes it anew
+/// instead of pointing at an object that is about to disappear.
+pub async fn purge(pool: &PgPool, storage: &dyn Storage, hashes: &[String]) {
+    for sha256 in hashes {
+        if let Err(e) = purge_one(pool, storage, sha256).await {
+            log::warn!("Failed to purge blob {}: {}", sha256, e);
+        }
+    }
+}
+
+async fn purge_one(pool: &PgPool, storage: &dyn Storage, sha256: &str) -> std::io::Result<()> {
+    let mut tx = pool.begin().await.map_err(std::io::Error::other)?;
+
+    let key: Option<String> = sqlx::query_scalar(
+        "SELECT storage_key FROM blobs WHERE sha256 = $1 AND ref_count <= 0 FOR UPDATE",
+    )
+    .bind(sha256)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(std::io::Error::other)?;
+
+    let key = match key {
+        Some(key) => key,
+        // Picked up again by a new upload in the meantime
+        None => return Ok(()),
+    };
+
+    storage.delete(&key).await?;
+
+    sqlx::query("DELETE FROM blobs WHERE sha256 = $1")
+        .bind(sha256)
+        .execute(&mut *tx)
+        .await
+        .map_err(std::io::Error::other)?;
+
+    tx.commit().await.map_err(std::io::Error::other)?;
+
+    log::info!("Deleted unreferenced blob {}", key);
+    Ok(())
+}
+
+/// Purges every blob left without references, e.g. when the server stopped
+/// between releasing an upload and deleting its blobs.
+pub async fn purge_unreferenced(pool: &PgPool, storage: &dyn Storage) {
+    match sqlx::query_scalar::<_, String>("SELECT sha256 FROM blobs WHERE ref_count <= 0")
+        .fetch_all(pool)
+        .await
+    {
+        Ok(hashes) => purge(pool, storage, &hashes).await,
+        Err(e) => log::error!("Failed to load unreferenced blobs: {}", e),
+    }
+}
+
+/// Moves files of live uploads that were stored under their old
+/// `{upload_id}_{name}` keys into the blob store. Files that cannot be moved
+/// are left where they are and keep being served from their old key.
+pub async fn migrate_legacy_files(pool: &PgPool, storage: &dyn Storage) {
+    let legacy: Vec<(i32, String)> = match sqlx::query_as(
+        r#"
+        SELECT f.id, f.stored_name
+        FROM upload_files f
+        JOIN uploads up ON up.upload_id = f.upload_id
+        WHERE up.is_deleted = FALSE AND f.stored_name NOT LIKE 'blobs/%'
+        ORDER BY f.id
+        "#,
+    )
+    .fetch_all(pool)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(e) => {
+            log::error!("Failed to load legacy upload files: {}", e);
+            return;
+        }
+    };
+
+    if legacy.is_empty() {
+        return;
+    }
+
+    log::info!("Moving {} legacy files into the blob store...", legacy.len());
+
+    let mut moved = 0;
+    for (file_id, stored_name) in legacy {
+        match migrate_file(pool, storage, file_id, &stored_name).await {
+            Ok(true) => moved += 1,
+            Ok(false) => log::warn!("Legacy file {} is missing, leaving it as is", stored_name),
+            Err(e) => log::warn!("Failed to move {} into the blob store: {}", stored_name, e),
+        }
+    }
+
+    log::info!("Moved {} legacy files into the blob store", moved);
+}
+
+async fn migrate_file(
+    pool: &PgPool,
+    storage: &dyn Storage,
+    file_id: i32,
+    stored_name: &str,
+) -> std::io::Result<bool> {
+    if storage.stat(stored_name).await?.is_none() {
+        return Ok(false);
+    }
+
+    // Copy through a local file so the content is hashed from what is
+    // actually stored, whatever the backend
+    let temp_path = Path::new(STAGING_DIR).join(format!("migrate-{}", file_id));
+    let (sha256, size) = match copy_to_local(storage, stored_name, &temp_path).await {
+        Ok(copied) => copied,
+        Err(e) => {
+            tokio::fs::remove_file(&temp_path).await.ok();
+            return Err(e);
+        }
+    };
+
+    let mut tx = pool.begin().await.map_err(std::io::Error::other)?;
+
+    let written = match store(&mut tx, storage, &sha256, size, &temp_path).await {
+        Ok(written) => written,
+        Err(e) => {
+            tokio::fs::remove_file(&temp_path).await.ok();
+            return Err(e);
+        }
+    };
+
+    let updated = sqlx::query("UPDATE upload_files SET stored_name = $1, sha256 = $2, size = $3 WHERE id = $4")
+        .bind(blob_key(&sha256))
+        .bind(&sha256)
+        .bind(size)
+        .bind(file_id)
+        .execute(&mut *tx)
+        .await;
+
+    let committed = match updated {
+        Ok(_) => tx.commit().await,
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = committed {
+        if let Some(key) = written {
+            storage.delete(&key).await.ok();
+        }
+        return Err(std::io::Error::other(e));
+    }
+
+    storage.delete(stored_name).await?;
+    Ok(true)
+}
+
+async fn copy_to_local(storage: &dyn Storage, key: &str, target: &Path) -> std::io::Result<(String, i64)> {
+    let mut object = storage.get(key, None).await?;
+    let mut file = tokio::fs::File::create(target).await?;
+    let mut hasher = Sha256::new();
+    let mut size: i64 = 0;
+
+    while let Some(chunk) = object.stream.next().await {
+        let data = chunk?;
+        size += data.len() as i64;
+        hasher.update(&data);
+        file.write_all(&data).await?;
+    }
+
+    file.flush().await?;
+    Ok((hex::encode(hasher.finalize()), size))
+}
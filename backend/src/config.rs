@@ -1,11 +1,115 @@
 use std::env;
+use std::net::IpAddr;
 
 #[derive(Clone)]
 pub struct Config {
     pub database_url: String,
     pub jwt_secret: String,
-    pub server_port: String,
-    pub allowed_origins: String,
+    /// Where the frontend is served; links in emails start with it.
+    pub public_url: String,
+    /// Lifetime of the JWTs sent with each request.
+    pub access_token_minutes: i64,
+    /// How long a session lasts without being refreshed; every refresh
+    /// extends it again.
+    pub refresh_token_days: i64,
+    pub storage: StorageConfig,
+    /// `None` when no SMTP server is configured and mail is not sent.
+    pub mail: Option<MailConfig>,
+    /// Single sign-on through an OpenID Connect provider, if configured.
+    pub oidc: Option<OidcConfig>,
+    /// Reverse proxies whose X-Forwarded-For header is believed. Without
+    /// any, clients are known by the address they connect from.
+    pub trusted_proxies: Vec<ProxyRange>,
+}
+
+/// An address or CIDR range, such as `172.18.0.0/16`.
+#[derive(Clone, Copy, Debug)]
+pub struct ProxyRange {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl ProxyRange {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix.parse().ok()?)),
+            None => (value, None),
+        };
+        let network: IpAddr = address.parse().ok()?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(bits);
+        (prefix <= bits).then_some(Self { network, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        let (network, ip, bits) = match (self.network, ip) {
+            (IpAddr::V4(n), IpAddr::V4(i)) => (u32::from(n) as u128, u32::from(i) as u128, 32),
+            (IpAddr::V6(n), IpAddr::V6(i)) => (u128::from(n), u128::from(i), 128),
+            _ => return false,
+        };
+        let shift = bits - self.prefix;
+        shift >= 128 || network >> shift == ip >> shift
+    }
+}
+
+#[derive(Clone)]
+pub enum StorageConfig {
+    Local { root: String },
+    S3(S3Config),
+}
+
+#[derive(Clone)]
+pub struct MailConfig {
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub security: SmtpSecurity,
+    pub from: String,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SmtpSecurity {
+    /// Plain connection; for local SMTP sinks such as Mailpit.
+    None,
+    StartTls,
+    Tls,
+}
+
+#[derive(Clone)]
+pub struct OidcConfig {
+    pub issuer_url: String,
+    pub client_id: String,
+    /// Not needed for public clients, which rely on PKCE alone.
+    pub client_secret: Option<String>,
+    /// Where the provider sends users back to; must point at
+    /// `/api/oidc/callback`.
+    pub redirect_url: String,
+    pub scopes: Vec<String>,
+    /// Label of the login button.
+    pub display_name: String,
+    pub groups_claim: String,
+    /// Users in this group become admins and admins outside it become users
+    /// again; the owner, moderators and guests keep their role. Without it,
+    /// roles are managed in RootDrop as usual.
+    pub admin_group: Option<String>,
+    /// Accounts are then only created through single sign-on.
+    pub disable_local_registration: bool,
+}
+
+#[derive(Clone)]
+pub struct S3Config {
+    pub endpoint: Option<String>,
+    pub region: String,
+    pub bucket: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub force_path_style: bool,
+    pub prefix: String,
 }
 
 impl Config {
@@ -30,11 +134,119 @@ impl Config {
             db_user, db_password, db_host, db_port, db_name
         );
 
+        let public_url = env::var("PUBLIC_URL")
+            .unwrap_or_else(|_| "http://localhost:3000".to_string())
+            .trim_end_matches('/')
+            .to_string();
+
         Self {
             database_url,
             jwt_secret,
-            server_port: env::var("SERVER_PORT").unwrap_or_else(|_| "8080".to_string()),
-            allowed_origins: env::var("ALLOWED_ORIGINS").unwrap_or_else(|_| "*".to_string()),
+            access_token_minutes: env::var("ACCESS_TOKEN_TTL_MINUTES")
+                .ok()
+                .map(|m| m.parse().expect("ACCESS_TOKEN_TTL_MINUTES must be a number of minutes"))
+                .unwrap_or(15),
+            refresh_token_days: env::var("REFRESH_TOKEN_TTL_DAYS")
+                .ok()
+                .map(|d| d.parse().expect("REFRESH_TOKEN_TTL_DAYS must be a number of days"))
+                .unwrap_or(7),
+            storage: StorageConfig::from_env(),
+            mail: MailConfig::from_env(),
+            oidc: OidcConfig::from_env(&public_url),
+            trusted_proxies: env::var("TRUSTED_PROXIES")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(|p| {
+                    ProxyRange::parse(p)
+                        .unwrap_or_else(|| panic!("TRUSTED_PROXIES: {} is not an address or CIDR range", p))
+                })
+                .collect(),
+            public_url,
+        }
+    }
+}
+
+impl OidcConfig {
+    fn from_env(public_url: &str) -> Option<Self> {
+        let issuer_url = env::var("OIDC_ISSUER_URL").ok().filter(|u| !u.is_empty())?;
+
+        Some(Self {
+            issuer_url,
+            client_id: env::var("OIDC_CLIENT_ID").expect("OIDC_CLIENT_ID must be set when OIDC_ISSUER_URL is set"),
+            client_secret: env::var("OIDC_CLIENT_SECRET").ok().filter(|s| !s.is_empty()),
+            redirect_url: env::var("OIDC_REDIRECT_URL")
+                .unwrap_or_else(|_| format!("{}/api/oidc/callback", public_url)),
+            scopes: env::var("OIDC_SCOPES")
+                .unwrap_or_else(|_| "openid email profile".to_string())
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+            display_name: env::var("OIDC_DISPLAY_NAME").unwrap_or_else(|_| "Single sign-on".to_string()),
+            groups_claim: env::var("OIDC_GROUPS_CLAIM").unwrap_or_else(|_| "groups".to_string()),
+            admin_group: env::var("OIDC_ADMIN_GROUP").ok().filter(|g| !g.is_empty()),
+            disable_local_registration: env::var("OIDC_DISABLE_LOCAL_REGISTRATION")
+                .map(|v| v == "true")
+                .unwrap_or(false),
+        })
+    }
+}
+
+impl MailConfig {
+    fn from_env() -> Option<Self> {
+        let smtp_host = env::var("SMTP_HOST").ok().filter(|h| !h.is_empty())?;
+
+        let security = match env::var("SMTP_SECURITY").unwrap_or_else(|_| "starttls".to_string()).to_lowercase().as_str() {
+            "none" => SmtpSecurity::None,
+            "starttls" => SmtpSecurity::StartTls,
+            "tls" => SmtpSecurity::Tls,
+            other => panic!("Unsupported SMTP_SECURITY: {}", other),
+        };
+        let default_port = match security {
+            SmtpSecurity::None => 25,
+            SmtpSecurity::StartTls => 587,
+            SmtpSecurity::Tls => 465,
+        };
+
+        Some(Self {
+            smtp_host,
+            smtp_port: env::var("SMTP_PORT")
+                .ok()
+                .map(|p| p.parse().expect("SMTP_PORT must be a port number"))
+                .unwrap_or(default_port),
+            smtp_username: env::var("SMTP_USERNAME").ok().filter(|u| !u.is_empty()),
+            smtp_password: env::var("SMTP_PASSWORD").ok().filter(|p| !p.is_empty()),
+            security,
+            from: env::var("MAIL_FROM").expect("MAIL_FROM must be set when SMTP_HOST is set"),
+        })
+    }
+}
+
+impl StorageConfig {
+    fn from_env() -> Self {
+        let backend = env::var("STORAGE_BACKEND").unwrap_or_else(|_| "local".to_string());
+
+        match backend.to_lowercase().as_str() {
+            "local" => StorageConfig::Local {
+                root: env::var("STORAGE_LOCAL_PATH").unwrap_or_else(|_| "./uploads".to_string()),
+            },
+            "s3" => StorageConfig::S3(S3Config {
+                endpoint: env::var("S3_ENDPOINT").ok().filter(|e| !e.is_empty()),
+                region: env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+                bucket: env::var("S3_BUCKET")
+                    .expect("S3_BUCKET must be set when STORAGE_BACKEND=s3"),
+                access_key_id: env::var("S3_ACCESS_KEY_ID")
+                    .expect("S3_ACCESS_KEY_ID must be set when STORAGE_BACKEND=s3"),
+                secret_access_key: env::var("S3_SECRET_ACCESS_KEY")
+                    .expect("S3_SECRET_ACCESS_KEY must be set when STORAGE_BACKEND=s3"),
+                // MinIO and most self-hosted stores need path-style addressing
+                force_path_style: env::var("S3_FORCE_PATH_STYLE")
+                    .map(|v| v != "false")
+                    .unwrap_or(true),
+                prefix: env::var("S3_PREFIX").unwrap_or_default(),
+            }),
+            other => panic!("Unsupported STORAGE_BACKEND: {}", other),
         }
     }
 }
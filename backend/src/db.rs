@@ -1,209 +1,227 @@
+use chrono::{DateTime, Utc};
+use sqlx::migrate::{AppliedMigration, Migrate, MigrateError, Migrator};
 use sqlx::{Pool, Postgres};
+use std::fmt;
 
-pub async fn run_migrations(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
-    // Fix timestamp columns to use TIMESTAMP WITH TIME ZONE
-    // This handles the case where tables were created by Go backend without timezone
-    log::info!("Fixing timestamp columns...");
-    
-    // Fix users table
-    sqlx::query("ALTER TABLE users ALTER COLUMN created_at TYPE TIMESTAMP WITH TIME ZONE")
-        .execute(pool)
-        .await
-        .ok(); // Ignore error if already correct type
-
-    // Fix settings table
-    sqlx::query("ALTER TABLE settings ALTER COLUMN updated_at TYPE TIMESTAMP WITH TIME ZONE")
-        .execute(pool)
-        .await
-        .ok();
-
-    // Fix uploads table
-    sqlx::query("ALTER TABLE uploads ALTER COLUMN created_at TYPE TIMESTAMP WITH TIME ZONE")
-        .execute(pool)
-        .await
-        .ok();
-    sqlx::query("ALTER TABLE uploads ALTER COLUMN expires_at TYPE TIMESTAMP WITH TIME ZONE")
-        .execute(pool)
-        .await
-        .ok();
-    sqlx::query("ALTER TABLE uploads ALTER COLUMN deleted_at TYPE TIMESTAMP WITH TIME ZONE")
-        .execute(pool)
-        .await
-        .ok();
-
-    // Fix reverse_share_tokens table
-    sqlx::query("ALTER TABLE reverse_share_tokens ALTER COLUMN created_at TYPE TIMESTAMP WITH TIME ZONE")
-        .execute(pool)
-        .await
-        .ok();
-    sqlx::query("ALTER TABLE reverse_share_tokens ALTER COLUMN expires_at TYPE TIMESTAMP WITH TIME ZONE")
-        .execute(pool)
-        .await
-        .ok();
-
-    // Fix deletion_logs table
-    sqlx::query("ALTER TABLE deletion_logs ALTER COLUMN uploaded_at TYPE TIMESTAMP WITH TIME ZONE")
-        .execute(pool)
-        .await
-        .ok();
-    sqlx::query("ALTER TABLE deletion_logs ALTER COLUMN deleted_at TYPE TIMESTAMP WITH TIME ZONE")
-        .execute(pool)
-        .await
-        .ok();
-    sqlx::query("ALTER TABLE deletion_logs ALTER COLUMN expires_at TYPE TIMESTAMP WITH TIME ZONE")
-        .execute(pool)
-        .await
-        .ok();
-
-    log::info!("Timestamp columns fixed");
-
-    // Users table
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS users (
-            id SERIAL PRIMARY KEY,
-            username VARCHAR(255) UNIQUE NOT NULL,
-            email VARCHAR(255) UNIQUE NOT NULL,
-            password_hash VARCHAR(255) NOT NULL,
-            is_admin BOOLEAN DEFAULT FALSE,
-            is_blocked BOOLEAN DEFAULT FALSE,
-            avatar VARCHAR(500),
-            created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
-        )
-        "#,
-    )
-    .execute(pool)
-    .await?;
+use crate::files::{guess_mime_type, insert_upload_files, sha256_file, NewUploadFile};
+use crate::utils::sanitize_filename_safe;
 
-    // Settings table
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS settings (
-            id SERIAL PRIMARY KEY,
-            theme VARCHAR(50) DEFAULT 'light',
-            logo_path VARCHAR(500),
-            background_path VARCHAR(500),
-            navbar_title VARCHAR(255) DEFAULT 'RootDrop',
-            max_upload_size BIGINT DEFAULT 104857600,
-            upload_box_transparency INTEGER DEFAULT 0,
-            blur_intensity INTEGER DEFAULT 0,
-            max_validity VARCHAR(20) DEFAULT '7days',
-            allow_registration BOOLEAN DEFAULT TRUE,
-            expiration_action VARCHAR(20) DEFAULT 'unavailable',
-            website_color VARCHAR(7) DEFAULT '#3b82f6',
-            gradient_color_1 VARCHAR(7) DEFAULT '#3b82f6',
-            gradient_color_2 VARCHAR(7) DEFAULT '#8b5cf6',
-            gradient_color_3 VARCHAR(7) DEFAULT '#ec4899',
-            updated_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
-        )
-        "#,
-    )
-    .execute(pool)
-    .await?;
+/// The numbered migrations in `migrations/`, built into the binary. Each
+/// has an `.up.sql` and a `.down.sql`; applied versions and their checksums
+/// are kept in `_sqlx_migrations`, and an applied migration whose file has
+/// since changed stops startup. Migrations up to 0021 predate the history
+/// and are written so that databases from before it adopt them as they are.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
 
-    // Uploads table
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS uploads (
-            id SERIAL PRIMARY KEY,
-            user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
-            upload_id VARCHAR(255) UNIQUE NOT NULL,
-            files TEXT NOT NULL,
-            total_size BIGINT NOT NULL,
-            email VARCHAR(255),
-            download_url VARCHAR(255) NOT NULL,
-            created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
-            expires_at TIMESTAMP WITH TIME ZONE NULL,
-            is_available BOOLEAN DEFAULT TRUE,
-            is_reverse BOOLEAN DEFAULT FALSE,
-            reverse_token VARCHAR(255),
-            is_deleted BOOLEAN DEFAULT FALSE,
-            deleted_at TIMESTAMP WITH TIME ZONE NULL,
-            deletion_reason VARCHAR(255) DEFAULT NULL
-        )
-        "#,
-    )
-    .execute(pool)
-    .await?;
+#[derive(Debug)]
+pub enum MigrationError {
+    /// The database has migrations this binary does not know of, so it was
+    /// migrated by a newer release.
+    SchemaTooNew { database: i64, binary: i64 },
+    /// No migration has this version.
+    UnknownVersion(i64),
+    Migrate(MigrateError),
+}
 
-    // Reverse share tokens table
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS reverse_share_tokens (
-            id SERIAL PRIMARY KEY,
-            user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
-            token VARCHAR(255) UNIQUE NOT NULL,
-            name VARCHAR(255) NOT NULL,
-            used_count INTEGER DEFAULT 0,
-            max_uses INTEGER DEFAULT -1,
-            created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
-            expires_at TIMESTAMP WITH TIME ZONE NULL
-        )
-        "#,
-    )
-    .execute(pool)
-    .await?;
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::SchemaTooNew { database, binary } => write!(
+                f,
+                "Database schema is at version {} but this build only knows up to {}; run a newer release",
+                database, binary
+            ),
+            MigrationError::UnknownVersion(version) => write!(f, "Unknown migration version: {}", version),
+            MigrationError::Migrate(e) => write!(f, "{}", e),
+        }
+    }
+}
 
-    // Deletion logs table
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS deletion_logs (
-            id SERIAL PRIMARY KEY,
-            user_id INTEGER REFERENCES users(id) ON DELETE SET NULL,
-            username VARCHAR(255) NOT NULL,
-            upload_id VARCHAR(255) NOT NULL,
-            files TEXT NOT NULL,
-            total_size BIGINT NOT NULL,
-            email VARCHAR(255),
-            download_url VARCHAR(255) NOT NULL,
-            uploaded_at TIMESTAMP WITH TIME ZONE NOT NULL,
-            deleted_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
-            expires_at TIMESTAMP WITH TIME ZONE NULL,
-            is_reverse BOOLEAN DEFAULT FALSE,
-            reverse_token VARCHAR(255),
-            deletion_reason VARCHAR(500) DEFAULT 'User deleted'
-        )
-        "#,
-    )
-    .execute(pool)
-    .await?;
+impl std::error::Error for MigrationError {}
+
+impl From<MigrateError> for MigrationError {
+    fn from(e: MigrateError) -> Self {
+        MigrationError::Migrate(e)
+    }
+}
+
+impl From<sqlx::Error> for MigrationError {
+    fn from(e: sqlx::Error) -> Self {
+        MigrationError::Migrate(MigrateError::Execute(e))
+    }
+}
+
+/// A migration as `migrate status` shows it. `description` is `None` for a
+/// version that only the database knows.
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: Option<String>,
+    pub installed_on: Option<DateTime<Utc>>,
+    pub checksum_ok: bool,
+}
+
+/// The latest migration this binary has.
+fn latest_version() -> i64 {
+    MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0)
+}
 
-    // Create indexes
-    sqlx::query("CREATE INDEX IF NOT EXISTS idx_users_email ON users(email)")
-        .execute(pool)
-        .await?;
-    sqlx::query("CREATE INDEX IF NOT EXISTS idx_uploads_user_id ON uploads(user_id)")
-        .execute(pool)
-        .await?;
-    sqlx::query("CREATE INDEX IF NOT EXISTS idx_uploads_upload_id ON uploads(upload_id)")
-        .execute(pool)
-        .await?;
-    sqlx::query("CREATE INDEX IF NOT EXISTS idx_uploads_expires_at ON uploads(expires_at)")
-        .execute(pool)
-        .await?;
-    sqlx::query("CREATE INDEX IF NOT EXISTS idx_reverse_tokens_token ON reverse_share_tokens(token)")
-        .execute(pool)
-        .await?;
-    sqlx::query("CREATE INDEX IF NOT EXISTS idx_reverse_tokens_user_id ON reverse_share_tokens(user_id)")
-        .execute(pool)
-        .await?;
-
-    // Insert default settings
-    sqlx::query(
+/// Versions the database has applied, with their checksums.
+async fn applied_migrations(pool: &Pool<Postgres>) -> Result<Vec<AppliedMigration>, MigrationError> {
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    Ok(conn.list_applied_migrations().await?)
+}
+
+/// Refuses a database migrated by a newer release, whose schema this
+/// binary might misread or damage.
+async fn check_not_newer(pool: &Pool<Postgres>) -> Result<(), MigrationError> {
+    let binary = latest_version();
+    let database = applied_migrations(pool)
+        .await?
+        .iter()
+        .map(|m| m.version)
+        .max()
+        .unwrap_or(0);
+
+    if database > binary {
+        return Err(MigrationError::SchemaTooNew { database, binary });
+    }
+    Ok(())
+}
+
+/// Applies pending migrations, then fills in data that needs more than SQL.
+pub async fn run_migrations(pool: &Pool<Postgres>) -> Result<(), MigrationError> {
+    check_not_newer(pool).await?;
+
+    MIGRATOR.run(pool).await?;
+
+    backfill_upload_files(pool).await?;
+
+    log::info!("Database schema is at version {}", latest_version());
+    Ok(())
+}
+
+/// Reverts every applied migration after `target`, 0 reverting them all,
+/// or without a target just the latest one. Returns the version the
+/// database is left at.
+pub async fn revert_migrations(pool: &Pool<Postgres>, target: Option<i64>) -> Result<i64, MigrationError> {
+    if let Some(target) = target.filter(|t| *t != 0) {
+        if !MIGRATOR.version_exists(target) {
+            return Err(MigrationError::UnknownVersion(target));
+        }
+    }
+
+    check_not_newer(pool).await?;
+
+    let target = match target {
+        Some(target) => target,
+        None => {
+            let mut applied: Vec<i64> = applied_migrations(pool).await?.iter().map(|m| m.version).collect();
+            applied.sort_unstable();
+            applied.iter().rev().nth(1).copied().unwrap_or(0)
+        }
+    };
+
+    MIGRATOR.undo(pool, target).await?;
+    Ok(target)
+}
+
+/// Every migration of this binary and of the database, oldest first.
+pub async fn migration_status(pool: &Pool<Postgres>) -> Result<Vec<MigrationStatus>, MigrationError> {
+    // Creates the history table on a database that has none yet
+    applied_migrations(pool).await?;
+
+    let applied: Vec<(i64, DateTime<Utc>, Vec<u8>)> =
+        sqlx::query_as("SELECT version, installed_on, checksum FROM _sqlx_migrations WHERE success")
+            .fetch_all(pool)
+            .await?;
+
+    let mut status: Vec<MigrationStatus> = MIGRATOR
+        .iter()
+        .filter(|m| m.migration_type.is_up_migration())
+        .map(|m| {
+            let installed = applied.iter().find(|(version, _, _)| *version == m.version);
+            MigrationStatus {
+                version: m.version,
+                description: Some(m.description.to_string()),
+                installed_on: installed.map(|(_, on, _)| *on),
+                checksum_ok: installed.is_none_or(|(_, _, checksum)| *checksum == *m.checksum),
+            }
+        })
+        .collect();
+
+    for (version, installed_on, _) in &applied {
+        if !MIGRATOR.version_exists(*version) {
+            status.push(MigrationStatus {
+                version: *version,
+                description: None,
+                installed_on: Some(*installed_on),
+                checksum_ok: true,
+            });
+        }
+    }
+
+    status.sort_by_key(|m| m.version);
+    Ok(status)
+}
+
+/// Creates `upload_files` rows for uploads that predate the table, using the
+/// JSON list in `uploads.files` and whatever is still on disk. Files that are
+/// gone (deleted or expired uploads) are recorded with size 0 and no hash.
+async fn backfill_upload_files(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    let legacy: Vec<(String, String)> = sqlx::query_as(
         r#"
-        INSERT INTO settings (
-            theme, max_upload_size, upload_box_transparency, blur_intensity,
-            max_validity, allow_registration, expiration_action,
-            website_color, gradient_color_1, gradient_color_2, gradient_color_3
-        )
-        SELECT 'light', 104857600, 0, 0, '7days', TRUE, 'unavailable',
-               '#3b82f6', '#3b82f6', '#8b5cf6', '#ec4899'
-        WHERE NOT EXISTS (SELECT 1 FROM settings)
+        SELECT up.upload_id, up.files
+        FROM uploads up
+        WHERE NOT EXISTS (SELECT 1 FROM upload_files f WHERE f.upload_id = up.upload_id)
         "#,
     )
-    .execute(pool)
+    .fetch_all(pool)
     .await?;
 
-    log::info!("Database tables created successfully");
+    if legacy.is_empty() {
+        return Ok(());
+    }
+
+    log::info!("Backfilling upload_files for {} uploads...", legacy.len());
+
+    for (upload_id, files_json) in legacy {
+        let names: Vec<String> = match serde_json::from_str(&files_json) {
+            Ok(names) => names,
+            Err(e) => {
+                log::warn!("Skipping upload {} with unreadable file list: {}", upload_id, e);
+                continue;
+            }
+        };
+
+        let mut records = Vec::with_capacity(names.len());
+        for name in names {
+            let stored_name = format!("{}_{}", upload_id, sanitize_filename_safe(&name));
+            let path = std::path::Path::new("./uploads").join(&stored_name);
+
+            let (size, sha256) = tokio::task::spawn_blocking(move || {
+                match std::fs::metadata(&path) {
+                    Ok(metadata) => (metadata.len() as i64, sha256_file(&path).ok()),
+                    Err(_) => (0, None),
+                }
+            })
+            .await
+            .unwrap_or((0, None));
+
+            records.push(NewUploadFile {
+                mime_type: guess_mime_type(&name),
+                original_name: name,
+                stored_name,
+                size,
+                sha256,
+            });
+        }
+
+        let mut tx = pool.begin().await?;
+        insert_upload_files(&mut tx, &upload_id, &records).await?;
+        tx.commit().await?;
+    }
+
+    log::info!("upload_files backfill completed");
     Ok(())
 }
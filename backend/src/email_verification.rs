@@ -0,0 +1,96 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::api_tokens::hash_token;
+use crate::mailer::{self, MailError, Mailer};
+
+/// How long an emailed verification link works.
+pub const VERIFICATION_TOKEN_HOURS: i64 = 24;
+
+/// Creates a verification token for a user and returns it with its expiry.
+/// Links sent earlier stop working, so only the latest email counts.
+pub async fn issue(pool: &PgPool, user_id: i32) -> Result<(String, DateTime<Utc>), sqlx::Error> {
+    let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let expires_at = Utc::now() + Duration::hours(VERIFICATION_TOKEN_HOURS);
+
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("DELETE FROM email_verifications WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("INSERT INTO email_verifications (token_hash, user_id, expires_at) VALUES ($1, $2, $3)")
+        .bind(hash_token(&token))
+        .bind(user_id)
+        .bind(expires_at)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok((token, expires_at))
+}
+
+/// Marks the email of the token's user as verified and uses the token up.
+/// Returns the user, or `None` if the token is unknown or expired.
+pub async fn verify(pool: &PgPool, token: &str) -> Result<Option<i32>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let found: Option<(i32,)> = sqlx::query_as(
+        "DELETE FROM email_verifications WHERE token_hash = $1 AND expires_at > NOW() RETURNING user_id"
+    )
+    .bind(hash_token(token))
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some((user_id,)) = found else {
+        return Ok(None);
+    };
+
+    sqlx::query("UPDATE users SET email_verified = TRUE WHERE id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(Some(user_id))
+}
+
+/// Emails a user a fresh verification link, in the background.
+pub fn send(mailer: Arc<Mailer>, pool: PgPool, user_id: i32, username: String, email: String) {
+    actix_web::rt::spawn(async move {
+        if let Err(e) = send_link(&mailer, &pool, user_id, username, &email).await {
+            log::warn!("Failed to send verification email to user {}: {}", user_id, e);
+        }
+    });
+}
+
+async fn send_link(
+    mailer: &Mailer,
+    pool: &PgPool,
+    user_id: i32,
+    username: String,
+    email: &str,
+) -> Result<(), MailError> {
+    let (token, expires_at) = issue(pool, user_id).await?;
+
+    let vars = [
+        ("username", username),
+        ("verify_url", mailer.link(&format!("/verify-email?token={}", token))),
+        ("expires_at", format!("on {}", expires_at.format("%Y-%m-%d %H:%M UTC"))),
+    ];
+    mailer.send_template(pool, mailer::VERIFY_EMAIL, email, &vars).await
+}
+
+/// Drops verification tokens that expired more than a day ago.
+pub async fn prune(pool: &PgPool) {
+    let pruned = sqlx::query("DELETE FROM email_verifications WHERE expires_at < NOW() - INTERVAL '1 day'")
+        .execute(pool)
+        .await;
+
+    if let Err(e) = pruned {
+        log::error!("Failed to prune email verifications: {}", e);
+    }
+}
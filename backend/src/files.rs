@@ -0,0 +1,114 @@
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool};
+use std::io::Read;
+
+use crate::models::UploadFile;
+
+/// A file about to be recorded in `upload_files`.
+pub struct NewUploadFile {
+    pub original_name: String,
+    pub stored_name: String,
+    pub size: i64,
+    pub mime_type: String,
+    pub sha256: Option<String>,
+}
+
+pub fn guess_mime_type(filename: &str) -> String {
+    mime_guess::from_path(filename)
+        .first_or_octet_stream()
+        .essence_str()
+        .to_string()
+}
+
+/// Whether content of this type is compressed already, so archiving it
+/// should store it rather than deflate it again.
+pub fn is_compressed_type(filename: &str, mime_type: &str) -> bool {
+    const COMPRESSED_MIME_TYPES: &[&str] = &[
+        "application/zip",
+        "application/gzip",
+        "application/x-gzip",
+        "application/x-bzip2",
+        "application/x-xz",
+        "application/zstd",
+        "application/x-7z-compressed",
+        "application/vnd.rar",
+        "application/x-rar-compressed",
+        "application/java-archive",
+        "application/vnd.android.package-archive",
+        "application/epub+zip",
+        "application/x-apple-diskimage",
+        "application/pdf",
+    ];
+    const COMPRESSED_EXTENSIONS: &[&str] = &["tgz", "tbz2", "txz", "zst", "msi", "msix", "appimage", "whl", "nupkg"];
+
+    let media = ["image/", "audio/", "video/"].iter().any(|p| mime_type.starts_with(p))
+        && !matches!(mime_type, "image/bmp" | "image/svg+xml" | "image/x-icon" | "audio/wav" | "audio/x-wav");
+
+    let extension = std::path::Path::new(filename)
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    media
+        || COMPRESSED_MIME_TYPES.contains(&mime_type)
+        || mime_type.starts_with("application/vnd.openxmlformats-officedocument.")
+        || COMPRESSED_EXTENSIONS.contains(&extension.as_str())
+}
+
+/// Hashes a file on disk. Blocking; run it through `spawn_blocking` from async
+/// code.
+pub fn sha256_file(path: &std::path::Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+pub async fn insert_upload_files(
+    conn: &mut PgConnection,
+    upload_id: &str,
+    files: &[NewUploadFile],
+) -> Result<(), sqlx::Error> {
+    for (position, file) in files.iter().enumerate() {
+        sqlx::query(
+            r#"
+            INSERT INTO upload_files (upload_id, position, original_name, stored_name, size, mime_type, sha256)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+        )
+        .bind(upload_id)
+        .bind(position as i32)
+        .bind(&file.original_name)
+        .bind(&file.stored_name)
+        .bind(file.size)
+        .bind(&file.mime_type)
+        .bind(&file.sha256)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn load_upload_files(pool: &PgPool, upload_id: &str) -> Result<Vec<UploadFile>, sqlx::Error> {
+    sqlx::query_as(
+        r#"
+        SELECT id, upload_id, position, original_name, stored_name, size, mime_type, sha256, created_at
+        FROM upload_files
+        WHERE upload_id = $1
+        ORDER BY position
+        "#,
+    )
+    .bind(upload_id)
+    .fetch_all(pool)
+    .await
+}
@@ -4,20 +4,62 @@ use futures_util::StreamExt;
 use sha2::{Digest, Sha256};
 use sqlx::PgPool;
 use std::io::Write;
+use std::sync::Arc;
 
+use crate::audit;
+use crate::auth::verify_password;
 use crate::config::Config;
-use crate::models::{AdminStats, AdminUser, BlockUserRequest, PromoteUserRequest, QuickSettingRequest, Settings};
-use crate::utils::{check_is_admin, extract_user_id_from_request, parse_size, sanitize_filename_safe};
+use crate::blobs;
+use crate::email_verification;
+use crate::mailer::{self, MailError, Mailer};
+use crate::storage::Storage;
+use crate::models::{
+    AdminStats, AdminUser, BlockUserRequest, BootstrapRequest, MailTemplateRequest,
+    QuickSettingRequest, SetRoleRequest, Settings, StorageQuotaRequest, TestMailRequest,
+    TransferOwnershipRequest, REGISTRATION_INVITE_ONLY, REGISTRATION_OPEN, REGISTRATION_VERIFY_EMAIL,
+};
+use crate::password_resets;
+use crate::quota;
+use crate::rate_limit::{self, RateLimits};
+use crate::retention;
+use crate::roles::{self, Bootstrap, Permission};
+use crate::sessions;
+use crate::utils::{check_permission, extract_user_id_from_request, parse_size};
+use crate::webhooks;
+
+/// All settings, including those left out of the public `/api/settings`.
+pub async fn get_settings(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if !check_permission(user_id, &pool, Permission::ManageSettings).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin access required"
+        })));
+    }
+
+    let settings: Option<Settings> = sqlx::query_as("SELECT * FROM settings ORDER BY id LIMIT 1")
+        .fetch_optional(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(settings.unwrap_or_default()))
+}
 
 pub async fn update_settings(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
+    mailer: web::Data<Mailer>,
+    rate_limits: web::Data<RateLimits>,
     req: HttpRequest,
     mut payload: Multipart,
 ) -> Result<HttpResponse, Error> {
     let user_id = extract_user_id_from_request(&req, &config)?;
 
-    if !check_is_admin(user_id, &pool).await.unwrap_or(false) {
+    if !check_permission(user_id, &pool, Permission::ManageSettings).await.unwrap_or(false) {
         return Ok(HttpResponse::Forbidden().json(serde_json::json!({
             "error": "Admin access required"
         })));
@@ -36,6 +78,7 @@ pub async fn update_settings(
         .await
         .map_err(error::ErrorInternalServerError)?
         .unwrap_or_default();
+    let before = serde_json::to_value(&settings).map_err(error::ErrorInternalServerError)?;
 
     let mut form_data = std::collections::HashMap::new();
     let mut logo_data: Option<(Vec<u8>, String)> = None;
@@ -125,6 +168,59 @@ pub async fn update_settings(
         }
     }
 
+    if let Some(quota_str) = form_data.get("defaultStorageQuota") {
+        if let Ok(quota) = parse_size(quota_str) {
+            settings.default_storage_quota = quota;
+        }
+    }
+
+    if let Some(require_2fa) = form_data.get("requireAdminTwoFactor") {
+        if let Ok(val) = require_2fa.parse::<bool>() {
+            // Otherwise the admin would lock themselves out of this page
+            if val && !settings.require_admin_two_factor {
+                let (enabled,): (bool,) = sqlx::query_as("SELECT totp_enabled FROM users WHERE id = $1")
+                    .bind(user_id)
+                    .fetch_one(pool.as_ref())
+                    .await
+                    .map_err(error::ErrorInternalServerError)?;
+
+                if !enabled {
+                    return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                        "error": "Enable two-factor authentication on your own account first"
+                    })));
+                }
+            }
+            settings.require_admin_two_factor = val;
+        }
+    }
+
+    if let Some(mode) = form_data.get("registrationMode") {
+        let valid_modes = [REGISTRATION_OPEN, REGISTRATION_VERIFY_EMAIL, REGISTRATION_INVITE_ONLY];
+        if valid_modes.contains(&mode.as_str()) {
+            if mode == REGISTRATION_VERIFY_EMAIL && !mailer.is_enabled() {
+                return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "Email verification needs email to be configured"
+                })));
+            }
+            settings.registration_mode = mode.clone();
+        }
+    }
+
+    let limits = [
+        ("authRateLimit", &mut settings.auth_rate_limit),
+        ("publicRateLimit", &mut settings.public_rate_limit),
+        ("accountRateLimit", &mut settings.account_rate_limit),
+        ("lockoutThreshold", &mut settings.lockout_threshold),
+        ("lockoutMinutes", &mut settings.lockout_minutes),
+    ];
+    for (name, setting) in limits {
+        if let Some(val) = form_data.get(name).and_then(|v| v.parse::<i32>().ok()) {
+            if val >= 0 {
+                *setting = val;
+            }
+        }
+    }
+
     // Handle logo upload
     if let Some((data, _)) = logo_data {
         std::fs::create_dir_all("./logos").map_err(error::ErrorInternalServerError)?;
@@ -185,8 +281,9 @@ pub async fn update_settings(
     if settings.id == 0 {
         let id: (i32,) = sqlx::query_as(
             r#"
-            INSERT INTO settings (theme, logo_path, background_path, navbar_title, max_upload_size, blur_intensity, max_validity, allow_registration, expiration_action)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING id
+            INSERT INTO settings (theme, logo_path, background_path, navbar_title, max_upload_size, blur_intensity, max_validity, allow_registration, expiration_action, default_storage_quota, require_admin_two_factor, registration_mode,
+                auth_rate_limit, public_rate_limit, account_rate_limit, lockout_threshold, lockout_minutes)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17) RETURNING id
             "#
         )
         .bind(&settings.theme)
@@ -198,6 +295,14 @@ pub async fn update_settings(
         .bind(&settings.max_validity)
         .bind(settings.allow_registration)
         .bind(&settings.expiration_action)
+        .bind(settings.default_storage_quota)
+        .bind(settings.require_admin_two_factor)
+        .bind(&settings.registration_mode)
+        .bind(settings.auth_rate_limit)
+        .bind(settings.public_rate_limit)
+        .bind(settings.account_rate_limit)
+        .bind(settings.lockout_threshold)
+        .bind(settings.lockout_minutes)
         .fetch_one(pool.as_ref())
         .await
         .map_err(error::ErrorInternalServerError)?;
@@ -207,8 +312,11 @@ pub async fn update_settings(
         sqlx::query(
             r#"
             UPDATE settings SET theme = $1, logo_path = $2, background_path = $3, navbar_title = $4, max_upload_size = $5,
-            blur_intensity = $6, max_validity = $7, allow_registration = $8, expiration_action = $9, updated_at = CURRENT_TIMESTAMP
-            WHERE id = $10
+            blur_intensity = $6, max_validity = $7, allow_registration = $8, expiration_action = $9,
+            default_storage_quota = $10, require_admin_two_factor = $11, registration_mode = $12,
+            auth_rate_limit = $13, public_rate_limit = $14, account_rate_limit = $15, lockout_threshold = $16,
+            lockout_minutes = $17, updated_at = CURRENT_TIMESTAMP
+            WHERE id = $18
             "#
         )
         .bind(&settings.theme)
@@ -220,12 +328,28 @@ pub async fn update_settings(
         .bind(&settings.max_validity)
         .bind(settings.allow_registration)
         .bind(&settings.expiration_action)
+        .bind(settings.default_storage_quota)
+        .bind(settings.require_admin_two_factor)
+        .bind(&settings.registration_mode)
+        .bind(settings.auth_rate_limit)
+        .bind(settings.public_rate_limit)
+        .bind(settings.account_rate_limit)
+        .bind(settings.lockout_threshold)
+        .bind(settings.lockout_minutes)
         .bind(settings.id)
         .execute(pool.as_ref())
         .await
         .map_err(error::ErrorInternalServerError)?;
     }
 
+    rate_limits.configure(&settings);
+
+    let after = serde_json::to_value(&settings).map_err(error::ErrorInternalServerError)?;
+    let (before, after) = audit::diff(&before, &after);
+    if after.as_object().is_some_and(|changed| !changed.is_empty()) {
+        audit::record(&pool, &req, Some(user_id), audit::SETTINGS_UPDATED, Some("settings"), before, after).await;
+    }
+
     Ok(HttpResponse::Ok().json(settings))
 }
 
@@ -236,7 +360,7 @@ pub async fn get_stats(
 ) -> Result<HttpResponse, Error> {
     let user_id = extract_user_id_from_request(&req, &config)?;
 
-    if !check_is_admin(user_id, &pool).await.unwrap_or(false) {
+    if !check_permission(user_id, &pool, Permission::ViewStats).await.unwrap_or(false) {
         return Ok(HttpResponse::Forbidden().json(serde_json::json!({
             "error": "Admin access required"
         })));
@@ -259,10 +383,42 @@ pub async fn get_stats(
     .await
     .map_err(error::ErrorInternalServerError)?;
 
+    // Logical: what live uploads add up to. Physical: what the deduplicated
+    // blobs (plus any files not moved into the blob store) actually take.
+    let logical_storage_used: (i64,) = sqlx::query_as(
+        r#"
+        SELECT COALESCE(SUM(f.size), 0)::BIGINT
+        FROM upload_files f
+        JOIN uploads up ON up.upload_id = f.upload_id
+        WHERE up.is_deleted = FALSE
+        "#
+    )
+    .fetch_one(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    let physical_storage_used: (i64,) = sqlx::query_as(
+        r#"
+        SELECT (
+            SELECT COALESCE(SUM(size), 0) FROM blobs WHERE ref_count > 0
+        )::BIGINT + (
+            SELECT COALESCE(SUM(f.size), 0)
+            FROM upload_files f
+            JOIN uploads up ON up.upload_id = f.upload_id
+            WHERE up.is_deleted = FALSE AND f.stored_name NOT LIKE 'blobs/%'
+        )::BIGINT
+        "#
+    )
+    .fetch_one(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
     Ok(HttpResponse::Ok().json(AdminStats {
         total_users: total_users.0,
         total_uploads: total_uploads.0,
         storage_used: storage_used.0.unwrap_or(0),
+        logical_storage_used: logical_storage_used.0,
+        physical_storage_used: physical_storage_used.0,
     }))
 }
 
@@ -273,7 +429,7 @@ pub async fn get_users(
 ) -> Result<HttpResponse, Error> {
     let user_id = extract_user_id_from_request(&req, &config)?;
 
-    if !check_is_admin(user_id, &pool).await.unwrap_or(false) {
+    if !check_permission(user_id, &pool, Permission::ManageUsers).await.unwrap_or(false) {
         return Ok(HttpResponse::Forbidden().json(serde_json::json!({
             "error": "Admin access required"
         })));
@@ -282,15 +438,19 @@ pub async fn get_users(
     let users: Vec<AdminUser> = sqlx::query_as(
         r#"
         SELECT
-            u.id, u.username, u.email, u.avatar, u.is_admin,
+            u.id, u.username, u.email, u.avatar, u.role,
+            u.role IN ('owner', 'admin') as is_admin,
             COALESCE(u.is_blocked, false) as is_blocked,
             u.created_at,
             COUNT(CASE WHEN up.id IS NOT NULL THEN 1 END) as upload_count,
             COALESCE(SUM(up.total_size), 0)::BIGINT as storage_used,
+            u.storage_quota,
+            CASE WHEN u.locked_until > NOW() THEN u.locked_until END as locked_until,
             MAX(up.created_at) as last_activity
         FROM users u
         LEFT JOIN uploads up ON u.id = up.user_id
-        GROUP BY u.id, u.username, u.email, u.avatar, u.is_admin, u.is_blocked, u.created_at
+        GROUP BY u.id, u.username, u.email, u.avatar, u.role, u.is_blocked, u.storage_quota, u.locked_until,
+                 u.created_at
         ORDER BY u.created_at DESC
         "#
     )
@@ -301,36 +461,286 @@ pub async fn get_users(
     Ok(HttpResponse::Ok().json(users))
 }
 
-pub async fn block_user(
+pub async fn get_user_quota(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
     req: HttpRequest,
     target_user_id: web::Path<i32>,
-    body: web::Json<BlockUserRequest>,
 ) -> Result<HttpResponse, Error> {
     let admin_id = extract_user_id_from_request(&req, &config)?;
 
-    if !check_is_admin(admin_id, &pool).await.unwrap_or(false) {
+    if !check_permission(admin_id, &pool, Permission::ManageUsers).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin access required"
+        })));
+    }
+
+    if !user_exists(&pool, *target_user_id).await? {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "User not found"
+        })));
+    }
+
+    let usage = quota::storage_usage(pool.as_ref(), *target_user_id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(usage))
+}
+
+pub async fn set_user_quota(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    target_user_id: web::Path<i32>,
+    body: web::Json<StorageQuotaRequest>,
+) -> Result<HttpResponse, Error> {
+    let admin_id = extract_user_id_from_request(&req, &config)?;
+
+    if !check_permission(admin_id, &pool, Permission::ManageUsers).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin access required"
+        })));
+    }
+
+    // Only users below one's own role can have their quota changed
+    let Some(target_role) = user_role(&pool, *target_user_id).await? else {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "User not found"
+        })));
+    };
+
+    if !roles::outranks(&pool, admin_id, &target_role)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+    {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "You can only manage users with a lower role than yours"
+        })));
+    }
+
+    let storage_quota = match body.storage_quota.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(value) => match parse_size(value) {
+            Ok(size) if size >= 0 => Some(size),
+            _ => {
+                return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": format!("Invalid storage quota: {}", value)
+                })));
+            }
+        },
+    };
+
+    let updated = sqlx::query("UPDATE users SET storage_quota = $1 WHERE id = $2")
+        .bind(storage_quota)
+        .bind(*target_user_id)
+        .execute(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if updated.rows_affected() == 0 {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "User not found"
+        })));
+    }
+
+    let usage = quota::storage_usage(pool.as_ref(), *target_user_id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(usage))
+}
+
+pub async fn get_mail_templates(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if !check_permission(user_id, &pool, Permission::ManageSettings).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin access required"
+        })));
+    }
+
+    let mut templates = Vec::new();
+    for default in mailer::TEMPLATES {
+        if let Some(template) = mailer::load_template(&pool, default.name)
+            .await
+            .map_err(error::ErrorInternalServerError)?
+        {
+            templates.push(template);
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "templates": templates
+    })))
+}
+
+pub async fn update_mail_template(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    name: web::Path<String>,
+    body: web::Json<MailTemplateRequest>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if !check_permission(user_id, &pool, Permission::ManageSettings).await.unwrap_or(false) {
         return Ok(HttpResponse::Forbidden().json(serde_json::json!({
             "error": "Admin access required"
         })));
     }
 
-    // Check if target is admin
-    let is_target_admin: (bool,) = sqlx::query_as(
-        "SELECT COALESCE(is_admin, false) FROM users WHERE id = $1"
+    if mailer::default_template(&name).is_none() {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Mail template not found"
+        })));
+    }
+
+    if body.subject.trim().is_empty() || body.body.trim().is_empty() {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Subject and body are required"
+        })));
+    }
+
+    sqlx::query(
+        r#"
+        INSERT INTO mail_templates (name, subject, body)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (name) DO UPDATE SET subject = EXCLUDED.subject, body = EXCLUDED.body, updated_at = NOW()
+        "#
     )
-    .bind(*target_user_id)
-    .fetch_one(pool.as_ref())
+    .bind(name.as_str())
+    .bind(body.subject.trim())
+    .bind(&body.body)
+    .execute(pool.as_ref())
     .await
     .map_err(error::ErrorInternalServerError)?;
 
-    if is_target_admin.0 && body.blocked {
+    let template = mailer::load_template(&pool, &name)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(template))
+}
+
+/// Drops an admin's changes so the built-in template is used again.
+pub async fn reset_mail_template(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    name: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if !check_permission(user_id, &pool, Permission::ManageSettings).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin access required"
+        })));
+    }
+
+    if mailer::default_template(&name).is_none() {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Mail template not found"
+        })));
+    }
+
+    sqlx::query("DELETE FROM mail_templates WHERE name = $1")
+        .bind(name.as_str())
+        .execute(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let template = mailer::load_template(&pool, &name)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(template))
+}
+
+/// Sends the test template, reporting SMTP errors back to the admin.
+pub async fn send_test_mail(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    mailer: web::Data<Mailer>,
+    req: HttpRequest,
+    body: web::Json<TestMailRequest>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if !check_permission(user_id, &pool, Permission::ManageSettings).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin access required"
+        })));
+    }
+
+    match mailer.send_template(&pool, mailer::TEST, body.to.trim(), &[]).await {
+        Ok(()) => Ok(HttpResponse::Ok().json(serde_json::json!({
+            "message": format!("Test email sent to {}", body.to.trim())
+        }))),
+        Err(MailError::Database(e)) => Err(error::ErrorInternalServerError(e)),
+        Err(MailError::NotConfigured) => Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "error": MailError::NotConfigured.to_string()
+        }))),
+        Err(e @ MailError::Address(_)) => Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": e.to_string()
+        }))),
+        Err(e) => Ok(HttpResponse::BadGateway().json(serde_json::json!({
+            "error": e.to_string()
+        }))),
+    }
+}
+
+async fn user_exists(pool: &PgPool, user_id: i32) -> Result<bool, Error> {
+    let exists: (bool,) = sqlx::query_as("SELECT EXISTS(SELECT 1 FROM users WHERE id = $1)")
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(exists.0)
+}
+
+pub async fn block_user(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    target_user_id: web::Path<i32>,
+    body: web::Json<BlockUserRequest>,
+) -> Result<HttpResponse, Error> {
+    let admin_id = extract_user_id_from_request(&req, &config)?;
+
+    if !check_permission(admin_id, &pool, Permission::ManageUsers).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin access required"
+        })));
+    }
+
+    // Only users below one's own role can be blocked or unblocked
+    let Some(target_role) = user_role(&pool, *target_user_id).await? else {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "User not found"
+        })));
+    };
+
+    if !roles::outranks(&pool, admin_id, &target_role)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+    {
         return Ok(HttpResponse::Forbidden().json(serde_json::json!({
-            "error": "Cannot block admin users"
+            "error": "You can only manage users with a lower role than yours"
         })));
     }
 
+    let (was_blocked,): (bool,) = sqlx::query_as("SELECT COALESCE(is_blocked, false) FROM users WHERE id = $1")
+        .bind(*target_user_id)
+        .fetch_one(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
     sqlx::query("UPDATE users SET is_blocked = $1 WHERE id = $2")
         .bind(body.blocked)
         .bind(*target_user_id)
@@ -338,6 +748,24 @@ pub async fn block_user(
         .await
         .map_err(error::ErrorInternalServerError)?;
 
+    // A blocked user is signed out everywhere
+    if body.blocked {
+        sessions::revoke_all(&pool, *target_user_id)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    }
+
+    audit::record(
+        &pool,
+        &req,
+        Some(admin_id),
+        if body.blocked { audit::USER_BLOCKED } else { audit::USER_UNBLOCKED },
+        Some(&format!("user:{}", target_user_id)),
+        serde_json::json!({ "blocked": was_blocked }),
+        serde_json::json!({ "blocked": body.blocked }),
+    )
+    .await;
+
     let action = if body.blocked { "blocked" } else { "unblocked" };
 
     Ok(HttpResponse::Ok().json(serde_json::json!({
@@ -345,72 +773,235 @@ pub async fn block_user(
     })))
 }
 
-pub async fn promote_user(
+/// Lifts a lockout from failed logins and resets the failure count.
+pub async fn unlock_user(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
     req: HttpRequest,
     target_user_id: web::Path<i32>,
-    body: web::Json<PromoteUserRequest>,
 ) -> Result<HttpResponse, Error> {
     let admin_id = extract_user_id_from_request(&req, &config)?;
 
-    // Only allow user ID 1 (super admin) to promote/demote users
-    if admin_id != 1 {
+    if !check_permission(admin_id, &pool, Permission::ManageUsers).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin access required"
+        })));
+    }
+
+    // As with blocking, only users below one's own role
+    let Some(target_role) = user_role(&pool, *target_user_id).await? else {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "User not found"
+        })));
+    };
+
+    if !roles::outranks(&pool, admin_id, &target_role)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+    {
         return Ok(HttpResponse::Forbidden().json(serde_json::json!({
-            "error": "Only the super admin can manage user roles"
+            "error": "You can only manage users with a lower role than yours"
         })));
     }
 
-    // Check if target user exists
-    let user_exists: (bool,) = sqlx::query_as(
-        "SELECT EXISTS(SELECT 1 FROM users WHERE id = $1)"
+    rate_limit::clear_failed_logins(&pool, *target_user_id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    audit::record(
+        &pool,
+        &req,
+        Some(admin_id),
+        audit::USER_UNLOCKED,
+        Some(&format!("user:{}", target_user_id)),
+        serde_json::Value::Null,
+        serde_json::Value::Null,
     )
-    .bind(*target_user_id)
-    .fetch_one(pool.as_ref())
-    .await
-    .map_err(error::ErrorInternalServerError)?;
+    .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "User unlocked successfully"
+    })))
+}
+
+/// The role of a user, or `None` if there is no such user.
+async fn user_role(pool: &PgPool, user_id: i32) -> Result<Option<String>, Error> {
+    let role: Option<(String,)> = sqlx::query_as("SELECT role FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(role.map(|(role,)| role))
+}
+
+/// Gives a user another role. Both the user's current and new role have to
+/// be below the caller's, so admins manage moderators, users and guests, and
+/// only the owner appoints admins. The owner role itself only changes hands
+/// through `transfer_ownership`.
+pub async fn set_user_role(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    target_user_id: web::Path<i32>,
+    body: web::Json<SetRoleRequest>,
+) -> Result<HttpResponse, Error> {
+    let admin_id = extract_user_id_from_request(&req, &config)?;
+
+    if !check_permission(admin_id, &pool, Permission::ManageRoles).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin access required"
+        })));
+    }
+
+    if !roles::is_valid(&body.role) {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("Unknown role: {}", body.role)
+        })));
+    }
+
+    if body.role == roles::OWNER {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Use the ownership transfer to make someone the owner"
+        })));
+    }
+
+    if *target_user_id == admin_id {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Cannot change your own role"
+        })));
+    }
 
-    if !user_exists.0 {
+    let Some(target_role) = user_role(&pool, *target_user_id).await? else {
         return Ok(HttpResponse::NotFound().json(serde_json::json!({
             "error": "User not found"
         })));
+    };
+
+    let allowed = roles::outranks(&pool, admin_id, &target_role)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        && roles::outranks(&pool, admin_id, &body.role)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    if !allowed {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "You can only assign roles below your own, to users below your own role"
+        })));
+    }
+
+    sqlx::query("UPDATE users SET role = $1 WHERE id = $2")
+        .bind(&body.role)
+        .bind(*target_user_id)
+        .execute(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    audit::record(
+        &pool,
+        &req,
+        Some(admin_id),
+        audit::USER_ROLE_CHANGED,
+        Some(&format!("user:{}", target_user_id)),
+        serde_json::json!({ "role": target_role }),
+        serde_json::json!({ "role": body.role }),
+    )
+    .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": format!("User is now {} {}", if body.role == roles::ADMIN { "an" } else { "a" }, body.role)
+    })))
+}
+
+/// Hands the instance to another user. The current owner stays on as an
+/// admin.
+pub async fn transfer_ownership(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    body: web::Json<TransferOwnershipRequest>,
+) -> Result<HttpResponse, Error> {
+    let owner_id = extract_user_id_from_request(&req, &config)?;
+
+    let role = roles::effective_role(&pool, owner_id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    if role != roles::OWNER {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Only the owner can transfer ownership"
+        })));
     }
 
-    // Prevent self-demotion
-    if *target_user_id == admin_id && !body.promote {
+    if body.user_id == owner_id {
         return Ok(HttpResponse::BadRequest().json(serde_json::json!({
-            "error": "Cannot demote yourself"
+            "error": "You are already the owner"
         })));
     }
 
-    // Check if there would be no admins left
-    if !body.promote {
-        let admin_count: (i64,) = sqlx::query_as(
-            "SELECT COUNT(*) FROM users WHERE is_admin = TRUE AND id != $1"
-        )
-        .bind(*target_user_id)
+    let (password_hash,): (String,) = sqlx::query_as("SELECT password_hash FROM users WHERE id = $1")
+        .bind(owner_id)
         .fetch_one(pool.as_ref())
         .await
         .map_err(error::ErrorInternalServerError)?;
 
-        if admin_count.0 == 0 {
+    if !verify_password(&body.password, &password_hash)
+        .map_err(|_| error::ErrorInternalServerError("Password verification failed"))?
+    {
+        return Ok(HttpResponse::Unauthorized().json(serde_json::json!({
+            "error": "Invalid password"
+        })));
+    }
+
+    let target: Option<(bool,)> = sqlx::query_as("SELECT COALESCE(is_blocked, false) FROM users WHERE id = $1")
+        .bind(body.user_id)
+        .fetch_optional(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    match target {
+        None => {
+            return Ok(HttpResponse::NotFound().json(serde_json::json!({
+                "error": "User not found"
+            })));
+        }
+        Some((true,)) => {
             return Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                "error": "Cannot demote the last admin user"
+                "error": "Cannot transfer ownership to a blocked user"
             })));
         }
+        Some(_) => {}
     }
 
-    sqlx::query("UPDATE users SET is_admin = $1 WHERE id = $2")
-        .bind(body.promote)
-        .bind(*target_user_id)
-        .execute(pool.as_ref())
+    // There is only ever one owner, so step down first
+    let mut tx = pool.begin().await.map_err(error::ErrorInternalServerError)?;
+
+    sqlx::query("UPDATE users SET role = 'admin' WHERE id = $1")
+        .bind(owner_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    sqlx::query("UPDATE users SET role = 'owner' WHERE id = $1")
+        .bind(body.user_id)
+        .execute(&mut *tx)
         .await
         .map_err(error::ErrorInternalServerError)?;
 
-    let action = if body.promote { "promoted to admin" } else { "demoted from admin" };
+    tx.commit().await.map_err(error::ErrorInternalServerError)?;
+
+    audit::record(
+        &pool,
+        &req,
+        Some(owner_id),
+        audit::OWNERSHIP_TRANSFERRED,
+        Some(&format!("user:{}", body.user_id)),
+        serde_json::json!({ "owner": owner_id }),
+        serde_json::json!({ "owner": body.user_id }),
+    )
+    .await;
 
     Ok(HttpResponse::Ok().json(serde_json::json!({
-        "message": format!("User {} successfully", action)
+        "message": "Ownership transferred successfully"
     })))
 }
 
@@ -422,7 +1013,7 @@ pub async fn quick_settings(
 ) -> Result<HttpResponse, Error> {
     let user_id = extract_user_id_from_request(&req, &config)?;
 
-    if !check_is_admin(user_id, &pool).await.unwrap_or(false) {
+    if !check_permission(user_id, &pool, Permission::ManageSettings).await.unwrap_or(false) {
         return Ok(HttpResponse::Forbidden().json(serde_json::json!({
             "error": "Admin access required"
         })));
@@ -434,12 +1025,32 @@ pub async fn quick_settings(
                 error::ErrorBadRequest("Invalid value for allowRegistration")
             })?;
 
+            let previous: Option<(bool,)> =
+                sqlx::query_as("SELECT allow_registration FROM settings ORDER BY id LIMIT 1")
+                    .fetch_optional(pool.as_ref())
+                    .await
+                    .map_err(error::ErrorInternalServerError)?;
+
             sqlx::query("UPDATE settings SET allow_registration = $1 WHERE id = (SELECT MIN(id) FROM settings)")
                 .bind(value)
                 .execute(pool.as_ref())
                 .await
                 .map_err(error::ErrorInternalServerError)?;
 
+            let previous = previous.map(|(v,)| v);
+            if previous != Some(value) {
+                audit::record(
+                    &pool,
+                    &req,
+                    Some(user_id),
+                    audit::SETTINGS_UPDATED,
+                    Some("settings"),
+                    serde_json::json!({ "allowRegistration": previous }),
+                    serde_json::json!({ "allowRegistration": value }),
+                )
+                .await;
+            }
+
             Ok(HttpResponse::Ok().json(serde_json::json!({
                 "message": "Setting updated successfully"
             })))
@@ -450,25 +1061,75 @@ pub async fn quick_settings(
     }
 }
 
-pub async fn promote_first_admin(pool: web::Data<PgPool>) -> Result<HttpResponse, Error> {
-    sqlx::query("UPDATE users SET is_admin = TRUE WHERE id = (SELECT MIN(id) FROM users)")
-        .execute(pool.as_ref())
-        .await
-        .map_err(error::ErrorInternalServerError)?;
+/// Makes the caller the owner of an instance without one, using the token
+/// printed at startup.
+pub async fn bootstrap_owner(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    bootstrap: web::Data<Bootstrap>,
+    req: HttpRequest,
+    body: web::Json<BootstrapRequest>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if !bootstrap.redeem(body.token.trim()) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Invalid bootstrap token"
+        })));
+    }
+
+    let claimed = sqlx::query(
+        "UPDATE users SET role = 'owner' WHERE id = $1 AND NOT EXISTS (SELECT 1 FROM users WHERE role = 'owner')"
+    )
+    .bind(user_id)
+    .execute(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    if claimed.rows_affected() == 0 {
+        return Ok(HttpResponse::Conflict().json(serde_json::json!({
+            "error": "This instance already has an owner"
+        })));
+    }
+
+    audit::record(
+        &pool,
+        &req,
+        Some(user_id),
+        audit::OWNERSHIP_CLAIMED,
+        Some(&format!("user:{}", user_id)),
+        serde_json::Value::Null,
+        serde_json::json!({ "owner": user_id }),
+    )
+    .await;
 
     Ok(HttpResponse::Ok().json(serde_json::json!({
-        "message": "First user promoted to admin"
+        "message": "You are now the owner of this instance"
     })))
 }
 
-pub async fn cleanup_expired_uploads(pool: PgPool) {
+pub async fn cleanup_expired_uploads(pool: PgPool, storage: Arc<dyn Storage>) {
     let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(3600)); // 1 hour
+    let mut runs: u64 = 0;
 
     loop {
         interval.tick().await;
 
+        // Orphan sweep lists the whole store, so only do it once a day
+        if runs.is_multiple_of(24) {
+            blobs::purge_unreferenced(&pool, storage.as_ref()).await;
+            remove_orphaned_objects(&pool, storage.as_ref()).await;
+            webhooks::prune_deliveries(&pool).await;
+            sessions::prune(&pool).await;
+            password_resets::prune(&pool).await;
+            email_verification::prune(&pool).await;
+        }
+        runs += 1;
+
         log::info!("Running expired uploads cleanup...");
 
+        crate::handlers::tus::purge_expired_sessions(&pool).await;
+
         // Get settings
         let settings: Option<Settings> = sqlx::query_as("SELECT * FROM settings ORDER BY id LIMIT 1")
             .fetch_optional(&pool)
@@ -481,8 +1142,8 @@ pub async fn cleanup_expired_uploads(pool: PgPool) {
             .unwrap_or_else(|| "unavailable".to_string());
 
         // Find expired uploads
-        let expired_uploads: Vec<(String, String)> = sqlx::query_as(
-            "SELECT upload_id, files FROM uploads WHERE expires_at IS NOT NULL AND expires_at < NOW() AND is_deleted = FALSE"
+        let expired_uploads: Vec<(String,)> = sqlx::query_as(
+            "SELECT upload_id FROM uploads WHERE expires_at IS NOT NULL AND expires_at < NOW() AND is_deleted = FALSE"
         )
         .fetch_all(&pool)
         .await
@@ -490,45 +1151,115 @@ pub async fn cleanup_expired_uploads(pool: PgPool) {
 
         log::info!("Found {} expired uploads, action: {}", expired_uploads.len(), expiration_action);
 
-        for (upload_id, files_json) in expired_uploads {
+        for (upload_id,) in expired_uploads {
             if expiration_action == "delete" {
-                // Parse files and delete
-                if let Ok(files) = serde_json::from_str::<Vec<String>>(&files_json) {
-                    for filename in files {
-                        let sanitized = sanitize_filename_safe(&filename);
-                        let file_path = format!("./uploads/{}_{}", upload_id, sanitized);
-                        if let Err(e) = std::fs::remove_file(&file_path) {
-                            log::warn!("Failed to delete file {}: {}", file_path, e);
-                        } else {
-                            log::info!("Deleted expired file: {}", file_path);
-                        }
+                // Mark as deleted and drop its blob references together
+                let unreferenced = match delete_expired_upload(&pool, &upload_id).await {
+                    Ok(Some(unreferenced)) => unreferenced,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        log::error!("Failed to mark upload as deleted: {}", e);
+                        continue;
                     }
-                }
+                };
+                log::info!("Marked upload as deleted: {}", upload_id);
+                webhooks::notify(&pool, webhooks::UPLOAD_EXPIRED, &upload_id, serde_json::json!({
+                    "reason": "Expired"
+                }));
 
-                // Mark as deleted
-                if let Err(e) = sqlx::query(
-                    "UPDATE uploads SET is_deleted = TRUE, deleted_at = NOW(), deletion_reason = 'Expired' WHERE upload_id = $1"
-                )
-                .bind(&upload_id)
-                .execute(&pool)
-                .await
-                {
-                    log::error!("Failed to mark upload as deleted: {}", e);
-                } else {
-                    log::info!("Marked upload as deleted: {}", upload_id);
-                }
+                retention::remove_contents(&pool, storage.as_ref(), &upload_id, &unreferenced).await;
             } else if expiration_action == "unavailable" {
-                // Just mark as unavailable
-                if let Err(e) = sqlx::query("UPDATE uploads SET is_available = FALSE WHERE upload_id = $1")
-                    .bind(&upload_id)
-                    .execute(&pool)
-                    .await
-                {
-                    log::error!("Failed to mark upload as unavailable: {}", e);
-                } else {
-                    log::info!("Marked upload as unavailable: {}", upload_id);
+                // Just mark as unavailable; uploads already unavailable stay
+                // quiet so the webhook fires once
+                match disable_expired_upload(&pool, &upload_id).await {
+                    Err(e) => log::error!("Failed to mark upload as unavailable: {}", e),
+                    Ok(true) => {
+                        log::info!("Marked upload as unavailable: {}", upload_id);
+                        webhooks::notify(&pool, webhooks::UPLOAD_EXPIRED, &upload_id, serde_json::json!({
+                            "reason": "Expired"
+                        }));
+                    }
+                    Ok(false) => {}
                 }
             }
         }
     }
 }
+
+/// Returns the blobs left unreferenced, or `None` if the upload was already
+/// deleted.
+async fn delete_expired_upload(pool: &PgPool, upload_id: &str) -> Result<Option<Vec<String>>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    retention::log_deletion(&mut tx, upload_id, "Expired").await?;
+    let unreferenced = retention::mark_deleted(&mut tx, upload_id, "Expired").await?;
+    if unreferenced.is_some() {
+        tx.commit().await?;
+    }
+
+    Ok(unreferenced)
+}
+
+/// Makes an expired upload unavailable and logs it. Returns whether it was
+/// still available.
+async fn disable_expired_upload(pool: &PgPool, upload_id: &str) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let updated = sqlx::query("UPDATE uploads SET is_available = FALSE WHERE upload_id = $1 AND is_available = TRUE")
+        .bind(upload_id)
+        .execute(&mut *tx)
+        .await?;
+
+    if updated.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    retention::log_deletion(&mut tx, upload_id, "Expired").await?;
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+/// Removes stored objects that neither a blob nor a live legacy file points
+/// at, such as files written by a request that crashed before committing.
+/// Recent objects are left alone so in-flight uploads are never touched.
+async fn remove_orphaned_objects(pool: &PgPool, storage: &dyn Storage) {
+    let objects = match storage.list("").await {
+        Ok(objects) => objects,
+        Err(e) => {
+            log::error!("Failed to list stored objects: {}", e);
+            return;
+        }
+    };
+
+    let known: std::collections::HashSet<String> =
+        match sqlx::query_scalar::<_, String>(
+            r#"
+            SELECT storage_key FROM blobs
+            UNION
+            SELECT f.stored_name
+            FROM upload_files f
+            JOIN uploads up ON up.upload_id = f.upload_id
+            WHERE up.is_deleted = FALSE
+            "#,
+        )
+        .fetch_all(pool)
+        .await
+        {
+            Ok(names) => names.into_iter().collect(),
+            Err(e) => {
+                log::error!("Failed to load stored file names: {}", e);
+                return;
+            }
+        };
+
+    let cutoff = chrono::Utc::now() - chrono::Duration::hours(24);
+    for object in objects {
+        let is_old = object.last_modified.map(|t| t < cutoff).unwrap_or(false);
+        if is_old && !known.contains(&object.key) {
+            match storage.delete(&object.key).await {
+                Ok(()) => log::info!("Removed orphaned object {} ({} bytes)", object.key, object.size),
+                Err(e) => log::warn!("Failed to remove orphaned object {}: {}", object.key, e),
+            }
+        }
+    }
+}
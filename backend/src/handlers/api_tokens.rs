@@ -0,0 +1,137 @@
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+
+use crate::api_tokens::{self, SCOPES, TOKEN_PREFIX};
+use crate::audit;
+use crate::config::Config;
+use crate::models::{ApiTokenInfo, CreateApiTokenRequest};
+use crate::utils::{calculate_expiry_time, extract_user_id_from_request};
+
+pub async fn get_tokens(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    let tokens: Vec<ApiTokenInfo> = sqlx::query_as(
+        r#"
+        SELECT id, name, token_prefix, scopes, created_at, last_used_at, expires_at
+        FROM api_tokens
+        WHERE user_id = $1 AND revoked_at IS NULL
+        ORDER BY created_at DESC
+        "#
+    )
+    .bind(user_id)
+    .fetch_all(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "tokens": tokens,
+        "available_scopes": SCOPES
+    })))
+}
+
+/// Creates a token. It is only shown in this response; afterwards just its
+/// first characters are, to tell tokens apart.
+pub async fn create_token(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    body: web::Json<CreateApiTokenRequest>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    let name = body.name.trim();
+    if name.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Token name is required"
+        })));
+    }
+
+    if body.scopes.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Select at least one scope"
+        })));
+    }
+
+    if let Some(unknown) = body.scopes.iter().find(|s| !SCOPES.contains(&s.as_str())) {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("Unknown scope: {}", unknown)
+        })));
+    }
+
+    let mut scopes = body.scopes.clone();
+    scopes.sort();
+    scopes.dedup();
+
+    let expires_at = body.expires_in.as_deref().and_then(calculate_expiry_time);
+    let (token, token_hash) = api_tokens::generate_token();
+    let token_prefix = &token[..TOKEN_PREFIX.len() + 8];
+
+    let info: ApiTokenInfo = sqlx::query_as(
+        r#"
+        INSERT INTO api_tokens (user_id, name, token_hash, token_prefix, scopes, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, name, token_prefix, scopes, created_at, last_used_at, expires_at
+        "#
+    )
+    .bind(user_id)
+    .bind(name)
+    .bind(&token_hash)
+    .bind(token_prefix)
+    .bind(&scopes)
+    .bind(expires_at)
+    .fetch_one(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    audit::record(
+        &pool,
+        &req,
+        Some(user_id),
+        audit::API_TOKEN_CREATED,
+        Some(&format!("api_token:{}", info.id)),
+        serde_json::Value::Null,
+        serde_json::json!({
+            "name": info.name,
+            "scopes": info.scopes,
+            "expires_at": info.expires_at
+        }),
+    )
+    .await;
+
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "token": token,
+        "info": info
+    })))
+}
+
+pub async fn revoke_token(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    token_id: web::Path<i32>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    let revoked = sqlx::query(
+        "UPDATE api_tokens SET revoked_at = NOW() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL"
+    )
+    .bind(*token_id)
+    .bind(user_id)
+    .execute(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    if revoked.rows_affected() == 0 {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Token not found"
+        })));
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Token revoked successfully"
+    })))
+}
@@ -0,0 +1,103 @@
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+
+use crate::config::Config;
+use crate::models::{AuditEvent, AuditEventQuery};
+use crate::roles::Permission;
+use crate::utils::{check_permission, extract_user_id_from_request, parse_date_bound};
+
+const DEFAULT_PER_PAGE: i64 = 50;
+const MAX_PER_PAGE: i64 = 500;
+
+/// The filters shared by the count and the listing; see `AuditEventQuery`.
+const FILTER: &str = r#"
+    WHERE ($1::INTEGER IS NULL OR actor_id = $1)
+      AND ($2::TEXT IS NULL OR action = $2 OR split_part(action, '.', 1) = $2)
+      AND ($3::TEXT IS NULL OR target = $3)
+      AND ($4::TIMESTAMPTZ IS NULL OR created_at >= $4)
+      AND ($5::TIMESTAMPTZ IS NULL OR created_at < $5)
+"#;
+
+/// Lists audit events, newest first, a page at a time.
+pub async fn get_audit_events(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    query: web::Query<AuditEventQuery>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if !check_permission(user_id, &pool, Permission::ViewAuditLog).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin access required"
+        })));
+    }
+
+    let from = match query.from.as_deref().filter(|v| !v.is_empty()) {
+        Some(value) => match parse_date_bound(value, false) {
+            Some(from) => Some(from),
+            None => {
+                return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "Invalid from date, use YYYY-MM-DD or an RFC 3339 timestamp"
+                })));
+            }
+        },
+        None => None,
+    };
+
+    let to = match query.to.as_deref().filter(|v| !v.is_empty()) {
+        Some(value) => match parse_date_bound(value, true) {
+            Some(to) => Some(to),
+            None => {
+                return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "Invalid to date, use YYYY-MM-DD or an RFC 3339 timestamp"
+                })));
+            }
+        },
+        None => None,
+    };
+
+    let action = query.action.as_deref().filter(|v| !v.is_empty());
+    let target = query.target.as_deref().filter(|v| !v.is_empty());
+
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE);
+
+    let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM audit_events {}", FILTER))
+        .bind(query.actor_id)
+        .bind(action)
+        .bind(target)
+        .bind(from)
+        .bind(to)
+        .fetch_one(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let events: Vec<AuditEvent> = sqlx::query_as(&format!(
+        r#"
+        SELECT id, actor_id, actor_name, action, target, before, after, ip, created_at
+        FROM audit_events
+        {}
+        ORDER BY id DESC
+        LIMIT $6 OFFSET $7
+        "#,
+        FILTER
+    ))
+    .bind(query.actor_id)
+    .bind(action)
+    .bind(target)
+    .bind(from)
+    .bind(to)
+    .bind(per_page)
+    .bind((page - 1).saturating_mul(per_page))
+    .fetch_all(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "events": events,
+        "total": total,
+        "page": page,
+        "per_page": per_page
+    })))
+}
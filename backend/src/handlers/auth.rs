@@ -1,34 +1,73 @@
 use actix_multipart::Multipart;
-use actix_web::{cookie::Cookie, error, web, Error, HttpRequest, HttpResponse};
+use actix_web::{cookie::Cookie, error, web, Error, HttpMessage, HttpRequest, HttpResponse};
 use futures_util::StreamExt;
 use sha2::{Digest, Sha256};
 use sqlx::PgPool;
 use std::io::Write;
 
-use crate::auth::{generate_jwt, hash_password, verify_password};
+use crate::audit;
+use crate::auth::{generate_two_factor_token, hash_password, validate_two_factor_token, verify_password};
 use crate::config::Config;
-use crate::models::{AuthResponse, LoginRequest, RegisterRequest, Settings, User, UserInfo};
+use crate::email_verification;
+use crate::mailer::Mailer;
+use crate::models::{
+    AuthResponse, LoginRequest, RefreshRequest, RegisterRequest, ResendVerificationRequest, Settings,
+    TwoFactorLoginRequest, User, UserInfo, VerifyEmailRequest, REGISTRATION_INVITE_ONLY, REGISTRATION_VERIFY_EMAIL,
+};
+use crate::quota;
+use crate::rate_limit::{self, Bucket, RateLimits};
+use crate::roles;
+use crate::sessions::{self, AuthenticatedSession, SessionTokens};
+use crate::two_factor;
 use crate::utils::extract_user_id_from_request;
 
+/// The refresh token cookie is kept away from the static file routes; it is
+/// read by `/api/refresh` and `/api/logout`.
+const REFRESH_COOKIE: &str = "refresh_token";
+const REFRESH_COOKIE_PATH: &str = "/api";
+
+pub fn session_cookies(config: &Config, tokens: &SessionTokens) -> (Cookie<'static>, Cookie<'static>) {
+    let access = Cookie::build("auth_token", tokens.access_token.clone())
+        .path("/")
+        .max_age(actix_web::cookie::time::Duration::minutes(config.access_token_minutes))
+        .http_only(true)
+        .finish();
+
+    let refresh = Cookie::build(REFRESH_COOKIE, tokens.refresh_token.clone())
+        .path(REFRESH_COOKIE_PATH)
+        .max_age(actix_web::cookie::time::Duration::days(config.refresh_token_days))
+        .http_only(true)
+        .finish();
+
+    (access, refresh)
+}
+
 pub async fn register(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
+    mailer: web::Data<Mailer>,
+    http_req: HttpRequest,
     req: web::Json<RegisterRequest>,
 ) -> Result<HttpResponse, Error> {
+    if config.oidc.as_ref().is_some_and(|o| o.disable_local_registration) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Accounts are created through single sign-on"
+        })));
+    }
+
     // Check if registration is allowed
-    let settings: Option<Settings> = sqlx::query_as(
+    let settings: Settings = sqlx::query_as(
         "SELECT * FROM settings ORDER BY id LIMIT 1"
     )
     .fetch_optional(pool.as_ref())
     .await
-    .map_err(error::ErrorInternalServerError)?;
+    .map_err(error::ErrorInternalServerError)?
+    .unwrap_or_default();
 
-    if let Some(s) = settings {
-        if !s.allow_registration {
-            return Ok(HttpResponse::Forbidden().json(serde_json::json!({
-                "error": "User registration is currently disabled"
-            })));
-        }
+    if !settings.allow_registration {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "User registration is currently disabled"
+        })));
     }
 
     // Check if user exists
@@ -55,61 +94,199 @@ pub async fn register(
 
     let is_first_user = user_count.0 == 0;
 
+    // The first user becomes the admin, with nobody to invite or verify them
+    let invite_only = settings.registration_mode == REGISTRATION_INVITE_ONLY && !is_first_user;
+    let mut verify_email = settings.registration_mode == REGISTRATION_VERIFY_EMAIL && !is_first_user;
+
+    let invite_code = req.invite_code.as_deref().map(str::trim).unwrap_or("");
+    if invite_only && invite_code.is_empty() {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Registration requires an invite code"
+        })));
+    }
+
     // Hash password
     let password_hash = hash_password(&req.password)
         .map_err(|_| error::ErrorInternalServerError("Failed to hash password"))?;
 
+    let mut tx = pool.begin().await.map_err(error::ErrorInternalServerError)?;
+
+    if invite_only {
+        let invite: Option<(i32, Vec<String>)> = sqlx::query_as(
+            r#"
+            SELECT id, email_domains FROM invites
+            WHERE code = $1 AND revoked_at IS NULL
+              AND (expires_at IS NULL OR expires_at > NOW())
+              AND (max_uses IS NULL OR uses < max_uses)
+            FOR UPDATE
+            "#
+        )
+        .bind(invite_code)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+        let Some((invite_id, email_domains)) = invite else {
+            return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+                "error": "Invalid or expired invite code"
+            })));
+        };
+
+        if !email_domains.is_empty() {
+            let domain = req.email.rsplit_once('@').map(|(_, d)| d.to_lowercase()).unwrap_or_default();
+            if !email_domains.contains(&domain) {
+                return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+                    "error": format!("This invite is only for {} addresses", email_domains.join(", "))
+                })));
+            }
+            // Otherwise anyone could claim an address at the domain
+            verify_email = true;
+        }
+
+        sqlx::query("UPDATE invites SET uses = uses + 1 WHERE id = $1")
+            .bind(invite_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    }
+
+    if verify_email && !mailer.is_enabled() {
+        return Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "error": "Registration needs email verification, but email is not configured"
+        })));
+    }
+
+    // The first account owns the instance
+    let role = if is_first_user { roles::OWNER } else { roles::USER };
+
     // Create user
     let user_id: (i32,) = sqlx::query_as(
-        "INSERT INTO users (username, email, password_hash, is_admin) VALUES ($1, $2, $3, $4) RETURNING id"
+        "INSERT INTO users (username, email, password_hash, role, email_verified) VALUES ($1, $2, $3, $4, $5) RETURNING id"
     )
     .bind(&req.username)
     .bind(&req.email)
     .bind(&password_hash)
-    .bind(is_first_user)
-    .fetch_one(pool.as_ref())
+    .bind(role)
+    .bind(!verify_email)
+    .fetch_one(&mut *tx)
     .await
     .map_err(error::ErrorInternalServerError)?;
 
-    // Generate JWT
-    let token = generate_jwt(user_id.0, &config.jwt_secret)
+    tx.commit().await.map_err(error::ErrorInternalServerError)?;
+
+    // No session until the emailed link has been opened
+    if verify_email {
+        email_verification::send(
+            mailer.into_inner(),
+            pool.get_ref().clone(),
+            user_id.0,
+            req.username.clone(),
+            req.email.clone(),
+        );
+
+        return Ok(HttpResponse::Created().json(serde_json::json!({
+            "message": "Check your email to activate your account",
+            "verification_required": true
+        })));
+    }
+
+    // Start a session and generate its tokens
+    let tokens = sessions::start(&pool, &config, user_id.0, &http_req)
+        .await
         .map_err(|_| error::ErrorInternalServerError("Failed to generate token"))?;
 
     let message = if is_first_user {
-        "First user created successfully with admin privileges"
+        "First user created successfully as the owner"
     } else {
         "User created successfully"
     };
 
-    let cookie = Cookie::build("auth_token", &token)
-        .path("/")
-        .max_age(actix_web::cookie::time::Duration::days(1))
-        .http_only(true)
-        .finish();
+    let (access_cookie, refresh_cookie) = session_cookies(&config, &tokens);
 
     Ok(HttpResponse::Created()
-        .cookie(cookie)
+        .cookie(access_cookie)
+        .cookie(refresh_cookie)
         .json(AuthResponse {
             message: message.to_string(),
-            token,
+            token: tokens.access_token,
+            refresh_token: tokens.refresh_token,
+            expires_in: config.access_token_minutes * 60,
             user: UserInfo {
                 id: user_id.0,
                 username: req.username.clone(),
                 email: req.email.clone(),
+                role: role.to_string(),
                 is_admin: is_first_user,
                 avatar: None,
             },
         }))
 }
 
+/// Activates an account with the link emailed at registration.
+pub async fn verify_email(
+    pool: web::Data<PgPool>,
+    body: web::Json<VerifyEmailRequest>,
+) -> Result<HttpResponse, Error> {
+    let verified = email_verification::verify(&pool, &body.token)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if verified.is_none() {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "This verification link is invalid or has expired"
+        })));
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Email verified, you can now log in"
+    })))
+}
+
+/// Sends another verification link to an account that is still waiting for
+/// one. The answer is the same whether or not there is such an account.
+pub async fn resend_verification(
+    pool: web::Data<PgPool>,
+    mailer: web::Data<Mailer>,
+    body: web::Json<ResendVerificationRequest>,
+) -> Result<HttpResponse, Error> {
+    if !mailer.is_enabled() {
+        return Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "error": "Email is not configured"
+        })));
+    }
+
+    let user: Option<(i32, String, String)> = sqlx::query_as(
+        "SELECT id, username, email FROM users WHERE email = $1 AND email_verified = FALSE AND is_blocked IS NOT TRUE"
+    )
+    .bind(body.email.trim())
+    .fetch_optional(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    if let Some((user_id, username, email)) = user {
+        email_verification::send(mailer.into_inner(), pool.get_ref().clone(), user_id, username, email);
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "If that address is waiting for verification, a new link is on its way"
+    })))
+}
+
 pub async fn login(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
+    rate_limits: web::Data<RateLimits>,
+    http_req: HttpRequest,
     req: web::Json<LoginRequest>,
 ) -> Result<HttpResponse, Error> {
+    // Guessing one account's password from many addresses is limited too
+    if let Err(retry_after) = rate_limits.check(Bucket::Account, &req.username_or_email.to_lowercase()) {
+        return Ok(rate_limit::too_many_requests(retry_after, "Too many login attempts for this account"));
+    }
+
     // Get user by username or email
     let user: Option<User> = sqlx::query_as(
-        "SELECT id, username, email, password_hash, is_admin, is_blocked, avatar, created_at FROM users WHERE email = $1 OR username = $1"
+        "SELECT id, username, email, password_hash, role, is_blocked, avatar, created_at FROM users WHERE email = $1 OR username = $1"
     )
     .bind(&req.username_or_email)
     .fetch_optional(pool.as_ref())
@@ -120,47 +297,246 @@ pub async fn login(
         error::ErrorUnauthorized("Invalid credentials")
     })?;
 
+    if let Some(response) = check_lockout(&pool, user.id).await? {
+        return Ok(response);
+    }
+
     // Verify password
     if !verify_password(&req.password, &user.password_hash)
         .map_err(|_| error::ErrorInternalServerError("Password verification failed"))?
     {
+        rate_limit::record_failed_login(&pool, &rate_limits, user.id)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        audit::record(
+            &pool,
+            &http_req,
+            None,
+            audit::LOGIN_FAILED,
+            Some(&format!("user:{}", user.id)),
+            serde_json::Value::Null,
+            serde_json::json!({ "method": "password" }),
+        )
+        .await;
         return Err(error::ErrorUnauthorized("Invalid credentials"));
     }
 
-    // Generate JWT
-    let token = generate_jwt(user.id, &config.jwt_secret)
+    if user.is_blocked.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Account blocked"
+        })));
+    }
+
+    let (email_verified, totp_enabled): (bool, bool) =
+        sqlx::query_as("SELECT email_verified, totp_enabled FROM users WHERE id = $1")
+            .bind(user.id)
+            .fetch_one(pool.as_ref())
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+
+    if !email_verified {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Please verify your email address first",
+            "email_verification_required": true
+        })));
+    }
+
+    // With 2FA on, the password only earns a challenge for the second step
+    if totp_enabled {
+        let challenge_token = generate_two_factor_token(user.id, &config.jwt_secret)
+            .map_err(|_| error::ErrorInternalServerError("Failed to generate token"))?;
+
+        return Ok(HttpResponse::Ok().json(serde_json::json!({
+            "two_factor_required": true,
+            "challenge_token": challenge_token
+        })));
+    }
+
+    complete_login(&pool, &config, &http_req, user, "password").await
+}
+
+/// Second step of a login with 2FA: the challenge from `login` and a TOTP or
+/// recovery code.
+pub async fn login_two_factor(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    rate_limits: web::Data<RateLimits>,
+    http_req: HttpRequest,
+    req: web::Json<TwoFactorLoginRequest>,
+) -> Result<HttpResponse, Error> {
+    let claims = validate_two_factor_token(&req.challenge_token, &config.jwt_secret)
+        .map_err(|_| error::ErrorUnauthorized("Login expired, please sign in again"))?;
+
+    let user: Option<User> = sqlx::query_as(
+        "SELECT id, username, email, password_hash, role, is_blocked, avatar, created_at FROM users WHERE id = $1"
+    )
+    .bind(claims.pending_user_id)
+    .fetch_optional(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    let user = user.ok_or_else(|| error::ErrorUnauthorized("Invalid credentials"))?;
+
+    if user.is_blocked.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Account blocked"
+        })));
+    }
+
+    if let Some(response) = check_lockout(&pool, user.id).await? {
+        return Ok(response);
+    }
+
+    if !two_factor::verify(&pool, user.id, &req.code)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+    {
+        rate_limit::record_failed_login(&pool, &rate_limits, user.id)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        audit::record(
+            &pool,
+            &http_req,
+            None,
+            audit::LOGIN_FAILED,
+            Some(&format!("user:{}", user.id)),
+            serde_json::Value::Null,
+            serde_json::json!({ "method": "two_factor" }),
+        )
+        .await;
+        return Err(error::ErrorUnauthorized("Invalid authentication code"));
+    }
+
+    complete_login(&pool, &config, &http_req, user, "two_factor").await
+}
+
+/// The response for an account locked after failed logins, if it is.
+pub(crate) async fn check_lockout(pool: &PgPool, user_id: i32) -> Result<Option<HttpResponse>, Error> {
+    let locked_for = rate_limit::locked_for(pool, user_id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(locked_for.map(|retry_after| {
+        rate_limit::too_many_requests(
+            retry_after,
+            "Account temporarily locked after too many failed logins",
+        )
+    }))
+}
+
+/// Starts a session for a user who passed every login step; `method` is the
+/// step that finished it, for the audit log.
+async fn complete_login(
+    pool: &PgPool,
+    config: &Config,
+    http_req: &HttpRequest,
+    user: User,
+    method: &str,
+) -> Result<HttpResponse, Error> {
+    rate_limit::clear_failed_logins(pool, user.id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    // Start a session and generate its tokens
+    let tokens = sessions::start(pool, config, user.id, http_req)
+        .await
         .map_err(|_| error::ErrorInternalServerError("Failed to generate token"))?;
 
-    let cookie = Cookie::build("auth_token", &token)
-        .path("/")
-        .max_age(actix_web::cookie::time::Duration::days(1))
-        .http_only(true)
-        .finish();
+    audit::record(
+        pool,
+        http_req,
+        Some(user.id),
+        audit::LOGIN,
+        Some(&format!("user:{}", user.id)),
+        serde_json::Value::Null,
+        serde_json::json!({ "method": method }),
+    )
+    .await;
+
+    let (access_cookie, refresh_cookie) = session_cookies(config, &tokens);
 
     Ok(HttpResponse::Ok()
-        .cookie(cookie)
+        .cookie(access_cookie)
+        .cookie(refresh_cookie)
         .json(AuthResponse {
             message: "Login successful".to_string(),
-            token,
+            token: tokens.access_token,
+            refresh_token: tokens.refresh_token,
+            expires_in: config.access_token_minutes * 60,
             user: UserInfo {
                 id: user.id,
                 username: user.username,
                 email: user.email,
-                is_admin: user.is_admin,
+                is_admin: roles::is_admin(&user.role),
+                role: user.role,
                 avatar: user.avatar,
             },
         }))
 }
 
-pub async fn logout() -> Result<HttpResponse, Error> {
+/// Trades a refresh token, from the body or the cookie, for a new access
+/// and refresh token.
+pub async fn refresh(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    body: Option<web::Json<RefreshRequest>>,
+) -> Result<HttpResponse, Error> {
+    let refresh_token = body
+        .and_then(|b| b.into_inner().refresh_token)
+        .or_else(|| req.cookie(REFRESH_COOKIE).map(|c| c.value().to_string()));
+
+    let Some(refresh_token) = refresh_token else {
+        return Err(error::ErrorUnauthorized("No refresh token provided"));
+    };
+
+    let tokens = sessions::refresh(&pool, &config, &refresh_token)
+        .await
+        .map_err(|_| error::ErrorInternalServerError("Failed to refresh session"))?
+        .ok_or_else(|| error::ErrorUnauthorized("Session has ended, please log in again"))?;
+
+    let (access_cookie, refresh_cookie) = session_cookies(&config, &tokens);
+
+    Ok(HttpResponse::Ok()
+        .cookie(access_cookie)
+        .cookie(refresh_cookie)
+        .json(serde_json::json!({
+            "token": tokens.access_token,
+            "refresh_token": tokens.refresh_token,
+            "expires_in": config.access_token_minutes * 60
+        })))
+}
+
+pub async fn logout(pool: web::Data<PgPool>, req: HttpRequest) -> Result<HttpResponse, Error> {
+    // Revoke the session so the tokens stop working, not just the cookies.
+    // The access token may already have expired, so fall back to the
+    // refresh token.
+    let session = req.extensions().get::<AuthenticatedSession>().cloned();
+    if let Some(session) = session {
+        sessions::revoke(&pool, session.user_id, &session.session_id)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    } else if let Some(refresh_cookie) = req.cookie(REFRESH_COOKIE) {
+        sessions::revoke_by_refresh_token(&pool, refresh_cookie.value())
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    }
+
     let cookie = Cookie::build("auth_token", "")
         .path("/")
         .max_age(actix_web::cookie::time::Duration::seconds(-1))
         .http_only(true)
         .finish();
 
+    let refresh_cookie = Cookie::build(REFRESH_COOKIE, "")
+        .path(REFRESH_COOKIE_PATH)
+        .max_age(actix_web::cookie::time::Duration::seconds(-1))
+        .http_only(true)
+        .finish();
+
     Ok(HttpResponse::Ok()
         .cookie(cookie)
+        .cookie(refresh_cookie)
         .json(serde_json::json!({
             "message": "Logged out successfully"
         })))
@@ -174,7 +550,7 @@ pub async fn me(
     let user_id = extract_user_id_from_request(&req, &config)?;
 
     let user: Option<User> = sqlx::query_as(
-        "SELECT id, username, email, password_hash, is_admin, is_blocked, avatar, created_at FROM users WHERE id = $1"
+        "SELECT id, username, email, password_hash, role, is_blocked, avatar, created_at FROM users WHERE id = $1"
     )
     .bind(user_id)
     .fetch_optional(pool.as_ref())
@@ -183,14 +559,43 @@ pub async fn me(
 
     let user = user.ok_or_else(|| error::ErrorNotFound("User not found"))?;
 
+    let usage = quota::storage_usage(pool.as_ref(), user.id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    // What the user can do right now, which for an admin without required
+    // 2FA is less than the role says
+    let effective_role = roles::effective_role(pool.as_ref(), user.id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    // Admins need to enrol before they get admin access back
+    let (two_factor_enabled, require_admin_two_factor): (bool, bool) = sqlx::query_as(
+        r#"
+        SELECT totp_enabled,
+               COALESCE((SELECT require_admin_two_factor FROM settings ORDER BY id LIMIT 1), FALSE)
+        FROM users WHERE id = $1
+        "#
+    )
+    .bind(user.id)
+    .fetch_one(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
     Ok(HttpResponse::Ok().json(serde_json::json!({
         "user": {
             "id": user.id,
             "username": user.username,
             "email": user.email,
-            "is_admin": user.is_admin,
+            "role": user.role,
+            "permissions": roles::permissions(&effective_role),
+            "is_admin": roles::is_admin(&user.role),
             "avatar": user.avatar,
             "created_at": user.created_at,
+            "storage_used": usage.storage_used,
+            "storage_quota": usage.storage_quota,
+            "two_factor_enabled": two_factor_enabled,
+            "two_factor_setup_required": roles::is_admin(&user.role) && require_admin_two_factor && !two_factor_enabled,
         }
     })))
 }
@@ -205,7 +610,7 @@ pub async fn upload_avatar(
 
     // Get user info
     let user: Option<User> = sqlx::query_as(
-        "SELECT id, username, email, password_hash, is_admin, is_blocked, avatar, created_at FROM users WHERE id = $1"
+        "SELECT id, username, email, password_hash, role, is_blocked, avatar, created_at FROM users WHERE id = $1"
     )
     .bind(user_id)
     .fetch_optional(pool.as_ref())
@@ -0,0 +1,184 @@
+use actix_web::{error, http::header, web, Error, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+
+use crate::config::Config;
+use crate::models::{DeletionLog, DeletionLogQuery};
+use crate::roles::Permission;
+use crate::utils::{check_permission, extract_user_id_from_request, parse_date_bound};
+
+const DEFAULT_PER_PAGE: i64 = 50;
+const MAX_PER_PAGE: i64 = 500;
+
+/// The filters shared by the count and the listing; see `DeletionLogQuery`.
+const FILTER: &str = r#"
+    WHERE ($1::INTEGER IS NULL OR user_id = $1)
+      AND ($2::TEXT IS NULL OR POSITION(LOWER($2) IN LOWER(username)) > 0)
+      AND ($3::TIMESTAMPTZ IS NULL OR deleted_at >= $3)
+      AND ($4::TIMESTAMPTZ IS NULL OR deleted_at < $4)
+      AND ($5::TEXT IS NULL OR POSITION(LOWER($5) IN LOWER(COALESCE(deletion_reason, ''))) > 0)
+      AND (NOT $6 OR is_reverse = TRUE)
+"#;
+
+/// Quotes a CSV field when needed, and defuses values a spreadsheet would
+/// run as a formula, since file and user names come from users.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn to_csv(logs: &[DeletionLog]) -> String {
+    let mut csv = String::from(
+        "id,user_id,username,email,upload_id,files,total_size,download_url,uploaded_at,deleted_at,expires_at,is_reverse,reverse_token,deletion_reason\n",
+    );
+
+    for log in logs {
+        let row = [
+            log.id.to_string(),
+            log.user_id.map(|id| id.to_string()).unwrap_or_default(),
+            csv_field(&log.username),
+            csv_field(log.email.as_deref().unwrap_or_default()),
+            csv_field(&log.upload_id),
+            csv_field(&log.files),
+            log.total_size.to_string(),
+            csv_field(&log.download_url),
+            log.uploaded_at.to_rfc3339(),
+            log.deleted_at.to_rfc3339(),
+            log.expires_at.map(|t| t.to_rfc3339()).unwrap_or_default(),
+            log.is_reverse.to_string(),
+            csv_field(log.reverse_token.as_deref().unwrap_or_default()),
+            csv_field(&log.deletion_reason),
+        ];
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+
+    csv
+}
+
+/// Lists deletion logs, newest first, a page at a time or, with
+/// `format=csv`, every matching entry as a CSV download.
+pub async fn get_deletion_logs(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    query: web::Query<DeletionLogQuery>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if !check_permission(user_id, &pool, Permission::ViewDeletionLogs).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Moderator access required"
+        })));
+    }
+
+    let from = match query.from.as_deref().filter(|v| !v.is_empty()) {
+        Some(value) => match parse_date_bound(value, false) {
+            Some(from) => Some(from),
+            None => {
+                return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "Invalid from date, use YYYY-MM-DD or an RFC 3339 timestamp"
+                })));
+            }
+        },
+        None => None,
+    };
+
+    let to = match query.to.as_deref().filter(|v| !v.is_empty()) {
+        Some(value) => match parse_date_bound(value, true) {
+            Some(to) => Some(to),
+            None => {
+                return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "Invalid to date, use YYYY-MM-DD or an RFC 3339 timestamp"
+                })));
+            }
+        },
+        None => None,
+    };
+
+    let username = query.username.as_deref().filter(|v| !v.is_empty());
+    let reason = query.reason.as_deref().filter(|v| !v.is_empty());
+    let csv = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(_) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "format must be json or csv"
+            })));
+        }
+    };
+
+    // Rows from before these columns had defaults can hold NULLs
+    let select = format!(
+        r#"
+        SELECT id, user_id, username, upload_id, files, total_size, email, download_url, uploaded_at,
+               COALESCE(deleted_at, uploaded_at) AS deleted_at, expires_at,
+               COALESCE(is_reverse, FALSE) AS is_reverse, reverse_token,
+               COALESCE(deletion_reason, 'User deleted') AS deletion_reason
+        FROM deletion_logs
+        {}
+        ORDER BY deleted_at DESC, id DESC
+        "#,
+        FILTER
+    );
+
+    if csv {
+        let logs: Vec<DeletionLog> = sqlx::query_as(&select)
+            .bind(query.user_id)
+            .bind(username)
+            .bind(from)
+            .bind(to)
+            .bind(reason)
+            .bind(query.reverse_only)
+            .fetch_all(pool.as_ref())
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+
+        return Ok(HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .insert_header((header::CONTENT_DISPOSITION, "attachment; filename=\"deletion-logs.csv\""))
+            .body(to_csv(&logs)));
+    }
+
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE);
+
+    let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM deletion_logs {}", FILTER))
+        .bind(query.user_id)
+        .bind(username)
+        .bind(from)
+        .bind(to)
+        .bind(reason)
+        .bind(query.reverse_only)
+        .fetch_one(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let logs: Vec<DeletionLog> = sqlx::query_as(&format!("{} LIMIT $7 OFFSET $8", select))
+        .bind(query.user_id)
+        .bind(username)
+        .bind(from)
+        .bind(to)
+        .bind(reason)
+        .bind(query.reverse_only)
+        .bind(per_page)
+        .bind((page - 1).saturating_mul(per_page))
+        .fetch_all(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "logs": logs,
+        "total": total,
+        "page": page,
+        "per_page": per_page
+    })))
+}
@@ -1,43 +1,233 @@
-use actix_files::NamedFile;
-use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use actix_web::body::SizedStream;
+use actix_web::http::header::{
+    self, Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue,
+};
+use actix_web::{error, web, Error, HttpMessage, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
 use sqlx::PgPool;
-use std::io::Write;
-use zip::write::SimpleFileOptions;
-use zip::ZipWriter;
+use std::collections::HashSet;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
 
-use crate::auth::extract_token_from_header;
+use crate::api_tokens::{ApiToken, SCOPE_READ};
+use crate::auth::{generate_unlock_token, password_fingerprint, validate_unlock_token, verify_password};
 use crate::config::Config;
-use crate::models::{FileInfo, FilesMetadataResponse, UploaderInfo};
-use crate::utils::sanitize_filename_safe;
+use crate::files::{is_compressed_type, load_upload_files};
+use crate::models::{FileInfo, FilesMetadataResponse, UnlockRequest, UploadFile, UploaderInfo};
+use crate::retention;
+use crate::sessions::AuthenticatedSession;
+use crate::storage::{parse_range_header, Storage};
+use crate::utils::{self, sanitize_filename_safe};
+use crate::webhooks;
+use crate::zip_stream::ZipStream;
+
+/// (username, avatar, email, expires_at)
+type UploaderRow = (String, Option<String>, Option<String>, Option<chrono::DateTime<chrono::Utc>>);
+
+/// Downloads are offered under the name the file was uploaded with rather
+/// than the prefixed name it is stored as. Media and plain text open in the
+/// browser, everything else is saved.
+fn content_disposition(filename: &str, mime_type: &str) -> ContentDisposition {
+    let mut parameters = vec![DispositionParam::Filename(filename.to_string())];
+    if !filename.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_string()),
+            language_tag: None,
+            value: filename.as_bytes().to_vec(),
+        }));
+    }
+
+    let inline = ["image/", "audio/", "video/"]
+        .iter()
+        .any(|prefix| mime_type.starts_with(prefix))
+        || mime_type == "text/plain";
+
+    ContentDisposition {
+        disposition: if inline {
+            DispositionType::Inline
+        } else {
+            DispositionType::Attachment
+        },
+        parameters,
+    }
+}
+
+/// Called with the number of bytes sent, and whether the body was sent to
+/// the end, once a response body has finished or was dropped because the
+/// client went away.
+type OnServed = Box<dyn FnOnce(u64, bool)>;
+
+/// A response body that reports how much of it was sent.
+struct ServedStream<S> {
+    inner: S,
+    bytes_sent: u64,
+    finished: bool,
+    on_served: Option<OnServed>,
+}
+
+impl<S> ServedStream<S> {
+    fn new(inner: S, on_served: Option<OnServed>) -> Self {
+        Self {
+            inner,
+            bytes_sent: 0,
+            finished: false,
+            on_served,
+        }
+    }
+}
+
+impl<S> Stream for ServedStream<S>
+where
+    S: Stream<Item = std::io::Result<Bytes>> + Unpin,
+{
+    type Item = std::io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let polled = Pin::new(&mut this.inner).poll_next(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(chunk))) => this.bytes_sent += chunk.len() as u64,
+            Poll::Ready(None) => this.finished = true,
+            _ => {}
+        }
+        polled
+    }
+}
+
+impl<S> Drop for ServedStream<S> {
+    fn drop(&mut self) {
+        if let Some(on_served) = self.on_served.take() {
+            on_served(self.bytes_sent, self.finished);
+        }
+    }
+}
+
+/// Streams one stored file from the storage backend, honouring a single
+/// `Range` request.
+async fn serve_file(
+    req: &HttpRequest,
+    storage: &dyn Storage,
+    file: &UploadFile,
+    on_served: Option<OnServed>,
+) -> Result<HttpResponse, Error> {
+    let size = file.size.max(0) as u64;
+    let range = match req.headers().get(header::RANGE).and_then(|h| h.to_str().ok()) {
+        Some(value) => match parse_range_header(value, size) {
+            Ok(range) => range,
+            Err(()) => {
+                return Ok(HttpResponse::RangeNotSatisfiable()
+                    .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+                    .finish());
+            }
+        },
+        None => None,
+    };
+
+    let object = storage.get(&file.stored_name, range).await.map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            error::ErrorNotFound("File not found")
+        } else {
+            error::ErrorInternalServerError(e)
+        }
+    })?;
+
+    let mut response = match range {
+        Some(range) => {
+            let mut builder = HttpResponse::PartialContent();
+            builder.insert_header((
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", range.start, range.end, size),
+            ));
+            builder
+        }
+        None => HttpResponse::Ok(),
+    };
+
+    Ok(response
+        .content_type(file.mime_type.as_str())
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header(content_disposition(&file.original_name, &file.mime_type))
+        .body(SizedStream::new(object.length, ServedStream::new(object.stream, on_served))))
+}
+
+#[derive(Deserialize)]
+struct UnlockQuery {
+    unlock_token: Option<String>,
+}
+
+/// Whether the request carries an unlock token for this upload and its
+/// current password, either in the `X-Unlock-Token` header or, for plain
+/// links, the `unlock_token` query parameter.
+fn has_unlock_token(req: &HttpRequest, upload_id: &str, password_hash: &str, config: &Config) -> bool {
+    let token = req
+        .headers()
+        .get("X-Unlock-Token")
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| {
+            web::Query::<UnlockQuery>::from_query(req.query_string())
+                .ok()
+                .and_then(|q| q.into_inner().unlock_token)
+        });
+
+    token
+        .and_then(|token| validate_unlock_token(&token, &config.jwt_secret).ok())
+        .map(|claims| claims.upload_id == upload_id && claims.password == password_fingerprint(password_hash))
+        .unwrap_or(false)
+}
+
+/// Who is asking for a share that they may access.
+struct ShareAccess {
+    is_owner: bool,
+}
 
 async fn check_upload_access(
     upload_id: &str,
     pool: &PgPool,
     req: &HttpRequest,
     config: &Config,
-) -> Result<(bool, i32), Error> {
+) -> Result<ShareAccess, Error> {
     // Get upload info
-    let upload_info: Option<(bool, i32)> = sqlx::query_as(
-        "SELECT is_available, user_id FROM uploads WHERE upload_id = $1"
+    let upload_info: Option<(bool, i32, Option<String>, bool, bool)> = sqlx::query_as(
+        "SELECT is_available, user_id, password_hash, is_deleted, removed_by_admin FROM uploads WHERE upload_id = $1"
     )
     .bind(upload_id)
     .fetch_optional(pool)
     .await
     .map_err(error::ErrorInternalServerError)?;
 
-    let (is_available, uploader_id) = upload_info
+    let (is_available, uploader_id, password_hash, is_deleted, removed_by_admin) = upload_info
         .ok_or_else(|| error::ErrorNotFound("Upload not found"))?;
 
-    // Try to get current user ID
-    let current_user_id = extract_token_from_header(
-        req.headers()
-            .get(actix_web::http::header::AUTHORIZATION)
-            .and_then(|h| h.to_str().ok()),
-    )
-    .or_else(|| req.cookie("auth_token").map(|c| c.value().to_string()))
-    .and_then(|token| crate::auth::validate_jwt(&token, &config.jwt_secret).ok())
-    .map(|claims| claims.user_id)
-    .unwrap_or(-1);
+    // Taken down for everyone, the owner included; the reason stays private
+    if removed_by_admin {
+        let response = HttpResponse::Gone().json(serde_json::json!({
+            "error": "Removed by administrator"
+        }));
+        return Err(error::InternalError::from_response("Removed by administrator", response).into());
+    }
+
+    // Deleted uploads may share blobs with live ones, so their files could
+    // still be readable
+    if is_deleted {
+        return Err(error::ErrorGone(
+            "This file has expired or is no longer available",
+        ));
+    }
+
+    // Try to get current user ID; API tokens need the read scope
+    let api_token_user = req
+        .extensions()
+        .get::<ApiToken>()
+        .filter(|t| t.has_scope(SCOPE_READ))
+        .map(|t| t.user_id);
+    let current_user_id = api_token_user
+        .or_else(|| req.extensions().get::<AuthenticatedSession>().map(|s| s.user_id))
+        .unwrap_or(-1);
 
     // Check access
     if !is_available && current_user_id != uploader_id {
@@ -46,102 +236,452 @@ async fn check_upload_access(
         ));
     }
 
-    Ok((is_available, uploader_id))
+    // The owner never needs the share password
+    if let Some(password_hash) = password_hash.filter(|_| current_user_id != uploader_id) {
+        if !has_unlock_token(req, upload_id, &password_hash, config) {
+            return Err(error::ErrorUnauthorized("This share is password protected"));
+        }
+    }
+
+    Ok(ShareAccess {
+        is_owner: current_user_id == uploader_id,
+    })
+}
+
+/// What `serve_file` will send for this request: how many bytes, and
+/// whether they start at the beginning of the file, which makes it a new
+/// download rather than a resumed one. That is the requested range, or the
+/// whole file without one, and nothing when the range cannot be satisfied.
+/// An empty file still counts as one byte.
+fn served_part(req: &HttpRequest, file: &UploadFile) -> (i64, bool) {
+    let size = file.size.max(0) as u64;
+    match req.headers().get(header::RANGE).and_then(|h| h.to_str().ok()) {
+        Some(value) => match parse_range_header(value, size) {
+            Ok(Some(range)) => ((range.end - range.start + 1) as i64, range.start == 0),
+            Ok(None) => (size.max(1) as i64, true),
+            // Answered with 416, nothing is sent
+            Err(()) => (0, false),
+        },
+        None => (size.max(1) as i64, true),
+    }
+}
+
+/// How much of a share's download limit is used. Every download started
+/// from the first byte counts, and so does every further copy of the
+/// share's contents served in ranges, so that reading a share piece by piece
+/// uses it up like reading it whole. A copy may take a quarter more than the
+/// share's size, for what a resumed download sends again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DownloadBudget {
+    count: i32,
+    max: Option<i32>,
+    bytes_served: i64,
+}
+
+impl DownloadBudget {
+    fn exhausted(&self) -> bool {
+        self.max.is_some_and(|max| self.count >= max)
+    }
+
+    /// Takes `bytes` of a share of `share_size` bytes, or `None` once the
+    /// limit is used up, whatever is asked for.
+    fn claim(self, bytes: i64, starts: bool, share_size: i64) -> Option<Self> {
+        if self.exhausted() {
+            return None;
+        }
+
+        let bytes_served = self.bytes_served + bytes;
+        let copy = share_size.max(1) * 5 / 4;
+        let copies = (bytes_served + copy - 1) / copy;
+        Some(Self {
+            count: (self.count + starts as i32).max(copies.min(i32::MAX as i64) as i32),
+            bytes_served,
+            ..self
+        })
+    }
+
+    /// Gives back claimed bytes that were never sent. A download that was
+    /// started stays counted.
+    fn refund(self, bytes: i64) -> Self {
+        Self {
+            bytes_served: (self.bytes_served - bytes).max(0),
+            ..self
+        }
+    }
+}
+
+/// Applies `update` to a share's download budget, with the share locked so
+/// that concurrent requests cannot both take what is left. Returns `None`,
+/// changing nothing, if `update` does.
+async fn update_budget(
+    pool: &PgPool,
+    upload_id: &str,
+    update: impl FnOnce(DownloadBudget, i64) -> Option<DownloadBudget>,
+) -> Result<Option<DownloadBudget>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let budget: Option<(i32, Option<i32>, i64)> = sqlx::query_as(
+        "SELECT download_count, max_downloads, bytes_served FROM uploads WHERE upload_id = $1 FOR UPDATE"
+    )
+    .bind(upload_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some((count, max, bytes_served)) = budget else {
+        return Ok(None);
+    };
+
+    let (share_size,): (i64,) =
+        sqlx::query_as("SELECT COALESCE(SUM(size), 0)::BIGINT FROM upload_files WHERE upload_id = $1")
+            .bind(upload_id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+    let budget = DownloadBudget { count, max, bytes_served };
+    let Some(updated) = update(budget, share_size) else {
+        return Ok(None);
+    };
+
+    sqlx::query("UPDATE uploads SET download_count = $1, bytes_served = $2 WHERE upload_id = $3")
+        .bind(updated.count)
+        .bind(updated.bytes_served)
+        .bind(upload_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(Some(updated))
+}
+
+/// Counts `bytes` about to be sent against the share's limit, see
+/// `DownloadBudget`. Returns whether that used the limit up, or the response
+/// to send if it already was. The owner's own downloads are not counted.
+async fn claim_download(
+    pool: &PgPool,
+    access: &ShareAccess,
+    upload_id: &str,
+    (bytes, starts): (i64, bool),
+) -> Result<Result<bool, HttpResponse>, Error> {
+    if access.is_owner {
+        return Ok(Ok(false));
+    }
+
+    let claimed = update_budget(pool, upload_id, |budget, share_size| budget.claim(bytes, starts, share_size))
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    match claimed {
+        None => Ok(Err(HttpResponse::Gone().json(serde_json::json!({
+            "error": "This share has reached its download limit"
+        })))),
+        Some(budget) => Ok(Ok(budget.exhausted())),
+    }
+}
+
+/// What a request took from a share's download limit.
+struct Claim {
+    /// Bytes counted for the response, to give back what it does not send.
+    /// Archives cannot be resumed, so they are counted whole and have none.
+    bytes: Option<i64>,
+    last_download: bool,
+}
+
+/// Builds the hook run once a download response has been sent: it logs a
+/// download event, gives back claimed bytes that a response which broke off
+/// did not send, and, after the last allowed download, retires the share
+/// (not earlier, while it is still being streamed). The owner's own
+/// downloads are not logged.
+fn served_hook(
+    pool: &web::Data<PgPool>,
+    storage: &web::Data<dyn Storage>,
+    req: &HttpRequest,
+    access: &ShareAccess,
+    upload_id: &str,
+    file_id: Option<i32>,
+    claim: Claim,
+) -> Option<OnServed> {
+    if access.is_owner {
+        return None;
+    }
+
+    let pool = pool.clone().into_inner();
+    let storage = storage.clone().into_inner();
+    let upload_id = upload_id.to_string();
+    let ip_address = utils::client_ip(req).unwrap_or_else(|| "unknown".to_string());
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_string);
+
+    Some(Box::new(move |bytes_served, finished| {
+        actix_web::rt::spawn(async move {
+            let unsent = claim.bytes.filter(|_| !finished).map_or(0, |claimed| claimed - bytes_served as i64);
+            if unsent > 0 {
+                let refunded = update_budget(&pool, &upload_id, |budget, _| Some(budget.refund(unsent))).await;
+                if let Err(e) = refunded {
+                    log::error!("Failed to refund download of {}: {}", upload_id, e);
+                }
+            }
+
+            let recorded = sqlx::query(
+                r#"
+                INSERT INTO download_events (upload_id, file_id, ip_address, user_agent, bytes_served)
+                VALUES ($1, $2, $3, $4, $5)
+                "#
+            )
+            .bind(&upload_id)
+            .bind(file_id)
+            .bind(&ip_address)
+            .bind(&user_agent)
+            .bind(bytes_served as i64)
+            .execute(pool.as_ref())
+            .await;
+
+            if let Err(e) = recorded {
+                log::error!("Failed to record download of {}: {}", upload_id, e);
+            }
+
+            webhooks::notify(&pool, webhooks::UPLOAD_DOWNLOADED, &upload_id, serde_json::json!({
+                "file_id": file_id,
+                "ip_address": ip_address,
+                "user_agent": user_agent,
+                "bytes_served": bytes_served,
+            }));
+
+            if claim.last_download {
+                retention::apply_download_limit(&pool, storage.as_ref(), &upload_id).await;
+            }
+        });
+    }))
+}
+
+/// Exchanges a share's password for a short-lived unlock token.
+pub async fn unlock(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    upload_id: web::Path<String>,
+    body: web::Json<UnlockRequest>,
+) -> Result<HttpResponse, Error> {
+    let password_hash: Option<(Option<String>,)> = sqlx::query_as(
+        "SELECT password_hash FROM uploads WHERE upload_id = $1 AND is_deleted = FALSE"
+    )
+    .bind(upload_id.as_str())
+    .fetch_optional(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    let password_hash = match password_hash {
+        Some((Some(hash),)) => hash,
+        Some((None,)) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "This share is not password protected"
+            })));
+        }
+        None => return Err(error::ErrorNotFound("Upload not found")),
+    };
+
+    if !verify_password(&body.password, &password_hash).unwrap_or(false) {
+        return Ok(HttpResponse::Unauthorized().json(serde_json::json!({
+            "error": "Incorrect password"
+        })));
+    }
+
+    let (token, expires_at) = generate_unlock_token(&upload_id, &password_hash, &config.jwt_secret)
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "token": token,
+        "expires_at": expires_at
+    })))
 }
 
 pub async fn download(
     pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
     config: web::Data<Config>,
     req: HttpRequest,
     upload_id: web::Path<String>,
 ) -> Result<HttpResponse, Error> {
-    check_upload_access(&upload_id, &pool, &req, &config).await?;
+    let access = check_upload_access(&upload_id, &pool, &req, &config).await?;
 
-    // Find matching files
-    let entries = std::fs::read_dir("./uploads")
+    let files = load_upload_files(&pool, &upload_id)
+        .await
         .map_err(error::ErrorInternalServerError)?;
-    
-    let prefix = format!("{}_", upload_id);
-    let mut matching_files: Vec<String> = entries
-        .filter_map(|e| e.ok())
-        .map(|e| e.file_name().to_string_lossy().to_string())
-        .filter(|name| name.starts_with(&prefix))
-        .collect();
-
-    if matching_files.is_empty() {
+
+    if files.is_empty() {
         return Err(error::ErrorNotFound("Files not found"));
     }
 
     // Single file - serve directly
-    if matching_files.len() == 1 {
-        let file_path = format!("./uploads/{}", matching_files[0]);
-        let file = NamedFile::open(file_path)
-            .map_err(error::ErrorInternalServerError)?;
-        return Ok(file.into_response(&req));
+    if files.len() == 1 {
+        let part = served_part(&req, &files[0]);
+        let last_download = match claim_download(&pool, &access, &upload_id, part).await? {
+            Ok(last_download) => last_download,
+            Err(response) => return Ok(response),
+        };
+        let claim = Claim { bytes: Some(part.0), last_download };
+        let on_served = served_hook(&pool, &storage, &req, &access, &upload_id, Some(files[0].id), claim);
+        return serve_file(&req, storage.as_ref(), &files[0], on_served).await;
     }
 
-    // Multiple files - create ZIP
-    matching_files.sort();
-    let mut buffer = Vec::new();
-    {
-        let mut zip = ZipWriter::new(std::io::Cursor::new(&mut buffer));
-        let options = SimpleFileOptions::default()
-            .compression_method(zip::CompressionMethod::Deflated);
+    // An archive ignores Range and always counts as a whole download
+    let share_size = files.iter().map(|f| f.size.max(0)).sum::<i64>().max(1);
+    let last_download = match claim_download(&pool, &access, &upload_id, (share_size, true)).await? {
+        Ok(last_download) => last_download,
+        Err(response) => return Ok(response),
+    };
+    let claim = Claim { bytes: None, last_download };
+    let on_served = served_hook(&pool, &storage, &req, &access, &upload_id, None, claim);
 
-        for file_name in &matching_files {
-            let original_name = file_name.strip_prefix(&prefix).unwrap_or(file_name);
-            let file_path = format!("./uploads/{}", file_name);
-            let file_content = std::fs::read(&file_path)
-                .map_err(error::ErrorInternalServerError)?;
+    // Multiple files - stream a ZIP while it is being built
+    let archive_name = archive_name(&files);
+    let storage = storage.into_inner();
+    let upload_id = upload_id.into_inner();
+    let (tx, rx) = mpsc::channel::<std::io::Result<Bytes>>(8);
 
-            zip.start_file(original_name, options)
-                .map_err(error::ErrorInternalServerError)?;
-            zip.write_all(&file_content)
-                .map_err(error::ErrorInternalServerError)?;
+    actix_web::rt::spawn(async move {
+        match write_zip(storage.as_ref(), &files, &tx).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+            Err(e) => {
+                log::warn!("ZIP download of {} failed: {}", upload_id, e);
+                tx.send(Err(e)).await.ok();
+            }
         }
+    });
 
-        zip.finish().map_err(error::ErrorInternalServerError)?;
-    }
+    let body = Box::pin(futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }));
 
     Ok(HttpResponse::Ok()
         .content_type("application/zip")
-        .append_header(("Content-Disposition", "attachment; filename=\"files.zip\""))
-        .body(buffer))
+        .insert_header(content_disposition(&archive_name, "application/zip"))
+        .streaming(ServedStream::new(body, on_served)))
+}
+
+/// Names the archive after the upload's first file, e.g. `report and 2 more.zip`.
+fn archive_name(files: &[UploadFile]) -> String {
+    let stem = files
+        .first()
+        .and_then(|f| Path::new(&f.original_name).file_stem().map(|s| s.to_string_lossy().into_owned()))
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "files".to_string());
+
+    format!("{} and {} more.zip", stem, files.len() - 1)
+}
+
+/// Archive entry names, made unique so no file overwrites another when the
+/// archive is extracted.
+fn entry_names(files: &[UploadFile]) -> Vec<String> {
+    let mut taken = HashSet::new();
+
+    files
+        .iter()
+        .map(|file| {
+            let name = &file.original_name;
+            let mut candidate = name.clone();
+            let mut n = 1;
+            while !taken.insert(candidate.clone()) {
+                let path = Path::new(name);
+                let stem = path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+                candidate = match path.extension() {
+                    Some(ext) => format!("{} ({}).{}", stem, n, ext.to_string_lossy()),
+                    None => format!("{} ({})", stem, n),
+                };
+                n += 1;
+            }
+            candidate
+        })
+        .collect()
+}
+
+async fn send_chunk(tx: &mpsc::Sender<std::io::Result<Bytes>>, chunk: Bytes) -> std::io::Result<()> {
+    if chunk.is_empty() {
+        return Ok(());
+    }
+
+    tx.send(Ok(chunk))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Client disconnected"))
+}
+
+/// Builds the archive one storage chunk at a time. Sending waits for the
+/// client, so at most a few chunks are held in memory.
+async fn write_zip(
+    storage: &dyn Storage,
+    files: &[UploadFile],
+    tx: &mpsc::Sender<std::io::Result<Bytes>>,
+) -> std::io::Result<()> {
+    let mut zip = ZipStream::default();
+
+    for (file, name) in files.iter().zip(entry_names(files)) {
+        let mut object = storage.get(&file.stored_name, None).await?;
+        let compress = !is_compressed_type(&file.original_name, &file.mime_type);
+
+        send_chunk(tx, zip.start_entry(&name, file.created_at, compress)?).await?;
+        while let Some(chunk) = object.stream.next().await {
+            send_chunk(tx, zip.write(chunk?)?).await?;
+        }
+        send_chunk(tx, zip.finish_entry()?).await?;
+    }
+
+    send_chunk(tx, zip.finish()?).await
 }
 
 pub async fn download_file(
     pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
     config: web::Data<Config>,
     req: HttpRequest,
     path: web::Path<(String, String)>,
 ) -> Result<HttpResponse, Error> {
     let (upload_id, filename) = path.into_inner();
 
-    check_upload_access(&upload_id, &pool, &req, &config).await?;
+    let access = check_upload_access(&upload_id, &pool, &req, &config).await?;
+
+    // The URL carries the original name; older links used the sanitized one
+    let stored_name = format!("{}_{}", upload_id, sanitize_filename_safe(&filename));
+    let file: Option<UploadFile> = sqlx::query_as(
+        r#"
+        SELECT id, upload_id, position, original_name, stored_name, size, mime_type, sha256, created_at
+        FROM upload_files
+        WHERE upload_id = $1 AND (original_name = $2 OR stored_name = $3)
+        ORDER BY (original_name = $2) DESC, position
+        LIMIT 1
+        "#,
+    )
+    .bind(&upload_id)
+    .bind(&filename)
+    .bind(&stored_name)
+    .fetch_optional(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
 
-    let sanitized = sanitize_filename_safe(&filename);
-    let actual_filename = format!("{}_{}", upload_id, sanitized);
-    let file_path = format!("./uploads/{}", actual_filename);
+    let file = file.ok_or_else(|| error::ErrorNotFound("File not found"))?;
 
-    if !std::path::Path::new(&file_path).exists() {
-        return Err(error::ErrorNotFound("File not found"));
-    }
+    let part = served_part(&req, &file);
+    let last_download = match claim_download(&pool, &access, &upload_id, part).await? {
+        Ok(last_download) => last_download,
+        Err(response) => return Ok(response),
+    };
 
-    let file = NamedFile::open(file_path)
-        .map_err(error::ErrorInternalServerError)?;
-    Ok(file.into_response(&req))
+    let claim = Claim { bytes: Some(part.0), last_download };
+    let on_served = served_hook(&pool, &storage, &req, &access, &upload_id, Some(file.id), claim);
+    serve_file(&req, storage.as_ref(), &file, on_served).await
 }
 
 pub async fn get_file_metadata(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
+    storage: web::Data<dyn Storage>,
     req: HttpRequest,
     upload_id: web::Path<String>,
 ) -> Result<HttpResponse, Error> {
     check_upload_access(&upload_id, &pool, &req, &config).await?;
 
     // Get uploader info
-    let uploader_info: Option<(String, Option<String>, Option<String>, Option<chrono::DateTime<chrono::Utc>>)> = sqlx::query_as(
+    let uploader_info: Option<UploaderRow> = sqlx::query_as(
         r#"
         SELECT u.username, u.avatar, up.email, up.expires_at
         FROM uploads up
@@ -157,26 +697,25 @@ pub async fn get_file_metadata(
     let (username, avatar, email, expiration_date) = uploader_info
         .unwrap_or_else(|| ("Unknown".to_string(), None, None, None));
 
-    // Get files
-    let entries = std::fs::read_dir("./uploads")
+    // Get files, leaving out any whose contents have gone missing
+    let files = load_upload_files(&pool, &upload_id)
+        .await
         .map_err(error::ErrorInternalServerError)?;
-    
-    let prefix = format!("{}_", upload_id);
-    let mut file_infos = Vec::new();
-
-    for entry in entries {
-        if let Ok(entry) = entry {
-            let file_name = entry.file_name().to_string_lossy().to_string();
-            if file_name.starts_with(&prefix) {
-                let original_name = file_name.strip_prefix(&prefix).unwrap_or(&file_name);
-                if let Ok(metadata) = entry.metadata() {
-                    file_infos.push(FileInfo {
-                        name: original_name.to_string(),
-                        size: metadata.len(),
-                        url: format!("/api/file/{}/{}", upload_id, original_name),
-                    });
-                }
-            }
+
+    let mut file_infos: Vec<FileInfo> = Vec::with_capacity(files.len());
+    for file in files {
+        let object = storage
+            .stat(&file.stored_name)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+
+        if let Some(object) = object {
+            file_infos.push(FileInfo {
+                url: format!("/api/file/{}/{}", upload_id, file.original_name),
+                name: file.original_name,
+                size: object.size,
+                mime_type: file.mime_type,
+            });
         }
     }
 
@@ -198,3 +737,109 @@ pub async fn get_file_metadata(
             },
         }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    const SIZE: i64 = 1000;
+
+    fn file() -> UploadFile {
+        UploadFile {
+            id: 1,
+            upload_id: "share".to_string(),
+            position: 0,
+            original_name: "report.pdf".to_string(),
+            stored_name: "blob".to_string(),
+            size: SIZE,
+            mime_type: "application/pdf".to_string(),
+            sha256: None,
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    fn request(range: Option<&str>) -> HttpRequest {
+        let mut req = TestRequest::default();
+        if let Some(range) = range {
+            req = req.insert_header((header::RANGE, range));
+        }
+        req.to_http_request()
+    }
+
+    fn budget(max: Option<i32>) -> DownloadBudget {
+        DownloadBudget { count: 0, max, bytes_served: 0 }
+    }
+
+    /// Claims a request the way `download_file` does, with the response then
+    /// sent in full.
+    fn fetch(budget: DownloadBudget, range: Option<&str>) -> Option<DownloadBudget> {
+        let (bytes, starts) = served_part(&request(range), &file());
+        budget.claim(bytes, starts, SIZE)
+    }
+
+    #[test]
+    fn ranges_past_the_start_use_up_the_limit() {
+        let mut state = budget(Some(2));
+        let mut served = 0;
+        while let Some(next) = fetch(state, Some("bytes=1-")) {
+            state = next;
+            served += 1;
+            assert!(served <= 2, "limit not enforced");
+        }
+        assert_eq!(served, 2);
+        assert!(state.exhausted());
+        // Whatever the range, nothing more is served
+        assert_eq!(fetch(state, Some("bytes=0-0")), None);
+        assert_eq!(fetch(state, None), None);
+    }
+
+    #[test]
+    fn burn_after_read_cannot_be_read_in_pieces() {
+        // Everything but the first byte, then the first byte
+        let state = fetch(budget(Some(1)), Some("bytes=1-")).unwrap();
+        assert!(state.exhausted());
+        assert_eq!(fetch(state, Some("bytes=0-0")), None);
+    }
+
+    #[test]
+    fn resuming_does_not_count_again() {
+        // The first attempt breaks off after 400 bytes
+        let state = fetch(budget(Some(2)), None).unwrap();
+        let state = state.refund(SIZE - 400);
+        assert_eq!(state.count, 1);
+
+        let state = fetch(state, Some("bytes=400-")).unwrap();
+        assert_eq!(state.count, 1);
+        assert!(!state.exhausted());
+
+        let state = fetch(state, None).unwrap();
+        assert!(state.exhausted());
+    }
+
+    #[test]
+    fn resending_what_was_lost_in_transit_does_not_count_again() {
+        // All was sent, but the client only got 900 bytes before it broke off
+        let state = fetch(budget(Some(2)), None).unwrap();
+        let state = fetch(state, Some("bytes=900-")).unwrap();
+        assert_eq!(state.count, 1);
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_count_nothing_but_are_refused_at_the_limit() {
+        let state = fetch(budget(Some(1)), Some("bytes=5000-")).unwrap();
+        assert_eq!(state, budget(Some(1)));
+
+        let state = fetch(state, None).unwrap();
+        assert_eq!(fetch(state, Some("bytes=5000-")), None);
+    }
+
+    #[test]
+    fn unlimited_shares_keep_counting() {
+        let mut state = budget(None);
+        for _ in 0..5 {
+            state = fetch(state, None).unwrap();
+        }
+        assert_eq!(state.count, 5);
+    }
+}
@@ -0,0 +1,142 @@
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+
+use crate::config::Config;
+use crate::mailer::Mailer;
+use crate::models::{CreateInviteRequest, Invite};
+use crate::roles::Permission;
+use crate::utils::{calculate_expiry_time, check_permission, extract_user_id_from_request};
+
+/// Invites that can still be used, newest first.
+pub async fn get_invites(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if !check_permission(user_id, &pool, Permission::ManageSettings).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin access required"
+        })));
+    }
+
+    let invites: Vec<Invite> = sqlx::query_as(
+        r#"
+        SELECT id, code, created_by, max_uses, uses, email_domains, expires_at, created_at
+        FROM invites
+        WHERE revoked_at IS NULL
+          AND (expires_at IS NULL OR expires_at > NOW())
+          AND (max_uses IS NULL OR uses < max_uses)
+        ORDER BY created_at DESC
+        "#
+    )
+    .fetch_all(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "invites": invites
+    })))
+}
+
+pub async fn create_invite(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    mailer: web::Data<Mailer>,
+    req: HttpRequest,
+    body: web::Json<CreateInviteRequest>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if !check_permission(user_id, &pool, Permission::ManageSettings).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin access required"
+        })));
+    }
+
+    let max_uses = match body.max_uses.unwrap_or(1) {
+        0 => None,
+        n if n > 0 => Some(n),
+        _ => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "max_uses cannot be negative"
+            })));
+        }
+    };
+
+    let mut email_domains: Vec<String> = body
+        .email_domains
+        .iter()
+        .map(|d| d.trim().trim_start_matches('@').to_lowercase())
+        .filter(|d| !d.is_empty())
+        .collect();
+    email_domains.sort();
+    email_domains.dedup();
+
+    if let Some(invalid) = email_domains.iter().find(|d| !d.contains('.') || d.contains('@')) {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("Invalid email domain: {}", invalid)
+        })));
+    }
+
+    // Accounts from a restricted invite have to prove they own the address
+    if !email_domains.is_empty() && !mailer.is_enabled() {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Restricting an invite to email domains needs email to be configured"
+        })));
+    }
+
+    let expires_at = body.expires_in.as_deref().and_then(calculate_expiry_time);
+    let raw = uuid::Uuid::new_v4().simple().to_string();
+    let code = format!("{}-{}-{}", &raw[..4], &raw[4..8], &raw[8..12]);
+
+    let invite: Invite = sqlx::query_as(
+        r#"
+        INSERT INTO invites (code, created_by, max_uses, email_domains, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id, code, created_by, max_uses, uses, email_domains, expires_at, created_at
+        "#
+    )
+    .bind(&code)
+    .bind(user_id)
+    .bind(max_uses)
+    .bind(&email_domains)
+    .bind(expires_at)
+    .fetch_one(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Created().json(invite))
+}
+
+pub async fn revoke_invite(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    invite_id: web::Path<i32>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if !check_permission(user_id, &pool, Permission::ManageSettings).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin access required"
+        })));
+    }
+
+    let revoked = sqlx::query("UPDATE invites SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL")
+        .bind(*invite_id)
+        .execute(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if revoked.rows_affected() == 0 {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Invite not found"
+        })));
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Invite revoked successfully"
+    })))
+}
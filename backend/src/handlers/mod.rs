@@ -1,6 +1,17 @@
 pub mod admin;
+pub mod api_tokens;
+pub mod audit;
 pub mod auth;
+pub mod deletion_logs;
 pub mod download;
+pub mod invites;
+pub mod moderation;
+pub mod oidc;
+pub mod password;
 pub mod reverse;
+pub mod sessions;
 pub mod settings;
+pub mod tus;
+pub mod two_factor;
 pub mod upload;
+pub mod webhooks;
@@ -0,0 +1,345 @@
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+
+use crate::audit;
+use crate::config::Config;
+use crate::models::{AdminUpload, AdminUploadQuery, ModerationRequest};
+use crate::retention;
+use crate::roles::{self, Permission};
+use crate::storage::Storage;
+use crate::utils::{check_permission, extract_user_id_from_request, parse_date_bound, parse_size};
+use crate::webhooks;
+
+const DEFAULT_PER_PAGE: i64 = 50;
+const MAX_PER_PAGE: i64 = 500;
+
+/// The filters shared by the count and the listing; see `AdminUploadQuery`.
+const FILTER: &str = r#"
+    WHERE ($1::TEXT IS NULL OR EXISTS (
+              SELECT 1 FROM upload_files f
+              WHERE f.upload_id = up.upload_id AND POSITION(LOWER($1) IN LOWER(f.original_name)) > 0
+          ))
+      AND ($2::TEXT IS NULL OR POSITION(LOWER($2) IN LOWER(u.username)) > 0)
+      AND ($3::BIGINT IS NULL OR up.total_size >= $3)
+      AND ($4::BIGINT IS NULL OR up.total_size <= $4)
+      AND ($5::TIMESTAMPTZ IS NULL OR up.created_at >= $5)
+      AND ($6::TIMESTAMPTZ IS NULL OR up.created_at < $6)
+      AND ($7 OR up.is_deleted = FALSE)
+"#;
+
+fn bad_request(message: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({
+        "error": message
+    }))
+}
+
+/// Checks that the caller may moderate shares, and this one in particular:
+/// shares of users with the caller's role or a higher one are off limits,
+/// other than the caller's own. Returns the response to send if not.
+async fn check_moderator(pool: &PgPool, user_id: i32, upload_id: &str) -> Result<Option<HttpResponse>, Error> {
+    if !check_permission(user_id, pool, Permission::ModerateShares).await.unwrap_or(false) {
+        return Ok(Some(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Moderator access required"
+        }))));
+    }
+
+    let owner: Option<(i32, String)> = sqlx::query_as(
+        "SELECT u.id, u.role FROM uploads up JOIN users u ON u.id = up.user_id WHERE up.upload_id = $1"
+    )
+    .bind(upload_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    let Some((owner_id, owner_role)) = owner else {
+        return Ok(Some(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Upload not found"
+        }))));
+    };
+
+    if owner_id != user_id
+        && !roles::outranks(pool, user_id, &owner_role)
+            .await
+            .map_err(error::ErrorInternalServerError)?
+    {
+        return Ok(Some(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "You can only moderate shares of users with a lower role than yours"
+        }))));
+    }
+
+    Ok(None)
+}
+
+/// The reason as recorded, so moderation is told apart from the owner's own
+/// deletions in the logs.
+fn moderation_reason(body: &ModerationRequest) -> Option<String> {
+    let reason = body.reason.trim();
+    if reason.is_empty() || reason.chars().count() > 400 {
+        return None;
+    }
+
+    Some(format!("Removed by administrator: {}", reason))
+}
+
+/// Every user's uploads, newest first, with search.
+pub async fn get_all_uploads(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    query: web::Query<AdminUploadQuery>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if !check_permission(user_id, &pool, Permission::ModerateShares).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Moderator access required"
+        })));
+    }
+
+    let filename = query.filename.as_deref().filter(|v| !v.is_empty());
+    let owner = query.owner.as_deref().filter(|v| !v.is_empty());
+
+    let min_size = match query.min_size.as_deref().filter(|v| !v.is_empty()).map(parse_size) {
+        Some(Ok(size)) => Some(size),
+        Some(Err(e)) => return Ok(bad_request(&format!("Invalid min_size: {}", e))),
+        None => None,
+    };
+    let max_size = match query.max_size.as_deref().filter(|v| !v.is_empty()).map(parse_size) {
+        Some(Ok(size)) => Some(size),
+        Some(Err(e)) => return Ok(bad_request(&format!("Invalid max_size: {}", e))),
+        None => None,
+    };
+
+    let from = match query.from.as_deref().filter(|v| !v.is_empty()) {
+        Some(value) => match parse_date_bound(value, false) {
+            Some(from) => Some(from),
+            None => return Ok(bad_request("Invalid from date, use YYYY-MM-DD or an RFC 3339 timestamp")),
+        },
+        None => None,
+    };
+    let to = match query.to.as_deref().filter(|v| !v.is_empty()) {
+        Some(value) => match parse_date_bound(value, true) {
+            Some(to) => Some(to),
+            None => return Ok(bad_request("Invalid to date, use YYYY-MM-DD or an RFC 3339 timestamp")),
+        },
+        None => None,
+    };
+
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE);
+
+    let (total,): (i64,) = sqlx::query_as(&format!(
+        "SELECT COUNT(*) FROM uploads up JOIN users u ON u.id = up.user_id {}",
+        FILTER
+    ))
+    .bind(filename)
+    .bind(owner)
+    .bind(min_size)
+    .bind(max_size)
+    .bind(from)
+    .bind(to)
+    .bind(query.include_deleted)
+    .fetch_one(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    let uploads: Vec<AdminUpload> = sqlx::query_as(&format!(
+        r#"
+        SELECT up.upload_id, up.user_id, u.username, up.files, up.total_size, up.download_url,
+               up.created_at, up.expires_at, up.is_available, up.is_reverse, up.is_deleted, up.deleted_at,
+               up.deletion_reason, up.removed_by_admin,
+               (up.password_hash IS NOT NULL) AS is_password_protected, up.download_count
+        FROM uploads up
+        JOIN users u ON u.id = up.user_id
+        {}
+        ORDER BY up.created_at DESC
+        LIMIT $8 OFFSET $9
+        "#,
+        FILTER
+    ))
+    .bind(filename)
+    .bind(owner)
+    .bind(min_size)
+    .bind(max_size)
+    .bind(from)
+    .bind(to)
+    .bind(query.include_deleted)
+    .bind(per_page)
+    .bind((page - 1).saturating_mul(per_page))
+    .fetch_all(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "uploads": uploads,
+        "total": total,
+        "page": page,
+        "per_page": per_page
+    })))
+}
+
+/// Takes a share offline without deleting it. Its owner cannot make it
+/// available again; `enable_upload` undoes this.
+pub async fn disable_upload(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    upload_id: web::Path<String>,
+    body: web::Json<ModerationRequest>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if let Some(res) = check_moderator(&pool, user_id, &upload_id).await? {
+        return Ok(res);
+    }
+
+    let Some(reason) = moderation_reason(&body) else {
+        return Ok(bad_request("A reason of at most 400 characters is required"));
+    };
+
+    let mut tx = pool.begin().await.map_err(error::ErrorInternalServerError)?;
+
+    let disabled = sqlx::query(
+        r#"
+        UPDATE uploads SET is_available = FALSE, removed_by_admin = TRUE, deletion_reason = $2
+        WHERE upload_id = $1 AND is_deleted = FALSE AND NOT removed_by_admin
+        "#
+    )
+    .bind(upload_id.as_str())
+    .bind(&reason)
+    .execute(&mut *tx)
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    if disabled.rows_affected() == 0 {
+        return Ok(bad_request("Upload is already deleted or disabled"));
+    }
+
+    retention::log_deletion(&mut tx, &upload_id, &reason)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    tx.commit().await.map_err(error::ErrorInternalServerError)?;
+
+    audit::record(
+        &pool,
+        &req,
+        Some(user_id),
+        audit::UPLOAD_DISABLED,
+        Some(&format!("upload:{}", upload_id)),
+        serde_json::Value::Null,
+        serde_json::json!({ "reason": reason }),
+    )
+    .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Upload disabled successfully"
+    })))
+}
+
+/// Lifts a moderation disable. The share stays unavailable until its owner
+/// turns it back on.
+pub async fn enable_upload(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    upload_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if let Some(res) = check_moderator(&pool, user_id, &upload_id).await? {
+        return Ok(res);
+    }
+
+    let enabled = sqlx::query(
+        r#"
+        UPDATE uploads SET removed_by_admin = FALSE, deletion_reason = NULL
+        WHERE upload_id = $1 AND is_deleted = FALSE AND removed_by_admin
+        "#
+    )
+    .bind(upload_id.as_str())
+    .execute(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    if enabled.rows_affected() == 0 {
+        return Ok(bad_request("Upload is not disabled by a moderator"));
+    }
+
+    audit::record(
+        &pool,
+        &req,
+        Some(user_id),
+        audit::UPLOAD_ENABLED,
+        Some(&format!("upload:{}", upload_id)),
+        serde_json::Value::Null,
+        serde_json::Value::Null,
+    )
+    .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Upload restored; its owner can make it available again"
+    })))
+}
+
+/// Deletes any user's share along with its files.
+pub async fn delete_any_upload(
+    pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    upload_id: web::Path<String>,
+    body: web::Json<ModerationRequest>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if let Some(res) = check_moderator(&pool, user_id, &upload_id).await? {
+        return Ok(res);
+    }
+
+    let Some(reason) = moderation_reason(&body) else {
+        return Ok(bad_request("A reason of at most 400 characters is required"));
+    };
+
+    let mut tx = pool.begin().await.map_err(error::ErrorInternalServerError)?;
+
+    retention::log_deletion(&mut tx, &upload_id, &reason)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let Some(unreferenced) = retention::mark_deleted(&mut tx, &upload_id, &reason)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+    else {
+        return Ok(bad_request("Upload is already deleted"));
+    };
+
+    sqlx::query("UPDATE uploads SET is_available = FALSE, removed_by_admin = TRUE WHERE upload_id = $1")
+        .bind(upload_id.as_str())
+        .execute(&mut *tx)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    tx.commit().await.map_err(error::ErrorInternalServerError)?;
+
+    retention::remove_contents(&pool, storage.as_ref(), &upload_id, &unreferenced).await;
+
+    audit::record(
+        &pool,
+        &req,
+        Some(user_id),
+        audit::UPLOAD_REMOVED,
+        Some(&format!("upload:{}", upload_id)),
+        serde_json::Value::Null,
+        serde_json::json!({ "reason": reason }),
+    )
+    .await;
+
+    webhooks::notify(&pool, webhooks::UPLOAD_DELETED, &upload_id, serde_json::json!({
+        "reason": reason
+    }));
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Upload deleted successfully"
+    })))
+}
@@ -0,0 +1,337 @@
+use actix_web::cookie::{time::Duration, Cookie, SameSite};
+use actix_web::{error, http::header, web, Error, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+
+use crate::audit;
+use crate::auth::hash_password;
+use crate::config::{Config, OidcConfig};
+use crate::handlers::auth::session_cookies;
+use crate::models::OidcCallbackQuery;
+use crate::oidc::{self, Identity};
+use crate::roles;
+use crate::sessions;
+
+/// How long the provider may take to send the user back.
+const LOGIN_TIMEOUT_MINUTES: i32 = 10;
+
+/// Holds the state of the login this browser started.
+const STATE_COOKIE: &str = "oidc_state";
+const STATE_COOKIE_PATH: &str = "/api/oidc";
+
+/// Lax, so that it is sent along when the provider redirects back.
+fn state_cookie(state: &str) -> Cookie<'static> {
+    Cookie::build(STATE_COOKIE, state.to_string())
+        .path(STATE_COOKIE_PATH)
+        .max_age(Duration::minutes(LOGIN_TIMEOUT_MINUTES.into()))
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .finish()
+}
+
+/// Whether the callback comes back to the browser that started the login.
+/// Otherwise anyone could start a login and send their callback link to a
+/// victim, who would end up signed in to the attacker's account.
+fn started_here(req: &HttpRequest, state: &str) -> bool {
+    req.cookie(STATE_COOKIE).is_some_and(|cookie| cookie.value() == state)
+}
+
+/// Sends the browser back to the login page with an error to show.
+fn redirect_with_error(config: &Config, message: &str) -> HttpResponse {
+    let login_page = format!("{}/auth", config.public_url);
+    let location = reqwest::Url::parse_with_params(&login_page, &[("error", message)])
+        .map(|url| url.to_string())
+        .unwrap_or(login_page);
+
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
+
+fn not_configured() -> HttpResponse {
+    HttpResponse::NotFound().json(serde_json::json!({
+        "error": "Single sign-on is not configured"
+    }))
+}
+
+/// Ways to sign in, for the login page.
+pub async fn get_providers(config: web::Data<Config>) -> Result<HttpResponse, Error> {
+    let oidc = config.oidc.as_ref().map(|oidc| {
+        serde_json::json!({
+            "name": oidc.display_name,
+            "login_url": "/api/oidc/login"
+        })
+    });
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "local_registration": !config.oidc.as_ref().is_some_and(|o| o.disable_local_registration),
+        "oidc": oidc
+    })))
+}
+
+/// Redirects to the identity provider.
+pub async fn login(pool: web::Data<PgPool>, config: web::Data<Config>) -> Result<HttpResponse, Error> {
+    let Some(oidc_config) = &config.oidc else {
+        return Ok(not_configured());
+    };
+
+    let pending = match oidc::begin_login(oidc_config).await {
+        Ok(pending) => pending,
+        Err(e) => {
+            log::error!("Single sign-on failed: {}", e);
+            return Ok(redirect_with_error(&config, "The identity provider is unavailable"));
+        }
+    };
+
+    // Logins that were never completed
+    sqlx::query("DELETE FROM oidc_logins WHERE created_at < NOW() - make_interval(mins => $1)")
+        .bind(LOGIN_TIMEOUT_MINUTES)
+        .execute(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    sqlx::query("INSERT INTO oidc_logins (state, pkce_verifier, nonce) VALUES ($1, $2, $3)")
+        .bind(&pending.state)
+        .bind(&pending.pkce_verifier)
+        .bind(&pending.nonce)
+        .execute(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Found()
+        .cookie(state_cookie(&pending.state))
+        .insert_header((header::LOCATION, pending.authorize_url))
+        .finish())
+}
+
+/// Where the identity provider sends the user back to. Signs them in,
+/// creating their account on first login, and redirects to the frontend.
+pub async fn callback(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    query: web::Query<OidcCallbackQuery>,
+) -> Result<HttpResponse, Error> {
+    let Some(oidc_config) = &config.oidc else {
+        return Ok(not_configured());
+    };
+
+    if let Some(e) = &query.error {
+        let message = query.error_description.as_deref().unwrap_or(e);
+        return Ok(redirect_with_error(&config, message));
+    }
+
+    let (Some(code), Some(state)) = (&query.code, &query.state) else {
+        return Ok(redirect_with_error(&config, "Invalid single sign-on response"));
+    };
+
+    if !started_here(&req, state) {
+        return Ok(redirect_with_error(&config, "Login expired, please try again"));
+    }
+
+    // Each login can only be completed once
+    let pending: Option<(String, String)> = sqlx::query_as(
+        r#"
+        DELETE FROM oidc_logins
+        WHERE state = $1 AND created_at > NOW() - make_interval(mins => $2)
+        RETURNING pkce_verifier, nonce
+        "#
+    )
+    .bind(state)
+    .bind(LOGIN_TIMEOUT_MINUTES)
+    .fetch_optional(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    let Some((pkce_verifier, nonce)) = pending else {
+        return Ok(redirect_with_error(&config, "Login expired, please try again"));
+    };
+
+    let identity = match oidc::finish_login(oidc_config, code, &pkce_verifier, &nonce).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            log::error!("Single sign-on failed: {}", e);
+            return Ok(redirect_with_error(&config, "Single sign-on failed"));
+        }
+    };
+
+    let user_id = match find_or_create_user(&pool, oidc_config, &identity).await? {
+        Ok(user_id) => user_id,
+        Err(message) => return Ok(redirect_with_error(&config, message)),
+    };
+
+    let (is_blocked,): (Option<bool>,) = sqlx::query_as("SELECT is_blocked FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if is_blocked.unwrap_or(false) {
+        return Ok(redirect_with_error(&config, "Account blocked"));
+    }
+
+    // The provider is trusted to have checked any second factor
+    let tokens = sessions::start(&pool, &config, user_id, &req)
+        .await
+        .map_err(|_| error::ErrorInternalServerError("Failed to generate token"))?;
+
+    audit::record(
+        &pool,
+        &req,
+        Some(user_id),
+        audit::LOGIN,
+        Some(&format!("user:{}", user_id)),
+        serde_json::Value::Null,
+        serde_json::json!({ "method": "sso" }),
+    )
+    .await;
+
+    let (access_cookie, refresh_cookie) = session_cookies(&config, &tokens);
+    let mut used_state = state_cookie("");
+    used_state.make_removal();
+
+    Ok(HttpResponse::Found()
+        .cookie(access_cookie)
+        .cookie(refresh_cookie)
+        .cookie(used_state)
+        .insert_header((header::LOCATION, format!("{}/", config.public_url)))
+        .finish())
+}
+
+/// The user linked to the identity. Users are linked by subject, or on
+/// their first single sign-on by verified email; anyone else gets a new
+/// account. Admin rights follow the admin group if one is configured.
+async fn find_or_create_user(
+    pool: &PgPool,
+    oidc_config: &OidcConfig,
+    identity: &Identity,
+) -> Result<Result<i32, &'static str>, Error> {
+    let linked: Option<(i32,)> = sqlx::query_as("SELECT id FROM users WHERE oidc_subject = $1")
+        .bind(&identity.subject)
+        .fetch_optional(pool)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let user_id = match linked {
+        Some((user_id,)) => user_id,
+        None => {
+            let Some(email) = &identity.email else {
+                return Ok(Err("The identity provider did not share an email address"));
+            };
+
+            let existing: Option<(i32, Option<String>)> =
+                sqlx::query_as("SELECT id, oidc_subject FROM users WHERE LOWER(email) = LOWER($1)")
+                    .bind(email)
+                    .fetch_optional(pool)
+                    .await
+                    .map_err(error::ErrorInternalServerError)?;
+
+            match existing {
+                Some((user_id, None)) if identity.email_verified => {
+                    sqlx::query("UPDATE users SET oidc_subject = $1, email_verified = TRUE WHERE id = $2")
+                        .bind(&identity.subject)
+                        .bind(user_id)
+                        .execute(pool)
+                        .await
+                        .map_err(error::ErrorInternalServerError)?;
+                    user_id
+                }
+                Some(_) => return Ok(Err("An account with this email address already exists")),
+                None => create_user(pool, identity, email).await?,
+            }
+        }
+    };
+
+    // The group makes users admins and losing it makes admins users again;
+    // the owner, moderators and guests keep their role
+    if let Some(admin_group) = &oidc_config.admin_group {
+        sqlx::query(
+            r#"
+            UPDATE users SET role = CASE WHEN $1 THEN 'admin' ELSE 'user' END
+            WHERE id = $2 AND ((role = 'user' AND $1) OR (role = 'admin' AND NOT $1))
+            "#
+        )
+            .bind(identity.groups.contains(admin_group))
+            .bind(user_id)
+            .execute(pool)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    }
+
+    Ok(Ok(user_id))
+}
+
+async fn create_user(pool: &PgPool, identity: &Identity, email: &str) -> Result<i32, Error> {
+    let wanted = identity
+        .username
+        .as_deref()
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default());
+    let base: String = wanted
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        .collect();
+    let base = if base.is_empty() { "user".to_string() } else { base };
+
+    // Usernames are unique, so number clashing ones
+    let mut username = base.clone();
+    let mut suffix = 1;
+    loop {
+        let taken: Option<(i32,)> = sqlx::query_as("SELECT id FROM users WHERE username = $1")
+            .bind(&username)
+            .fetch_optional(pool)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        if taken.is_none() {
+            break;
+        }
+        suffix += 1;
+        username = format!("{}-{}", base, suffix);
+    }
+
+    let user_count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users")
+        .fetch_one(pool)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    // Password login stays impossible until the user sets one
+    let password_hash = hash_password(&uuid::Uuid::new_v4().to_string())
+        .map_err(|_| error::ErrorInternalServerError("Failed to hash password"))?;
+
+    let (user_id,): (i32,) = sqlx::query_as(
+        "INSERT INTO users (username, email, password_hash, role, oidc_subject) VALUES ($1, $2, $3, $4, $5) RETURNING id"
+    )
+    .bind(&username)
+    .bind(email)
+    .bind(&password_hash)
+    .bind(if user_count.0 == 0 { roles::OWNER } else { roles::USER })
+    .bind(&identity.subject)
+    .fetch_one(pool)
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    log::info!("Created user {} through single sign-on", username);
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn callback_needs_the_state_cookie_of_this_browser() {
+        let req = TestRequest::default().cookie(state_cookie("abc")).to_http_request();
+        assert!(started_here(&req, "abc"));
+        assert!(!started_here(&req, "attacker-state"));
+
+        let req = TestRequest::default().to_http_request();
+        assert!(!started_here(&req, "abc"));
+    }
+
+    #[test]
+    fn state_cookie_survives_the_redirect_back_only() {
+        let cookie = state_cookie("abc");
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.path(), Some(STATE_COOKIE_PATH));
+    }
+}
@@ -0,0 +1,181 @@
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+
+use crate::audit;
+use crate::auth::{hash_password, verify_password};
+use crate::config::Config;
+use crate::handlers::auth::{check_lockout, session_cookies};
+use crate::mailer::{self, Mailer};
+use crate::models::{ChangePasswordRequest, ForgotPasswordRequest, ResetPasswordRequest};
+use crate::password_resets;
+use crate::rate_limit::{self, RateLimits};
+use crate::sessions;
+use crate::utils::extract_user_id_from_request;
+
+/// Matches what the registration form asks for.
+const MIN_PASSWORD_LENGTH: usize = 6;
+
+fn password_too_short(password: &str) -> Option<HttpResponse> {
+    (password.chars().count() < MIN_PASSWORD_LENGTH).then(|| {
+        HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("Password must be at least {} characters long", MIN_PASSWORD_LENGTH)
+        }))
+    })
+}
+
+/// Changes the password of the logged-in user. Every session ends, and the
+/// caller gets a fresh one so that only this device stays logged in. Wrong
+/// current passwords count towards the same lockout as failed logins.
+pub async fn change_password(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    rate_limits: web::Data<RateLimits>,
+    req: HttpRequest,
+    body: web::Json<ChangePasswordRequest>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if let Some(response) = password_too_short(&body.new_password) {
+        return Ok(response);
+    }
+
+    let (password_hash,): (String,) = sqlx::query_as("SELECT password_hash FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if let Some(response) = check_lockout(&pool, user_id).await? {
+        return Ok(response);
+    }
+
+    if !verify_password(&body.current_password, &password_hash)
+        .map_err(|_| error::ErrorInternalServerError("Password verification failed"))?
+    {
+        rate_limit::record_failed_login(&pool, &rate_limits, user_id)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        audit::record(
+            &pool,
+            &req,
+            Some(user_id),
+            audit::LOGIN_FAILED,
+            Some(&format!("user:{}", user_id)),
+            serde_json::Value::Null,
+            serde_json::json!({ "method": "password_change" }),
+        )
+        .await;
+        return Err(error::ErrorUnauthorized("Invalid password"));
+    }
+
+    rate_limit::clear_failed_logins(&pool, user_id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let new_hash = hash_password(&body.new_password)
+        .map_err(|_| error::ErrorInternalServerError("Failed to hash password"))?;
+
+    sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2")
+        .bind(&new_hash)
+        .bind(user_id)
+        .execute(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    sessions::revoke_all(&pool, user_id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let tokens = sessions::start(&pool, &config, user_id, &req)
+        .await
+        .map_err(|_| error::ErrorInternalServerError("Failed to generate token"))?;
+
+    let (access_cookie, refresh_cookie) = session_cookies(&config, &tokens);
+
+    Ok(HttpResponse::Ok()
+        .cookie(access_cookie)
+        .cookie(refresh_cookie)
+        .json(serde_json::json!({
+            "message": "Password changed, other sessions have been logged out",
+            "token": tokens.access_token,
+            "refresh_token": tokens.refresh_token,
+            "expires_in": config.access_token_minutes * 60
+        })))
+}
+
+/// Emails a reset link if the address belongs to an active account. The
+/// answer is the same either way, so it does not reveal who has an account.
+pub async fn forgot_password(
+    pool: web::Data<PgPool>,
+    mailer: web::Data<Mailer>,
+    body: web::Json<ForgotPasswordRequest>,
+) -> Result<HttpResponse, Error> {
+    if !mailer.is_enabled() {
+        return Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "error": "Password reset by email is not available"
+        })));
+    }
+
+    let user: Option<(i32, String, String)> = sqlx::query_as(
+        "SELECT id, username, email FROM users WHERE LOWER(email) = LOWER($1) AND is_blocked IS NOT TRUE"
+    )
+    .bind(body.email.trim())
+    .fetch_optional(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    if let Some((user_id, username, email)) = user {
+        let (token, expires_at) = password_resets::issue(&pool, user_id)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+
+        // Sent in the background, so the response takes as long whether or
+        // not there was anyone to email
+        let mailer = mailer.into_inner();
+        let pool = pool.get_ref().clone();
+        actix_web::rt::spawn(async move {
+            let vars = [
+                ("username", username),
+                ("reset_url", mailer.link(&format!("/reset-password?token={}", token))),
+                ("expires_at", format!("on {}", expires_at.format("%Y-%m-%d %H:%M UTC"))),
+            ];
+            if let Err(e) = mailer.send_template(&pool, mailer::PASSWORD_RESET, &email, &vars).await {
+                log::warn!("Failed to send password reset email to user {}: {}", user_id, e);
+            }
+        });
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "If an account uses that address, a reset link is on its way"
+    })))
+}
+
+/// Sets a new password with an emailed token and logs out every session.
+pub async fn reset_password(
+    pool: web::Data<PgPool>,
+    body: web::Json<ResetPasswordRequest>,
+) -> Result<HttpResponse, Error> {
+    if let Some(response) = password_too_short(&body.new_password) {
+        return Ok(response);
+    }
+
+    let new_hash = hash_password(&body.new_password)
+        .map_err(|_| error::ErrorInternalServerError("Failed to hash password"))?;
+
+    let Some(user_id) = password_resets::redeem(&pool, &body.token, &new_hash)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+    else {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "This reset link is invalid or has expired"
+        })));
+    };
+
+    sessions::revoke_all(&pool, user_id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Password has been reset, please log in"
+    })))
+}
@@ -3,15 +3,24 @@ use actix_web::{error, web, Error, HttpRequest, HttpResponse};
 use chrono::Utc;
 use futures_util::StreamExt;
 use sqlx::PgPool;
-use std::io::Write;
 use uuid::Uuid;
 
 use crate::config::Config;
+use crate::files::insert_upload_files;
+use crate::mailer::{self, Mailer};
 use crate::models::{CreateTokenRequest, ReverseShareToken, Settings, UploadResponse};
+use crate::quota;
+use crate::roles::Permission;
+use crate::staging::{StageError, UploadStaging};
+use crate::storage::{self, Storage};
 use crate::utils::{
-    calculate_expiry_time, extract_user_id_from_request, is_validity_allowed,
+    calculate_expiry_time, check_permission, extract_user_id_from_request, is_validity_allowed,
     sanitize_filename_safe,
 };
+use crate::webhooks;
+
+/// (id, user_id, name, used_count, max_uses, expires_at)
+type TokenRow = (i32, i32, String, i32, i32, Option<chrono::DateTime<Utc>>);
 
 pub async fn create_token(
     pool: web::Data<PgPool>,
@@ -21,6 +30,12 @@ pub async fn create_token(
 ) -> Result<HttpResponse, Error> {
     let user_id = extract_user_id_from_request(&req, &config)?;
 
+    if !check_permission(user_id, &pool, Permission::CreateShares).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Guest accounts cannot create reverse share links"
+        })));
+    }
+
     let token = Uuid::new_v4().to_string();
     let expires_at = body
         .expires_in
@@ -103,21 +118,22 @@ pub async fn delete_token(
     })))
 }
 
-pub async fn reverse_upload(
-    pool: web::Data<PgPool>,
-    token: web::Path<String>,
-    mut payload: Multipart,
-) -> Result<HttpResponse, Error> {
-    // Validate token
-    let token_data: Option<(i32, i32, String, i32, i32, Option<chrono::DateTime<chrono::Utc>>)> = sqlx::query_as(
+/// Looks up a reverse-share token and checks that it can still be used.
+/// Returns `(token_id, owner_id)`, or the reason to report when the token has
+/// expired or run out of uses.
+pub async fn check_reverse_token(
+    pool: &PgPool,
+    token: &str,
+) -> Result<Result<(i32, i32), &'static str>, Error> {
+    let token_data: Option<TokenRow> = sqlx::query_as(
         r#"
         SELECT id, user_id, name, used_count, max_uses, expires_at
         FROM reverse_share_tokens
         WHERE token = $1
         "#
     )
-    .bind(token.as_str())
-    .fetch_optional(pool.as_ref())
+    .bind(token)
+    .fetch_optional(pool)
     .await
     .map_err(error::ErrorInternalServerError)?;
 
@@ -127,19 +143,35 @@ pub async fn reverse_upload(
     // Check expiration
     if let Some(exp) = expires_at {
         if exp < Utc::now() {
-            return Ok(HttpResponse::Forbidden().json(serde_json::json!({
-                "error": "Token has expired"
-            })));
+            return Ok(Err("Token has expired"));
         }
     }
 
     // Check max uses
     if max_uses != -1 && used_count >= max_uses {
-        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
-            "error": "Token has reached maximum uses"
-        })));
+        return Ok(Err("Token has reached maximum uses"));
     }
 
+    Ok(Ok((token_id, user_id)))
+}
+
+pub async fn reverse_upload(
+    pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
+    mailer: web::Data<Mailer>,
+    token: web::Path<String>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    // Validate token
+    let (token_id, user_id) = match check_reverse_token(&pool, &token).await? {
+        Ok(ids) => ids,
+        Err(reason) => {
+            return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+                "error": reason
+            })));
+        }
+    };
+
     // Get settings
     let settings: Settings = sqlx::query_as("SELECT * FROM settings ORDER BY id LIMIT 1")
         .fetch_optional(pool.as_ref())
@@ -154,12 +186,17 @@ pub async fn reverse_upload(
     };
 
     let upload_id = Uuid::new_v4().to_string();
-    let mut uploaded_files = Vec::new();
-    let mut total_size: i64 = 0;
     let mut email = String::new();
     let mut validity = String::from("7days");
 
-    std::fs::create_dir_all("./uploads").map_err(error::ErrorInternalServerError)?;
+    // Reverse uploads count against the token owner's quota
+    let usage = quota::storage_usage(pool.as_ref(), user_id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let mut staging = UploadStaging::new(&upload_id, max_size, usage.remaining())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
 
     // Process multipart
     while let Some(item) = payload.next().await {
@@ -185,28 +222,21 @@ pub async fn reverse_upload(
                     })));
                 }
 
-                let mut file_data = Vec::new();
-                while let Some(chunk) = field.next().await {
-                    let data = chunk.map_err(error::ErrorBadRequest)?;
-                    file_data.extend_from_slice(&data);
+                match staging.stage_field(&mut field, filename.clone()).await {
+                    Ok(()) => {}
+                    Err(StageError::LimitExceeded { limit }) => {
+                        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                            "error": format!("Total file size exceeds maximum allowed size ({} bytes)", limit)
+                        })));
+                    }
+                    Err(StageError::QuotaExceeded { .. }) => {
+                        return Ok(HttpResponse::PayloadTooLarge().json(serde_json::json!({
+                            "error": "The owner of this link has run out of storage space"
+                        })));
+                    }
+                    Err(StageError::Payload(e)) => return Err(error::ErrorBadRequest(e)),
+                    Err(StageError::Io(e)) => return Err(error::ErrorInternalServerError(e)),
                 }
-
-                let file_size = file_data.len() as i64;
-                total_size += file_size;
-
-                if total_size > max_size {
-                    return Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                        "error": format!("Total file size ({} bytes) exceeds maximum allowed size ({} bytes)", total_size, max_size)
-                    })));
-                }
-
-                let file_path = format!("./uploads/{}_{}", upload_id, sanitized);
-                let mut file = std::fs::File::create(&file_path)
-                    .map_err(error::ErrorInternalServerError)?;
-                file.write_all(&file_data)
-                    .map_err(error::ErrorInternalServerError)?;
-
-                uploaded_files.push(filename);
             }
             "email" => {
                 while let Some(chunk) = field.next().await {
@@ -224,7 +254,7 @@ pub async fn reverse_upload(
         }
     }
 
-    if uploaded_files.is_empty() {
+    if staging.is_empty() {
         return Ok(HttpResponse::BadRequest().json(serde_json::json!({
             "error": "No files uploaded"
         })));
@@ -235,6 +265,9 @@ pub async fn reverse_upload(
         validity = settings.max_validity.clone();
     }
 
+    let uploaded_files = staging.original_names();
+    let total_size = staging.total_size();
+
     let expires_at = calculate_expiry_time(&validity);
     let files_json = serde_json::to_string(&uploaded_files)
         .map_err(error::ErrorInternalServerError)?;
@@ -243,6 +276,18 @@ pub async fn reverse_upload(
     let email_value = if email.is_empty() { None } else { Some(email) };
 
     // Save upload
+    let mut tx = pool.begin().await.map_err(error::ErrorInternalServerError)?;
+
+    if quota::reserve(&mut tx, user_id, total_size)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .is_err()
+    {
+        return Ok(HttpResponse::PayloadTooLarge().json(serde_json::json!({
+            "error": "The owner of this link has run out of storage space"
+        })));
+    }
+
     sqlx::query(
         r#"
         INSERT INTO uploads (user_id, upload_id, files, total_size, email, download_url, expires_at, is_available, is_reverse, reverse_token)
@@ -253,26 +298,45 @@ pub async fn reverse_upload(
     .bind(&upload_id)
     .bind(&files_json)
     .bind(total_size)
-    .bind(email_value)
+    .bind(&email_value)
     .bind(&download_url)
     .bind(expires_at)
     .bind(true)
     .bind(true)
     .bind(token.as_str())
-    .execute(pool.as_ref())
+    .execute(&mut *tx)
     .await
     .map_err(error::ErrorInternalServerError)?;
 
     // Update token usage
     sqlx::query("UPDATE reverse_share_tokens SET used_count = used_count + 1 WHERE id = $1")
         .bind(token_id)
-        .execute(pool.as_ref())
+        .execute(&mut *tx)
         .await
         .map_err(|e| {
             log::error!("Failed to update token usage count: {}", e);
             error::ErrorInternalServerError("Failed to update token usage")
         })?;
 
+    insert_upload_files(&mut tx, &upload_id, &staging.file_records())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let stored_keys = staging
+        .persist(&mut tx, storage.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if let Err(e) = tx.commit().await {
+        storage::discard(storage.as_ref(), &stored_keys).await;
+        return Err(error::ErrorInternalServerError(e));
+    }
+
+    mailer::notify_upload(&mailer, &pool, &upload_id);
+    webhooks::notify(&pool, webhooks::REVERSE_UPLOAD_RECEIVED, &upload_id, serde_json::json!({
+        "uploader_email": email_value,
+    }));
+
     let files_count = uploaded_files.len();
     Ok(HttpResponse::Ok().json(UploadResponse {
         download_url,
@@ -0,0 +1,89 @@
+use actix_web::{error, web, Error, HttpMessage, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+
+use crate::config::Config;
+use crate::models::SessionInfo;
+use crate::roles::Permission;
+use crate::sessions::{self, AuthenticatedSession};
+use crate::utils::{check_permission, extract_user_id_from_request};
+
+/// The user's live sessions, most recently active first.
+pub async fn get_sessions(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+    let current = req
+        .extensions()
+        .get::<AuthenticatedSession>()
+        .map(|s| s.session_id.clone());
+
+    let sessions: Vec<SessionInfo> = sqlx::query_as(
+        r#"
+        SELECT id, created_at, expires_at, last_seen_at, ip_address, user_agent,
+               id IS NOT DISTINCT FROM $2 AS current
+        FROM sessions
+        WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+        ORDER BY last_seen_at DESC
+        "#
+    )
+    .bind(user_id)
+    .bind(current)
+    .fetch_all(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "sessions": sessions
+    })))
+}
+
+/// Signs out one of the user's sessions, e.g. a forgotten device.
+pub async fn revoke_session(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    session_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    let revoked = sessions::revoke(&pool, user_id, &session_id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if !revoked {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Session not found"
+        })));
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Session revoked successfully"
+    })))
+}
+
+/// Signs a user out everywhere.
+pub async fn revoke_user_sessions(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    target_user_id: web::Path<i32>,
+) -> Result<HttpResponse, Error> {
+    let admin_id = extract_user_id_from_request(&req, &config)?;
+
+    if !check_permission(admin_id, &pool, Permission::ManageUsers).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin access required"
+        })));
+    }
+
+    let revoked = sessions::revoke_all(&pool, *target_user_id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Sessions revoked successfully",
+        "revoked": revoked
+    })))
+}
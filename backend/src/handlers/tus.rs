@@ -0,0 +1,715 @@
+use actix_web::{error, http::StatusCode, web, Error, HttpRequest, HttpResponse, HttpResponseBuilder};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::blobs::{self, blob_key};
+use crate::config::Config;
+use crate::files::{guess_mime_type, insert_upload_files, sha256_file, NewUploadFile};
+use crate::handlers::reverse::check_reverse_token;
+use crate::mailer::{self, Mailer};
+use crate::models::{Settings, TusUpload};
+use crate::quota;
+use crate::roles::Permission;
+use crate::storage::Storage;
+use crate::utils::{
+    calculate_expiry_time, check_is_blocked, check_permission, extract_user_id_from_request,
+    is_allowed_file_type, is_validity_allowed, sanitize_filename_safe,
+};
+use crate::webhooks;
+
+// Implements the core tus 1.0.0 protocol plus the creation, termination and
+// expiration extensions. Each tus upload carries a single file; once its last
+// byte arrives it becomes a regular share in the uploads table.
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,termination,expiration";
+const TUS_DIR: &str = "./uploads/.tus";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// Unfinished sessions are dropped after this long without a PATCH.
+const SESSION_TTL_HOURS: i64 = 24;
+
+/// Sessions with a PATCH currently in flight. Only one request may append to
+/// a given upload at a time.
+fn active_patches() -> &'static Mutex<HashSet<String>> {
+    static ACTIVE: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    ACTIVE.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+struct PatchGuard(String);
+
+impl PatchGuard {
+    fn acquire(id: &str) -> Option<Self> {
+        let mut active = active_patches().lock().unwrap_or_else(|e| e.into_inner());
+        if active.insert(id.to_string()) {
+            Some(Self(id.to_string()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for PatchGuard {
+    fn drop(&mut self) {
+        let mut active = active_patches().lock().unwrap_or_else(|e| e.into_inner());
+        active.remove(&self.0);
+    }
+}
+
+fn tus_response(status: StatusCode) -> HttpResponseBuilder {
+    let mut builder = HttpResponse::build(status);
+    builder
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Cache-Control", "no-store"));
+    builder
+}
+
+fn tus_error(status: StatusCode, message: &str) -> HttpResponse {
+    tus_response(status).json(serde_json::json!({
+        "error": message
+    }))
+}
+
+fn header_str<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|h| h.to_str().ok())
+}
+
+/// Every request except OPTIONS must announce the protocol version we speak.
+fn check_version(req: &HttpRequest) -> Option<HttpResponse> {
+    if header_str(req, "Tus-Resumable") == Some(TUS_VERSION) {
+        return None;
+    }
+
+    Some(
+        tus_response(StatusCode::PRECONDITION_FAILED)
+            .insert_header(("Tus-Version", TUS_VERSION))
+            .finish(),
+    )
+}
+
+/// Parses `Upload-Metadata`: comma separated `key base64(value)` pairs.
+fn parse_metadata(raw: &str) -> Result<HashMap<String, String>, String> {
+    let mut metadata = HashMap::new();
+
+    for pair in raw.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let mut parts = pair.splitn(2, ' ');
+        let key = parts.next().unwrap_or_default().to_string();
+        let value = match parts.next() {
+            Some(encoded) => {
+                let bytes = base64::engine::general_purpose::STANDARD
+                    .decode(encoded.trim())
+                    .map_err(|_| format!("Invalid metadata value for '{}'", key))?;
+                String::from_utf8(bytes)
+                    .map_err(|_| format!("Metadata value for '{}' is not UTF-8", key))?
+            }
+            None => String::new(),
+        };
+        metadata.insert(key, value);
+    }
+
+    Ok(metadata)
+}
+
+fn session_path(id: &str) -> std::path::PathBuf {
+    std::path::Path::new(TUS_DIR).join(id)
+}
+
+fn session_expiry(updated_at: DateTime<Utc>) -> String {
+    (updated_at + chrono::Duration::hours(SESSION_TTL_HOURS))
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+async fn load_settings(pool: &PgPool) -> Result<Settings, Error> {
+    Ok(sqlx::query_as("SELECT * FROM settings ORDER BY id LIMIT 1")
+        .fetch_optional(pool)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .unwrap_or_default())
+}
+
+fn max_upload_size(settings: &Settings) -> i64 {
+    if settings.max_upload_size > 0 {
+        settings.max_upload_size
+    } else {
+        100 * 1024 * 1024 // 100MB default
+    }
+}
+
+async fn load_session(pool: &PgPool, id: &str) -> Result<Option<TusUpload>, Error> {
+    sqlx::query_as(
+        r#"
+        SELECT id, user_id, reverse_token, filename, upload_length, upload_offset, email, validity,
+               upload_id, created_at, updated_at
+        FROM tus_uploads
+        WHERE id = $1
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+    .map_err(error::ErrorInternalServerError)
+}
+
+/// Sessions opened by a logged-in user can only be touched by that user.
+/// Reverse-share sessions are addressed by their unguessable id alone, since
+/// the uploader has no account.
+fn can_access(session: &TusUpload, req: &HttpRequest, config: &Config) -> bool {
+    if session.reverse_token.is_some() {
+        return true;
+    }
+
+    extract_user_id_from_request(req, config)
+        .map(|user_id| user_id == session.user_id)
+        .unwrap_or(false)
+}
+
+pub async fn options(pool: web::Data<PgPool>) -> Result<HttpResponse, Error> {
+    let settings = load_settings(&pool).await?;
+
+    Ok(tus_response(StatusCode::NO_CONTENT)
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", max_upload_size(&settings).to_string()))
+        .finish())
+}
+
+pub async fn create(
+    pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
+    mailer: web::Data<Mailer>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    if let Some(res) = check_version(&req) {
+        return Ok(res);
+    }
+
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if !check_permission(user_id, &pool, Permission::CreateShares).await.unwrap_or(false) {
+        return Ok(tus_error(StatusCode::FORBIDDEN, "Guest accounts cannot upload files"));
+    }
+
+    create_session(&pool, storage.as_ref(), &mailer, &req, user_id, None).await
+}
+
+pub async fn create_reverse(
+    pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
+    mailer: web::Data<Mailer>,
+    req: HttpRequest,
+    token: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    if let Some(res) = check_version(&req) {
+        return Ok(res);
+    }
+
+    let user_id = match check_reverse_token(&pool, &token).await? {
+        Ok((_, owner_id)) => owner_id,
+        Err(reason) => return Ok(tus_error(StatusCode::FORBIDDEN, reason)),
+    };
+
+    create_session(&pool, storage.as_ref(), &mailer, &req, user_id, Some(token.into_inner())).await
+}
+
+async fn create_session(
+    pool: &PgPool,
+    storage: &dyn Storage,
+    mailer: &Arc<Mailer>,
+    req: &HttpRequest,
+    user_id: i32,
+    reverse_token: Option<String>,
+) -> Result<HttpResponse, Error> {
+    // Check if user is blocked
+    if check_is_blocked(user_id, pool).await.unwrap_or(false) {
+        return Ok(tus_error(
+            StatusCode::FORBIDDEN,
+            "Account blocked - uploads are not allowed",
+        ));
+    }
+
+    let upload_length = match header_str(req, "Upload-Length").map(|v| v.parse::<i64>()) {
+        Some(Ok(length)) if length >= 0 => length,
+        Some(_) => return Ok(tus_error(StatusCode::BAD_REQUEST, "Invalid Upload-Length")),
+        None => {
+            return Ok(tus_error(
+                StatusCode::BAD_REQUEST,
+                "Upload-Length is required (deferred length is not supported)",
+            ))
+        }
+    };
+
+    let settings = load_settings(pool).await?;
+    let max_size = max_upload_size(&settings);
+
+    if upload_length > max_size {
+        return Ok(tus_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &format!("File size ({} bytes) exceeds maximum allowed size ({} bytes)", upload_length, max_size),
+        ));
+    }
+
+    let usage = quota::storage_usage(pool, user_id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if !usage.allows(upload_length) {
+        let message = if reverse_token.is_some() {
+            "The owner of this link has run out of storage space".to_string()
+        } else {
+            usage.exceeded_message(upload_length)
+        };
+        return Ok(tus_error(StatusCode::PAYLOAD_TOO_LARGE, &message));
+    }
+
+    let metadata = match parse_metadata(header_str(req, "Upload-Metadata").unwrap_or("")) {
+        Ok(metadata) => metadata,
+        Err(e) => return Ok(tus_error(StatusCode::BAD_REQUEST, &e)),
+    };
+
+    // tus-js-client uses "filename", Uppy uses "name"
+    let filename = match metadata.get("filename").or_else(|| metadata.get("name")) {
+        Some(name) if !name.is_empty() => name.clone(),
+        _ => return Ok(tus_error(StatusCode::BAD_REQUEST, "Missing filename metadata")),
+    };
+
+    if !is_allowed_file_type(&filename) {
+        return Ok(tus_error(
+            StatusCode::BAD_REQUEST,
+            &format!("File type not allowed: {}", filename),
+        ));
+    }
+
+    if sanitize_filename_safe(&filename).is_empty() {
+        return Ok(tus_error(StatusCode::BAD_REQUEST, "Invalid filename"));
+    }
+
+    let mut validity = metadata
+        .get("validity")
+        .filter(|v| !v.is_empty())
+        .cloned()
+        .unwrap_or_else(|| "7days".to_string());
+
+    if !is_validity_allowed(&validity, &settings.max_validity) {
+        if reverse_token.is_some() {
+            validity = settings.max_validity.clone();
+        } else {
+            return Ok(tus_error(
+                StatusCode::BAD_REQUEST,
+                &format!("Requested validity '{}' exceeds maximum allowed '{}'", validity, settings.max_validity),
+            ));
+        }
+    }
+
+    let email = metadata.get("email").filter(|e| !e.is_empty()).cloned();
+
+    let id = Uuid::new_v4().simple().to_string();
+
+    tokio::fs::create_dir_all(TUS_DIR)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    tokio::fs::File::create(session_path(&id))
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let inserted: Result<(DateTime<Utc>,), _> = sqlx::query_as(
+        r#"
+        INSERT INTO tus_uploads (id, user_id, reverse_token, filename, upload_length, email, validity)
+        VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING updated_at
+        "#,
+    )
+    .bind(&id)
+    .bind(user_id)
+    .bind(&reverse_token)
+    .bind(&filename)
+    .bind(upload_length)
+    .bind(&email)
+    .bind(&validity)
+    .fetch_one(pool)
+    .await;
+
+    let updated_at = match inserted {
+        Ok(updated_at) => updated_at,
+        Err(e) => {
+            // Without its row nothing would ever clean the file up
+            tokio::fs::remove_file(session_path(&id)).await.ok();
+            return Err(error::ErrorInternalServerError(e));
+        }
+    };
+
+    // An empty file is complete as soon as it is created
+    let mut response = tus_response(StatusCode::CREATED);
+    response
+        .insert_header(("Location", format!("/api/tus/{}", id)))
+        .insert_header(("Upload-Expires", session_expiry(updated_at.0)));
+
+    if upload_length == 0 {
+        let session = load_session(pool, &id)
+            .await?
+            .ok_or_else(|| error::ErrorInternalServerError("Upload session vanished"))?;
+        match finalize(pool, storage, mailer, &session).await? {
+            Ok(download_url) => {
+                response.insert_header(("Upload-Download-Url", download_url));
+            }
+            Err(res) => return Ok(res),
+        }
+    }
+
+    Ok(response.finish())
+}
+
+pub async fn head(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    if let Some(res) = check_version(&req) {
+        return Ok(res);
+    }
+
+    let session = match load_session(&pool, &id).await? {
+        Some(session) if can_access(&session, &req, &config) => session,
+        _ => return Ok(tus_response(StatusCode::NOT_FOUND).finish()),
+    };
+
+    let mut response = tus_response(StatusCode::OK);
+    response
+        .insert_header(("Upload-Offset", session.upload_offset.to_string()))
+        .insert_header(("Upload-Length", session.upload_length.to_string()))
+        .insert_header(("Upload-Expires", session_expiry(session.updated_at)));
+
+    if let Some(upload_id) = &session.upload_id {
+        response.insert_header(("Upload-Download-Url", format!("/download/{}", upload_id)));
+    }
+
+    Ok(response.finish())
+}
+
+pub async fn patch(
+    pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
+    mailer: web::Data<Mailer>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    mut payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    if let Some(res) = check_version(&req) {
+        return Ok(res);
+    }
+
+    if header_str(&req, "Content-Type") != Some(OFFSET_CONTENT_TYPE) {
+        return Ok(tus_error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Content-Type must be application/offset+octet-stream",
+        ));
+    }
+
+    let offset = match header_str(&req, "Upload-Offset").map(|v| v.parse::<i64>()) {
+        Some(Ok(offset)) if offset >= 0 => offset,
+        _ => return Ok(tus_error(StatusCode::BAD_REQUEST, "Invalid Upload-Offset")),
+    };
+
+    let _guard = match PatchGuard::acquire(&id) {
+        Some(guard) => guard,
+        None => {
+            return Ok(tus_error(
+                StatusCode::LOCKED,
+                "Another request is already writing to this upload",
+            ))
+        }
+    };
+
+    let session = match load_session(&pool, &id).await? {
+        Some(session) if can_access(&session, &req, &config) => session,
+        _ => return Ok(tus_response(StatusCode::NOT_FOUND).finish()),
+    };
+
+    // The file of a completed session has moved to its share; HEAD still
+    // reports where
+    if session.upload_id.is_some() {
+        return Ok(tus_error(StatusCode::CONFLICT, "Upload is already complete"));
+    }
+
+    if session.updated_at + chrono::Duration::hours(SESSION_TTL_HOURS) < Utc::now() {
+        return Ok(tus_error(StatusCode::GONE, "Upload session has expired"));
+    }
+
+    if offset != session.upload_offset {
+        return Ok(tus_error(
+            StatusCode::CONFLICT,
+            &format!("Upload-Offset mismatch, expected {}", session.upload_offset),
+        ));
+    }
+
+    // Check if user is blocked
+    if check_is_blocked(session.user_id, &pool).await.unwrap_or(false) {
+        return Ok(tus_error(
+            StatusCode::FORBIDDEN,
+            "Account blocked - uploads are not allowed",
+        ));
+    }
+
+    let remaining = session.upload_length - session.upload_offset;
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(session_path(&session.id))
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    // Bytes past the recorded offset belong to a request that died before it
+    // could record them, so they are overwritten
+    file.set_len(offset as u64)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    file.seek(std::io::SeekFrom::Start(offset as u64))
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let mut written: i64 = 0;
+    let mut failure: Option<HttpResponse> = None;
+
+    while let Some(chunk) = payload.next().await {
+        let data = match chunk {
+            Ok(data) => data,
+            Err(e) => {
+                // The client can resume from whatever made it to disk
+                log::warn!("tus upload {} interrupted: {}", session.id, e);
+                failure = Some(tus_error(StatusCode::BAD_REQUEST, "Upload interrupted"));
+                break;
+            }
+        };
+
+        if written + data.len() as i64 > remaining {
+            failure = Some(tus_error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Request body exceeds the declared Upload-Length",
+            ));
+            break;
+        }
+
+        file.write_all(&data)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        written += data.len() as i64;
+    }
+
+    file.flush().await.map_err(error::ErrorInternalServerError)?;
+    drop(file);
+
+    let new_offset = offset + written;
+    let updated_at: (DateTime<Utc>,) = sqlx::query_as(
+        "UPDATE tus_uploads SET upload_offset = $1, updated_at = CURRENT_TIMESTAMP WHERE id = $2 RETURNING updated_at",
+    )
+    .bind(new_offset)
+    .bind(&session.id)
+    .fetch_one(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    if let Some(res) = failure {
+        return Ok(res);
+    }
+
+    let mut response = tus_response(StatusCode::NO_CONTENT);
+    response
+        .insert_header(("Upload-Offset", new_offset.to_string()))
+        .insert_header(("Upload-Expires", session_expiry(updated_at.0)));
+
+    if new_offset == session.upload_length {
+        match finalize(&pool, storage.as_ref(), &mailer, &session).await? {
+            Ok(download_url) => {
+                response.insert_header(("Upload-Download-Url", download_url));
+            }
+            Err(res) => return Ok(res),
+        }
+    }
+
+    Ok(response.finish())
+}
+
+/// Turns a completed tus session into a share. Returns the download URL, or
+/// the response to send if the share can no longer be created.
+async fn finalize(
+    pool: &PgPool,
+    storage: &dyn Storage,
+    mailer: &Arc<Mailer>,
+    session: &TusUpload,
+) -> Result<Result<String, HttpResponse>, Error> {
+    let mut token_id = None;
+    if let Some(token) = &session.reverse_token {
+        match check_reverse_token(pool, token).await? {
+            Ok((id, _)) => token_id = Some(id),
+            Err(reason) => return Ok(Err(tus_error(StatusCode::FORBIDDEN, reason))),
+        }
+    }
+
+    let path = session_path(&session.id);
+    let sha256 = web::block(move || sha256_file(&path))
+        .await?
+        .map_err(error::ErrorInternalServerError)?;
+
+    let upload_id = Uuid::new_v4().to_string();
+    let expires_at = calculate_expiry_time(&session.validity);
+    let files_json = serde_json::to_string(&vec![session.filename.clone()])
+        .map_err(error::ErrorInternalServerError)?;
+    let download_url = format!("/download/{}", upload_id);
+
+    let mut tx = pool.begin().await.map_err(error::ErrorInternalServerError)?;
+
+    if let Err(usage) = quota::reserve(&mut tx, session.user_id, session.upload_length)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+    {
+        return Ok(Err(tus_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &usage.exceeded_message(session.upload_length),
+        )));
+    }
+
+    sqlx::query(
+        r#"
+        INSERT INTO uploads (user_id, upload_id, files, total_size, email, download_url, expires_at, is_available, is_reverse, reverse_token)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        "#
+    )
+    .bind(session.user_id)
+    .bind(&upload_id)
+    .bind(&files_json)
+    .bind(session.upload_length)
+    .bind(&session.email)
+    .bind(&download_url)
+    .bind(expires_at)
+    .bind(true)
+    .bind(session.reverse_token.is_some())
+    .bind(&session.reverse_token)
+    .execute(&mut *tx)
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    let record = NewUploadFile {
+        original_name: session.filename.clone(),
+        stored_name: blob_key(&sha256),
+        size: session.upload_length,
+        mime_type: guess_mime_type(&session.filename),
+        sha256: Some(sha256.clone()),
+    };
+    insert_upload_files(&mut tx, &upload_id, &[record])
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    sqlx::query("UPDATE tus_uploads SET upload_id = $1 WHERE id = $2")
+        .bind(&upload_id)
+        .bind(&session.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if let Some(token_id) = token_id {
+        sqlx::query("UPDATE reverse_share_tokens SET used_count = used_count + 1 WHERE id = $1")
+            .bind(token_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    }
+
+    let written = blobs::store(
+        &mut tx,
+        storage,
+        &sha256,
+        session.upload_length,
+        &session_path(&session.id),
+    )
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    if let Err(e) = tx.commit().await {
+        if let Some(key) = written {
+            storage.delete(&key).await.ok();
+        }
+        return Err(error::ErrorInternalServerError(e));
+    }
+
+    log::info!("tus upload {} completed as {}", session.id, upload_id);
+
+    mailer::notify_upload(mailer, pool, &upload_id);
+    if session.reverse_token.is_some() {
+        webhooks::notify(pool, webhooks::REVERSE_UPLOAD_RECEIVED, &upload_id, serde_json::json!({
+            "uploader_email": session.email,
+        }));
+    } else {
+        webhooks::notify(pool, webhooks::UPLOAD_CREATED, &upload_id, serde_json::json!({}));
+    }
+
+    Ok(Ok(download_url))
+}
+
+pub async fn terminate(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    if let Some(res) = check_version(&req) {
+        return Ok(res);
+    }
+
+    let _guard = match PatchGuard::acquire(&id) {
+        Some(guard) => guard,
+        None => {
+            return Ok(tus_error(
+                StatusCode::LOCKED,
+                "Another request is already writing to this upload",
+            ))
+        }
+    };
+
+    let session = match load_session(&pool, &id).await? {
+        Some(session) if can_access(&session, &req, &config) => session,
+        _ => return Ok(tus_response(StatusCode::NOT_FOUND).finish()),
+    };
+
+    sqlx::query("DELETE FROM tus_uploads WHERE id = $1")
+        .bind(&session.id)
+        .execute(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    // A completed session's file already belongs to its share
+    if session.upload_id.is_none() {
+        tokio::fs::remove_file(session_path(&session.id)).await.ok();
+    }
+
+    Ok(tus_response(StatusCode::NO_CONTENT).finish())
+}
+
+/// Drops sessions that have not been touched within the expiration window,
+/// together with any partial data. Called from the periodic cleanup task.
+pub async fn purge_expired_sessions(pool: &PgPool) {
+    let expired: Vec<(String, Option<String>)> = sqlx::query_as(
+        "DELETE FROM tus_uploads WHERE updated_at < NOW() - make_interval(hours => $1) RETURNING id, upload_id",
+    )
+    .bind(SESSION_TTL_HOURS as i32)
+    .fetch_all(pool)
+    .await
+    .unwrap_or_else(|e| {
+        log::error!("Failed to purge expired tus sessions: {}", e);
+        Vec::new()
+    });
+
+    for (id, upload_id) in expired {
+        if upload_id.is_none() {
+            if let Err(e) = tokio::fs::remove_file(session_path(&id)).await {
+                log::warn!("Failed to delete partial tus upload {}: {}", id, e);
+            }
+        }
+    }
+}
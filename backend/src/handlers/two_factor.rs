@@ -0,0 +1,216 @@
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+
+use crate::auth::verify_password;
+use crate::config::Config;
+use crate::models::{DisableTwoFactorRequest, TwoFactorCodeRequest};
+use crate::roles;
+use crate::two_factor;
+use crate::utils::extract_user_id_from_request;
+
+pub async fn get_status(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    let (enabled, recovery_codes_left): (bool, i64) = sqlx::query_as(
+        r#"
+        SELECT totp_enabled,
+               (SELECT COUNT(*) FROM recovery_codes WHERE user_id = users.id AND used_at IS NULL)
+        FROM users WHERE id = $1
+        "#
+    )
+    .bind(user_id)
+    .fetch_one(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "enabled": enabled,
+        "recovery_codes_left": recovery_codes_left
+    })))
+}
+
+/// Starts enrolment with a new secret. It only takes effect once a code from
+/// it is confirmed.
+pub async fn setup(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    let (enabled, email, site_name): (bool, String, Option<String>) = sqlx::query_as(
+        "SELECT totp_enabled, email, (SELECT navbar_title FROM settings ORDER BY id LIMIT 1) FROM users WHERE id = $1"
+    )
+    .bind(user_id)
+    .fetch_one(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    if enabled {
+        return Ok(HttpResponse::Conflict().json(serde_json::json!({
+            "error": "Two-factor authentication is already enabled"
+        })));
+    }
+
+    let secret = two_factor::generate_secret();
+    let issuer = site_name.unwrap_or_else(|| "RootDrop".to_string());
+    let otpauth_uri = two_factor::provisioning_uri(&secret, &issuer, &email)
+        .ok_or_else(|| error::ErrorInternalServerError("Failed to create secret"))?;
+
+    sqlx::query("UPDATE users SET totp_secret = $1, totp_last_step = NULL WHERE id = $2")
+        .bind(&secret)
+        .bind(user_id)
+        .execute(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "secret": secret,
+        "otpauth_uri": otpauth_uri
+    })))
+}
+
+/// Enables 2FA once the user proves their authenticator works, and returns
+/// the recovery codes. They are not shown again.
+pub async fn confirm(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    body: web::Json<TwoFactorCodeRequest>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    let (enabled, secret): (bool, Option<String>) =
+        sqlx::query_as("SELECT totp_enabled, totp_secret FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(pool.as_ref())
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+
+    if enabled {
+        return Ok(HttpResponse::Conflict().json(serde_json::json!({
+            "error": "Two-factor authentication is already enabled"
+        })));
+    }
+
+    let Some(secret) = secret else {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Start the setup first"
+        })));
+    };
+
+    if !two_factor::verify_totp(&pool, user_id, &secret, &body.code)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+    {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Invalid authentication code"
+        })));
+    }
+
+    sqlx::query("UPDATE users SET totp_enabled = TRUE WHERE id = $1")
+        .bind(user_id)
+        .execute(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let recovery_codes = two_factor::replace_recovery_codes(&pool, user_id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Two-factor authentication enabled",
+        "recovery_codes": recovery_codes
+    })))
+}
+
+/// Turns 2FA off. Needs the password and a current code, so a stolen session
+/// alone is not enough.
+pub async fn disable(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    body: web::Json<DisableTwoFactorRequest>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    let (password_hash, role, required): (String, String, bool) = sqlx::query_as(
+        r#"
+        SELECT password_hash, role,
+               COALESCE((SELECT require_admin_two_factor FROM settings ORDER BY id LIMIT 1), FALSE)
+        FROM users WHERE id = $1
+        "#
+    )
+    .bind(user_id)
+    .fetch_one(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    if roles::is_admin(&role) && required {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Two-factor authentication is required for admin accounts"
+        })));
+    }
+
+    if !verify_password(&body.password, &password_hash)
+        .map_err(|_| error::ErrorInternalServerError("Password verification failed"))?
+    {
+        return Err(error::ErrorUnauthorized("Invalid password"));
+    }
+
+    if !two_factor::verify(&pool, user_id, &body.code)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+    {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Invalid authentication code"
+        })));
+    }
+
+    sqlx::query("UPDATE users SET totp_enabled = FALSE, totp_secret = NULL, totp_last_step = NULL WHERE id = $1")
+        .bind(user_id)
+        .execute(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Two-factor authentication disabled"
+    })))
+}
+
+/// Replaces the recovery codes, invalidating the old ones.
+pub async fn regenerate_recovery_codes(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    body: web::Json<TwoFactorCodeRequest>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if !two_factor::verify(&pool, user_id, &body.code)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+    {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Invalid authentication code"
+        })));
+    }
+
+    let recovery_codes = two_factor::replace_recovery_codes(&pool, user_id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "recovery_codes": recovery_codes
+    })))
+}
@@ -2,20 +2,44 @@ use actix_multipart::Multipart;
 use actix_web::{error, web, Error, HttpRequest, HttpResponse};
 use futures_util::StreamExt;
 use sqlx::PgPool;
-use std::io::Write;
 use uuid::Uuid;
 
+use crate::auth::hash_password;
+use crate::blobs;
 use crate::config::Config;
+use crate::files::{insert_upload_files, load_upload_files};
+use crate::mailer::{self, Mailer};
 use crate::models::{
-    AvailabilityRequest, ExpirationRequest, Settings, Upload, UploadResponse,
+    AvailabilityRequest, DownloadEvent, ExpirationRequest, Settings, SharePasswordRequest, Upload,
+    UploadResponse,
 };
+use crate::quota;
+use crate::roles::Permission;
+use crate::staging::{StageError, UploadStaging};
+use crate::storage::{self, Storage};
 use crate::utils::{
-    calculate_expiry_time, check_is_blocked, extract_user_id_from_request,
+    calculate_expiry_time, check_is_blocked, check_permission, extract_user_id_from_request,
     is_allowed_file_type, is_validity_allowed, sanitize_filename_safe,
 };
+use crate::webhooks;
+
+/// (upload_id, files, total_size, download_url, created_at, expires_at, is_reverse, reverse_token, is_deleted)
+type DeletableUploadRow = (
+    String,
+    String,
+    i64,
+    String,
+    chrono::DateTime<chrono::Utc>,
+    Option<chrono::DateTime<chrono::Utc>>,
+    bool,
+    Option<String>,
+    bool,
+);
 
 pub async fn upload(
     pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
+    mailer: web::Data<Mailer>,
     config: web::Data<Config>,
     req: HttpRequest,
     mut payload: Multipart,
@@ -29,6 +53,12 @@ pub async fn upload(
         })));
     }
 
+    if !check_permission(user_id, &pool, Permission::CreateShares).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Guest accounts cannot upload files"
+        })));
+    }
+
     // Get settings
     let settings: Settings = sqlx::query_as("SELECT * FROM settings ORDER BY id LIMIT 1")
         .fetch_optional(pool.as_ref())
@@ -43,13 +73,20 @@ pub async fn upload(
     };
 
     let upload_id = Uuid::new_v4().to_string();
-    let mut uploaded_files = Vec::new();
-    let mut total_size: i64 = 0;
     let mut email = String::new();
     let mut validity = String::from("7days");
+    let mut password = String::new();
+    let mut max_downloads_str = String::new();
+
+    let usage = quota::storage_usage(pool.as_ref(), user_id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
 
-    // Create uploads directory
-    std::fs::create_dir_all("./uploads").map_err(error::ErrorInternalServerError)?;
+    // Files are streamed into a private staging directory and only moved into
+    // ./uploads once the whole request has been accepted
+    let mut staging = UploadStaging::new(&upload_id, max_size, usage.remaining())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
 
     // Process multipart form
     while let Some(item) = payload.next().await {
@@ -83,38 +120,21 @@ pub async fn upload(
                     })));
                 }
 
-                let mut file_data = Vec::new();
-                while let Some(chunk) = field.next().await {
-                    let data = chunk.map_err(error::ErrorBadRequest)?;
-                    file_data.extend_from_slice(&data);
-                }
-
-                let file_size = file_data.len() as i64;
-                
-                // Check file size
-                if file_size > max_size {
-                    return Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                        "error": format!("File {} is too large ({} bytes)", filename, file_size)
-                    })));
-                }
-
-                total_size += file_size;
-
-                // Check total size
-                if total_size > max_size {
-                    return Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                        "error": format!("Total file size ({} bytes) exceeds maximum allowed size ({} bytes)", total_size, max_size)
-                    })));
+                match staging.stage_field(&mut field, filename.clone()).await {
+                    Ok(()) => {}
+                    Err(StageError::LimitExceeded { limit }) => {
+                        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                            "error": format!("Upload of {} exceeds maximum allowed size ({} bytes)", filename, limit)
+                        })));
+                    }
+                    Err(StageError::QuotaExceeded { remaining }) => {
+                        return Ok(HttpResponse::PayloadTooLarge().json(serde_json::json!({
+                            "error": format!("Upload of {} exceeds your storage quota ({} bytes left)", filename, remaining)
+                        })));
+                    }
+                    Err(StageError::Payload(e)) => return Err(error::ErrorBadRequest(e)),
+                    Err(StageError::Io(e)) => return Err(error::ErrorInternalServerError(e)),
                 }
-
-                // Save file
-                let file_path = format!("./uploads/{}_{}", upload_id, sanitized);
-                let mut file = std::fs::File::create(&file_path)
-                    .map_err(error::ErrorInternalServerError)?;
-                file.write_all(&file_data)
-                    .map_err(error::ErrorInternalServerError)?;
-
-                uploaded_files.push(filename.to_string());
             }
             "email" => {
                 while let Some(chunk) = field.next().await {
@@ -128,11 +148,23 @@ pub async fn upload(
                     validity = String::from_utf8_lossy(&data).to_string();
                 }
             }
+            "password" => {
+                while let Some(chunk) = field.next().await {
+                    let data = chunk.map_err(error::ErrorBadRequest)?;
+                    password.push_str(&String::from_utf8_lossy(&data));
+                }
+            }
+            "max_downloads" => {
+                while let Some(chunk) = field.next().await {
+                    let data = chunk.map_err(error::ErrorBadRequest)?;
+                    max_downloads_str.push_str(&String::from_utf8_lossy(&data));
+                }
+            }
             _ => {}
         }
     }
 
-    if uploaded_files.is_empty() {
+    if staging.is_empty() {
         return Ok(HttpResponse::BadRequest().json(serde_json::json!({
             "error": "No files uploaded"
         })));
@@ -145,6 +177,22 @@ pub async fn upload(
         })));
     }
 
+    // Empty means unlimited; 1 makes a burn-after-read share
+    let max_downloads = match max_downloads_str.trim() {
+        "" => None,
+        value => match value.parse::<i32>() {
+            Ok(limit) if limit > 0 => Some(limit),
+            _ => {
+                return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": format!("Invalid download limit: {}", value)
+                })));
+            }
+        },
+    };
+
+    let uploaded_files = staging.original_names();
+    let total_size = staging.total_size();
+
     let expires_at = calculate_expiry_time(&validity);
     let files_json = serde_json::to_string(&uploaded_files)
         .map_err(error::ErrorInternalServerError)?;
@@ -152,11 +200,29 @@ pub async fn upload(
 
     let email_value = if email.is_empty() { None } else { Some(email) };
 
+    let password_hash = if password.is_empty() {
+        None
+    } else {
+        Some(hash_password(&password).map_err(|_| error::ErrorInternalServerError("Failed to hash password"))?)
+    };
+
     // Save to database
+    let mut tx = pool.begin().await.map_err(error::ErrorInternalServerError)?;
+
+    // Authoritative check; another upload may have finished in the meantime
+    if let Err(usage) = quota::reserve(&mut tx, user_id, total_size)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+    {
+        return Ok(HttpResponse::PayloadTooLarge().json(serde_json::json!({
+            "error": usage.exceeded_message(total_size)
+        })));
+    }
+
     sqlx::query(
         r#"
-        INSERT INTO uploads (user_id, upload_id, files, total_size, email, download_url, expires_at, is_available, is_reverse)
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        INSERT INTO uploads (user_id, upload_id, files, total_size, email, download_url, expires_at, is_available, is_reverse, password_hash, max_downloads)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
         "#
     )
     .bind(user_id)
@@ -168,10 +234,29 @@ pub async fn upload(
     .bind(expires_at)
     .bind(true)
     .bind(false)
-    .execute(pool.as_ref())
+    .bind(&password_hash)
+    .bind(max_downloads)
+    .execute(&mut *tx)
     .await
     .map_err(error::ErrorInternalServerError)?;
 
+    insert_upload_files(&mut tx, &upload_id, &staging.file_records())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let stored_keys = staging
+        .persist(&mut tx, storage.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if let Err(e) = tx.commit().await {
+        storage::discard(storage.as_ref(), &stored_keys).await;
+        return Err(error::ErrorInternalServerError(e));
+    }
+
+    mailer::notify_upload(&mailer, &pool, &upload_id);
+    webhooks::notify(&pool, webhooks::UPLOAD_CREATED, &upload_id, serde_json::json!({}));
+
     let files_count = uploaded_files.len();
     Ok(HttpResponse::Ok().json(UploadResponse {
         download_url,
@@ -191,7 +276,9 @@ pub async fn get_uploads(
     let uploads: Vec<Upload> = sqlx::query_as(
         r#"
         SELECT id, user_id, upload_id, files, total_size, email, download_url, created_at, expires_at,
-               is_available, is_reverse, reverse_token, is_deleted, deleted_at, deletion_reason
+               is_available, is_reverse, reverse_token, is_deleted, deleted_at, deletion_reason, removed_by_admin,
+               (password_hash IS NOT NULL) AS is_password_protected, max_downloads, download_count,
+               (SELECT MAX(downloaded_at) FROM download_events e WHERE e.upload_id = uploads.upload_id) AS last_downloaded_at
         FROM uploads
         WHERE user_id = $1
         ORDER BY created_at DESC
@@ -209,6 +296,7 @@ pub async fn get_uploads(
 
 pub async fn delete_upload(
     pool: web::Data<PgPool>,
+    storage: web::Data<dyn Storage>,
     config: web::Data<Config>,
     req: HttpRequest,
     upload_id: web::Path<String>,
@@ -216,7 +304,7 @@ pub async fn delete_upload(
     let user_id = extract_user_id_from_request(&req, &config)?;
 
     // Get upload details
-    let upload: Option<(String, String, i64, String, chrono::DateTime<chrono::Utc>, Option<chrono::DateTime<chrono::Utc>>, bool, Option<String>, bool)> = sqlx::query_as(
+    let upload: Option<DeletableUploadRow> = sqlx::query_as(
         r#"
         SELECT u.upload_id, u.files, u.total_size, u.download_url, u.created_at, u.expires_at, u.is_reverse, u.reverse_token, u.is_deleted
         FROM uploads u
@@ -249,8 +337,8 @@ pub async fn delete_upload(
 
     let (username, user_email) = user_info;
 
-    // Parse files
-    let files: Vec<String> = serde_json::from_str(&files_str)
+    let files = load_upload_files(&pool, &uid)
+        .await
         .map_err(error::ErrorInternalServerError)?;
 
     // Begin transaction
@@ -279,11 +367,11 @@ pub async fn delete_upload(
     .map_err(error::ErrorInternalServerError)?;
 
     // Soft delete
-    sqlx::query(
+    let deleted = sqlx::query(
         r#"
         UPDATE uploads
         SET is_deleted = TRUE, deleted_at = CURRENT_TIMESTAMP, deletion_reason = 'User deleted'
-        WHERE user_id = $1 AND upload_id = $2
+        WHERE user_id = $1 AND upload_id = $2 AND is_deleted = FALSE
         "#
     )
     .bind(user_id)
@@ -292,19 +380,34 @@ pub async fn delete_upload(
     .await
     .map_err(error::ErrorInternalServerError)?;
 
+    // A concurrent delete got there first and already released the files
+    if deleted.rows_affected() == 0 {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Upload is already deleted"
+        })));
+    }
+
+    let unreferenced = blobs::release_upload(&mut tx, &uid)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
     tx.commit().await.map_err(error::ErrorInternalServerError)?;
 
-    // Delete physical files
-    for filename in files {
-        let sanitized = sanitize_filename_safe(&filename);
-        let file_path = format!("./uploads/{}_{}", uid, sanitized);
-        if let Err(e) = std::fs::remove_file(&file_path) {
-            log::warn!("Failed to delete file {}: {}", file_path, e);
+    // Delete blobs no other upload shares, and files never moved into the
+    // blob store
+    blobs::purge(&pool, storage.as_ref(), &unreferenced).await;
+    for file in files.iter().filter(|f| !blobs::is_blob_key(&f.stored_name)) {
+        if let Err(e) = storage.delete(&file.stored_name).await {
+            log::warn!("Failed to delete file {}: {}", file.stored_name, e);
         } else {
-            log::info!("Successfully deleted file: {}", file_path);
+            log::info!("Successfully deleted file: {}", file.stored_name);
         }
     }
 
+    webhooks::notify(&pool, webhooks::UPLOAD_DELETED, &uid, serde_json::json!({
+        "reason": "User deleted"
+    }));
+
     Ok(HttpResponse::Ok().json(serde_json::json!({
         "message": "Upload deleted successfully"
     })))
@@ -319,7 +422,22 @@ pub async fn toggle_availability(
 ) -> Result<HttpResponse, Error> {
     let user_id = extract_user_id_from_request(&req, &config)?;
 
-    sqlx::query("UPDATE uploads SET is_available = $1 WHERE user_id = $2 AND upload_id = $3")
+    let removed: Option<(bool,)> = sqlx::query_as(
+        "SELECT removed_by_admin FROM uploads WHERE user_id = $1 AND upload_id = $2"
+    )
+    .bind(user_id)
+    .bind(upload_id.as_str())
+    .fetch_optional(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    if removed == Some((true,)) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "This upload was removed by an administrator"
+        })));
+    }
+
+    sqlx::query("UPDATE uploads SET is_available = $1 WHERE user_id = $2 AND upload_id = $3 AND NOT removed_by_admin")
         .bind(body.is_available)
         .bind(user_id)
         .bind(upload_id.as_str())
@@ -380,3 +498,117 @@ pub async fn update_expiration(
         "message": "Upload expiration updated successfully"
     })))
 }
+
+pub async fn update_password(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    upload_id: web::Path<String>,
+    body: web::Json<SharePasswordRequest>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    // Check if user is blocked
+    if check_is_blocked(user_id, &pool).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Account blocked"
+        })));
+    }
+
+    // Check ownership
+    let owner: Option<(i32,)> = sqlx::query_as(
+        "SELECT user_id FROM uploads WHERE upload_id = $1"
+    )
+    .bind(upload_id.as_str())
+    .fetch_optional(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    let owner_id = owner
+        .ok_or_else(|| error::ErrorNotFound("Upload not found"))?
+        .0;
+
+    if owner_id != user_id {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "You can only update your own uploads"
+        })));
+    }
+
+    let password_hash = match body.password.as_deref() {
+        Some(password) if !password.is_empty() => Some(
+            hash_password(password).map_err(|_| error::ErrorInternalServerError("Failed to hash password"))?,
+        ),
+        _ => None,
+    };
+
+    sqlx::query("UPDATE uploads SET password_hash = $1 WHERE upload_id = $2")
+        .bind(&password_hash)
+        .bind(upload_id.as_str())
+        .execute(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let message = if password_hash.is_some() {
+        "Upload password set successfully"
+    } else {
+        "Upload password removed successfully"
+    };
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": message
+    })))
+}
+
+/// Lists who downloaded an upload and how much of it they received, newest
+/// first.
+pub async fn get_activity(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    upload_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    // Check ownership
+    let upload: Option<(i32, i32, Option<i32>)> = sqlx::query_as(
+        "SELECT user_id, download_count, max_downloads FROM uploads WHERE upload_id = $1"
+    )
+    .bind(upload_id.as_str())
+    .fetch_optional(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    let (owner_id, download_count, max_downloads) =
+        upload.ok_or_else(|| error::ErrorNotFound("Upload not found"))?;
+
+    if owner_id != user_id {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "You can only view activity of your own uploads"
+        })));
+    }
+
+    let events: Vec<DownloadEvent> = sqlx::query_as(
+        r#"
+        SELECT e.id, e.file_id, f.original_name AS file_name, e.ip_address, e.user_agent,
+               e.bytes_served, e.downloaded_at
+        FROM download_events e
+        LEFT JOIN upload_files f ON f.id = e.file_id
+        WHERE e.upload_id = $1
+        ORDER BY e.downloaded_at DESC, e.id DESC
+        "#
+    )
+    .bind(upload_id.as_str())
+    .fetch_all(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    let bytes_served: i64 = events.iter().map(|e| e.bytes_served).sum();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "upload_id": upload_id.as_str(),
+        "download_count": download_count,
+        "max_downloads": max_downloads,
+        "bytes_served": bytes_served,
+        "events": events
+    })))
+}
@@ -0,0 +1,280 @@
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+
+use crate::config::Config;
+use crate::models::{CreateWebhookRequest, UpdateWebhookRequest, Webhook, WebhookDelivery};
+use crate::roles::Permission;
+use crate::utils::{check_permission, extract_user_id_from_request};
+use crate::webhooks;
+
+fn validate_events(events: &[String]) -> Result<(), String> {
+    if events.is_empty() {
+        return Err("Subscribe to at least one event".to_string());
+    }
+
+    match events.iter().find(|e| !webhooks::EVENTS.contains(&e.as_str())) {
+        Some(unknown) => Err(format!("Unknown event: {}", unknown)),
+        None => Ok(()),
+    }
+}
+
+fn bad_request(message: String) -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({
+        "error": message
+    }))
+}
+
+/// Loads a webhook the user may manage: their own, or an admin-level one if
+/// they are an admin.
+async fn load_webhook(pool: &PgPool, webhook_id: i32, user_id: i32) -> Result<Result<Webhook, HttpResponse>, Error> {
+    let webhook: Option<Webhook> = sqlx::query_as(
+        "SELECT id, user_id, url, events, is_active, created_at FROM webhooks WHERE id = $1"
+    )
+    .bind(webhook_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    let allowed = match &webhook {
+        Some(w) if w.user_id == Some(user_id) => true,
+        Some(w) if w.user_id.is_none() => check_permission(user_id, pool, Permission::ManageSettings).await.unwrap_or(false),
+        _ => false,
+    };
+
+    match webhook {
+        Some(webhook) if allowed => Ok(Ok(webhook)),
+        _ => Ok(Err(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Webhook not found"
+        })))),
+    }
+}
+
+pub async fn get_webhooks(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    let webhooks: Vec<Webhook> = sqlx::query_as(
+        "SELECT id, user_id, url, events, is_active, created_at FROM webhooks WHERE user_id = $1 ORDER BY created_at DESC"
+    )
+    .bind(user_id)
+    .fetch_all(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "webhooks": webhooks,
+        "available_events": webhooks::EVENTS
+    })))
+}
+
+/// Admin-level webhooks, which receive events for every user's uploads.
+pub async fn get_global_webhooks(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if !check_permission(user_id, &pool, Permission::ManageSettings).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin access required"
+        })));
+    }
+
+    let webhooks: Vec<Webhook> = sqlx::query_as(
+        "SELECT id, user_id, url, events, is_active, created_at FROM webhooks WHERE user_id IS NULL ORDER BY created_at DESC"
+    )
+    .fetch_all(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "webhooks": webhooks,
+        "available_events": webhooks::EVENTS
+    })))
+}
+
+/// Registers a webhook. The signing secret is only ever returned here.
+pub async fn create_webhook(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    body: web::Json<CreateWebhookRequest>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    if body.global && !check_permission(user_id, &pool, Permission::ManageSettings).await.unwrap_or(false) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin access required"
+        })));
+    }
+
+    let url = body.url.trim();
+    if let Err(message) = webhooks::check_url(url).await {
+        return Ok(bad_request(message));
+    }
+
+    let events = body
+        .events
+        .clone()
+        .unwrap_or_else(|| webhooks::EVENTS.iter().map(|e| e.to_string()).collect());
+    if let Err(message) = validate_events(&events) {
+        return Ok(bad_request(message));
+    }
+
+    let secret = webhooks::generate_secret();
+    let owner_id = if body.global { None } else { Some(user_id) };
+
+    let webhook: Webhook = sqlx::query_as(
+        r#"
+        INSERT INTO webhooks (user_id, url, secret, events)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, user_id, url, events, is_active, created_at
+        "#
+    )
+    .bind(owner_id)
+    .bind(url)
+    .bind(&secret)
+    .bind(&events)
+    .fetch_one(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "webhook": webhook,
+        "secret": secret
+    })))
+}
+
+pub async fn update_webhook(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    webhook_id: web::Path<i32>,
+    body: web::Json<UpdateWebhookRequest>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    let webhook = match load_webhook(&pool, *webhook_id, user_id).await? {
+        Ok(webhook) => webhook,
+        Err(response) => return Ok(response),
+    };
+
+    let url = body.url.as_deref().map(str::trim).unwrap_or(&webhook.url);
+    if let Err(message) = webhooks::check_url(url).await {
+        return Ok(bad_request(message));
+    }
+
+    let events = body.events.as_ref().unwrap_or(&webhook.events);
+    if let Err(message) = validate_events(events) {
+        return Ok(bad_request(message));
+    }
+
+    let updated: Webhook = sqlx::query_as(
+        r#"
+        UPDATE webhooks SET url = $2, events = $3, is_active = $4
+        WHERE id = $1
+        RETURNING id, user_id, url, events, is_active, created_at
+        "#
+    )
+    .bind(webhook.id)
+    .bind(url)
+    .bind(events)
+    .bind(body.is_active.unwrap_or(webhook.is_active))
+    .fetch_one(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(updated))
+}
+
+pub async fn delete_webhook(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    webhook_id: web::Path<i32>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    let webhook = match load_webhook(&pool, *webhook_id, user_id).await? {
+        Ok(webhook) => webhook,
+        Err(response) => return Ok(response),
+    };
+
+    sqlx::query("DELETE FROM webhooks WHERE id = $1")
+        .bind(webhook.id)
+        .execute(pool.as_ref())
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Webhook deleted successfully"
+    })))
+}
+
+/// The webhook's most recent deliveries with the outcome of their last
+/// attempt.
+pub async fn get_deliveries(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    webhook_id: web::Path<i32>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+
+    let webhook = match load_webhook(&pool, *webhook_id, user_id).await? {
+        Ok(webhook) => webhook,
+        Err(response) => return Ok(response),
+    };
+
+    let deliveries: Vec<WebhookDelivery> = sqlx::query_as(
+        r#"
+        SELECT id, event, payload, status, attempts, last_status_code, last_error,
+               created_at, last_attempt_at, next_attempt_at, delivered_at
+        FROM webhook_deliveries
+        WHERE webhook_id = $1
+        ORDER BY created_at DESC, id DESC
+        LIMIT 100
+        "#
+    )
+    .bind(webhook.id)
+    .fetch_all(pool.as_ref())
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "deliveries": deliveries
+    })))
+}
+
+pub async fn redeliver(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<(i32, i64)>,
+) -> Result<HttpResponse, Error> {
+    let user_id = extract_user_id_from_request(&req, &config)?;
+    let (webhook_id, delivery_id) = path.into_inner();
+
+    let webhook = match load_webhook(&pool, webhook_id, user_id).await? {
+        Ok(webhook) => webhook,
+        Err(response) => return Ok(response),
+    };
+
+    let queued = webhooks::redeliver(&pool, webhook.id, delivery_id)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if !queued {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Delivery not found"
+        })));
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Delivery queued"
+    })))
+}
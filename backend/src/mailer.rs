@@ -0,0 +1,343 @@
+use chrono::{DateTime, Utc};
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::config::{Config, MailConfig, SmtpSecurity};
+
+/// A built-in email. Admins may override its subject and body in the
+/// `mail_templates` table; `{{name}}` placeholders are filled in when sending.
+pub struct DefaultTemplate {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub variables: &'static [&'static str],
+    pub subject: &'static str,
+    pub body: &'static str,
+}
+
+pub const UPLOAD_CREATED: &str = "upload_created";
+pub const REVERSE_UPLOAD_RECEIVED: &str = "reverse_upload_received";
+pub const PASSWORD_RESET: &str = "password_reset";
+pub const VERIFY_EMAIL: &str = "verify_email";
+pub const TEST: &str = "test";
+
+pub const TEMPLATES: &[DefaultTemplate] = &[
+    DefaultTemplate {
+        name: UPLOAD_CREATED,
+        description: "Sent to the recipient given with an upload",
+        variables: &["site_name", "uploader", "files", "total_size", "download_url", "expires_at"],
+        subject: "{{uploader}} shared files with you",
+        body: "Hello,\n\n\
+               {{uploader}} shared the following files with you on {{site_name}}:\n\n\
+               {{files}} ({{total_size}})\n\n\
+               Download them at {{download_url}}\n\
+               The link expires {{expires_at}}.\n",
+    },
+    DefaultTemplate {
+        name: REVERSE_UPLOAD_RECEIVED,
+        description: "Sent to the owner of a reverse share link when someone uploads through it",
+        variables: &["site_name", "token_name", "uploader", "files", "total_size", "download_url", "expires_at"],
+        subject: "New upload through \"{{token_name}}\"",
+        body: "Hello,\n\n\
+               {{uploader}} uploaded files through your link \"{{token_name}}\" on {{site_name}}:\n\n\
+               {{files}} ({{total_size}})\n\n\
+               Download them at {{download_url}}\n\
+               The upload expires {{expires_at}}.\n",
+    },
+    DefaultTemplate {
+        name: PASSWORD_RESET,
+        description: "Sent to a user who asked to reset a forgotten password",
+        variables: &["site_name", "username", "reset_url", "expires_at"],
+        subject: "Reset your {{site_name}} password",
+        body: "Hello {{username}},\n\n\
+               Someone asked to reset the password of your {{site_name}} account.\n\
+               To choose a new one, open {{reset_url}}\n\
+               The link works once and expires {{expires_at}}.\n\n\
+               If this wasn't you, you can ignore this email.\n",
+    },
+    DefaultTemplate {
+        name: VERIFY_EMAIL,
+        description: "Sent to a new user to confirm their email address when registration requires it",
+        variables: &["site_name", "username", "verify_url", "expires_at"],
+        subject: "Confirm your email for {{site_name}}",
+        body: "Hello {{username}},\n\n\
+               Thanks for signing up to {{site_name}}. To activate your account, open {{verify_url}}\n\
+               The link expires {{expires_at}}.\n\n\
+               If you didn't sign up, you can ignore this email.\n",
+    },
+    DefaultTemplate {
+        name: TEST,
+        description: "Sent from the admin panel to check the mail settings",
+        variables: &["site_name"],
+        subject: "Test email from {{site_name}}",
+        body: "If you can read this, {{site_name}} can send email.\n",
+    },
+];
+
+pub fn default_template(name: &str) -> Option<&'static DefaultTemplate> {
+    TEMPLATES.iter().find(|t| t.name == name)
+}
+
+/// A template as admins see it: the override if there is one, the built-in
+/// text otherwise.
+#[derive(Debug, Serialize)]
+pub struct MailTemplate {
+    pub name: String,
+    pub description: String,
+    pub variables: Vec<String>,
+    pub subject: String,
+    pub body: String,
+    pub customized: bool,
+}
+
+/// Loads the effective version of a built-in template.
+pub async fn load_template(pool: &PgPool, name: &str) -> Result<Option<MailTemplate>, sqlx::Error> {
+    let Some(default) = default_template(name) else {
+        return Ok(None);
+    };
+
+    let custom: Option<(String, String)> = sqlx::query_as(
+        "SELECT subject, body FROM mail_templates WHERE name = $1"
+    )
+    .bind(name)
+    .fetch_optional(pool)
+    .await?;
+
+    let customized = custom.is_some();
+    let (subject, body) = custom.unwrap_or_else(|| (default.subject.to_string(), default.body.to_string()));
+
+    Ok(Some(MailTemplate {
+        name: default.name.to_string(),
+        description: default.description.to_string(),
+        variables: default.variables.iter().map(|v| v.to_string()).collect(),
+        subject,
+        body,
+        customized,
+    }))
+}
+
+/// Replaces `{{name}}` placeholders; unknown ones are left as they are.
+pub fn render(text: &str, vars: &[(&str, String)]) -> String {
+    vars.iter().fold(text.to_string(), |text, (name, value)| {
+        text.replace(&format!("{{{{{}}}}}", name), value)
+    })
+}
+
+#[derive(Debug)]
+pub enum MailError {
+    NotConfigured,
+    UnknownTemplate(String),
+    Address(lettre::address::AddressError),
+    Message(lettre::error::Error),
+    Smtp(lettre::transport::smtp::Error),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailError::NotConfigured => write!(f, "Email is not configured"),
+            MailError::UnknownTemplate(name) => write!(f, "Unknown mail template: {}", name),
+            MailError::Address(e) => write!(f, "Invalid email address: {}", e),
+            MailError::Message(e) => write!(f, "Failed to build email: {}", e),
+            MailError::Smtp(e) => write!(f, "Failed to send email: {}", e),
+            MailError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MailError {}
+
+impl From<sqlx::Error> for MailError {
+    fn from(e: sqlx::Error) -> Self {
+        MailError::Database(e)
+    }
+}
+
+/// Sends email through the configured SMTP server. Without one, nothing is
+/// sent.
+pub struct Mailer {
+    transport: Option<(AsyncSmtpTransport<Tokio1Executor>, Mailbox)>,
+    public_url: String,
+}
+
+impl Mailer {
+    pub fn from_config(config: &Config) -> Result<Self, MailError> {
+        let transport = config.mail.as_ref().map(build_transport).transpose()?;
+
+        Ok(Self {
+            transport,
+            public_url: config.public_url.clone(),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.transport.is_some()
+    }
+
+    /// Turns a path such as an upload's `download_url` into a full link.
+    pub fn link(&self, path: &str) -> String {
+        format!("{}{}", self.public_url, path)
+    }
+
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), MailError> {
+        let (transport, from) = self.transport.as_ref().ok_or(MailError::NotConfigured)?;
+
+        let message = Message::builder()
+            .from(from.clone())
+            .to(to.parse().map_err(MailError::Address)?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(MailError::Message)?;
+
+        transport.send(message).await.map_err(MailError::Smtp)?;
+        Ok(())
+    }
+
+    /// Renders a template with `vars` plus `site_name` and sends it.
+    pub async fn send_template(
+        &self,
+        pool: &PgPool,
+        name: &str,
+        to: &str,
+        vars: &[(&str, String)],
+    ) -> Result<(), MailError> {
+        if !self.is_enabled() {
+            return Err(MailError::NotConfigured);
+        }
+
+        let template = load_template(pool, name)
+            .await?
+            .ok_or_else(|| MailError::UnknownTemplate(name.to_string()))?;
+
+        let site_name: Option<(String,)> = sqlx::query_as("SELECT navbar_title FROM settings ORDER BY id LIMIT 1")
+            .fetch_optional(pool)
+            .await?;
+        let mut vars = vars.to_vec();
+        vars.push(("site_name", site_name.map(|(s,)| s).unwrap_or_else(|| "RootDrop".to_string())));
+
+        self.send(to, &render(&template.subject, &vars), render(&template.body, &vars))
+            .await
+    }
+}
+
+fn build_transport(config: &MailConfig) -> Result<(AsyncSmtpTransport<Tokio1Executor>, Mailbox), MailError> {
+    let builder = match config.security {
+        SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.smtp_host),
+        SmtpSecurity::StartTls => {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host).map_err(MailError::Smtp)?
+        }
+        SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp_host).map_err(MailError::Smtp)?,
+    };
+
+    let mut builder = builder.port(config.smtp_port);
+    if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+        builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+
+    let from = config.from.parse().map_err(MailError::Address)?;
+    Ok((builder.build(), from))
+}
+
+/// (is_reverse, recipient email, download_url, files, total_size, expires_at,
+/// username, owner email, reverse token name)
+type NotificationRow = (
+    bool,
+    Option<String>,
+    String,
+    String,
+    i64,
+    Option<DateTime<Utc>>,
+    String,
+    Option<String>,
+    Option<String>,
+);
+
+/// Emails whoever should hear about a new upload, in the background: the
+/// recipient given with a regular upload, or the owner of the reverse share
+/// link it came through.
+pub fn notify_upload(mailer: &Arc<Mailer>, pool: &PgPool, upload_id: &str) {
+    if !mailer.is_enabled() {
+        return;
+    }
+
+    let mailer = Arc::clone(mailer);
+    let pool = pool.clone();
+    let upload_id = upload_id.to_string();
+    actix_web::rt::spawn(async move {
+        if let Err(e) = send_upload_notification(&mailer, &pool, &upload_id).await {
+            log::warn!("Failed to send notification for upload {}: {}", upload_id, e);
+        }
+    });
+}
+
+async fn send_upload_notification(mailer: &Mailer, pool: &PgPool, upload_id: &str) -> Result<(), MailError> {
+    let row: Option<NotificationRow> = sqlx::query_as(
+        r#"
+        SELECT up.is_reverse, up.email, up.download_url, up.files, up.total_size, up.expires_at,
+               u.username, u.email, t.name
+        FROM uploads up
+        JOIN users u ON u.id = up.user_id
+        LEFT JOIN reverse_share_tokens t ON t.token = up.reverse_token
+        WHERE up.upload_id = $1
+        "#,
+    )
+    .bind(upload_id)
+    .fetch_optional(pool)
+    .await?;
+
+    let Some((is_reverse, email, download_url, files, total_size, expires_at, username, owner_email, token_name)) = row
+    else {
+        return Ok(());
+    };
+
+    let files: Vec<String> = serde_json::from_str(&files).unwrap_or_default();
+    let mut vars = vec![
+        ("files", files.join(", ")),
+        ("total_size", format_size(total_size)),
+        ("download_url", mailer.link(&download_url)),
+        (
+            "expires_at",
+            expires_at
+                .map(|t| format!("on {}", t.format("%Y-%m-%d %H:%M UTC")))
+                .unwrap_or_else(|| "never".to_string()),
+        ),
+    ];
+
+    if is_reverse {
+        let Some(owner_email) = owner_email.filter(|e| !e.is_empty()) else {
+            return Ok(());
+        };
+        vars.push(("uploader", email.unwrap_or_else(|| "Someone".to_string())));
+        vars.push(("token_name", token_name.unwrap_or_default()));
+        mailer.send_template(pool, REVERSE_UPLOAD_RECEIVED, &owner_email, &vars).await
+    } else {
+        let Some(email) = email.filter(|e| !e.is_empty()) else {
+            return Ok(());
+        };
+        vars.push(("uploader", username));
+        mailer.send_template(pool, UPLOAD_CREATED, &email, &vars).await
+    }
+}
+
+fn format_size(bytes: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
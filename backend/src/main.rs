@@ -1,16 +1,38 @@
+mod api_tokens;
+mod audit;
 mod auth;
+mod blobs;
 mod config;
 mod db;
+mod email_verification;
+mod files;
 mod handlers;
+mod mailer;
 mod middleware;
 mod models;
+mod oidc;
+mod password_resets;
+mod quota;
+mod rate_limit;
+mod retention;
+mod roles;
+mod sessions;
+mod staging;
+mod storage;
+mod two_factor;
 mod utils;
+mod webhooks;
+mod zip_stream;
 mod tui;
 mod tui_middleware;
 
 use actix_cors::Cors;
 use actix_files as fs;
-use actix_web::{middleware::Logger, web, App, HttpServer};
+use actix_web::{
+    http::{header::HeaderName, Method},
+    middleware::Logger,
+    web, App, HttpServer,
+};
 use config::Config;
 use sqlx::postgres::PgPoolOptions;
 use std::sync::Arc;
@@ -19,6 +41,16 @@ use tui_middleware::TuiLogging;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    // `migrate ...` manages the schema and exits, without the TUI
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("migrate") {
+        if let Err(e) = migrate_command(&args[1..]).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     // Create TUI logger
     let (tui_logger, rx) = create_logger();
     let tui_logger = Arc::new(tui_logger);
@@ -60,7 +92,7 @@ async fn main() -> std::io::Result<()> {
     // Run migrations
     db::run_migrations(&db_pool)
         .await
-        .expect("Failed to run migrations");
+        .unwrap_or_else(|e| panic!("Failed to run migrations: {}", e));
 
     tui_logger.log(
         LogLevel::Info,
@@ -68,6 +100,34 @@ async fn main() -> std::io::Result<()> {
         Some("database".to_string()),
     );
 
+    // Set up file storage
+    let file_storage = storage::from_config(&config.storage)
+        .await
+        .expect("Failed to initialize file storage");
+
+    tui_logger.log(
+        LogLevel::Info,
+        format!(
+            "File storage ready ({})",
+            match &config.storage {
+                config::StorageConfig::Local { root } => format!("local: {}", root),
+                config::StorageConfig::S3(s3) => format!("s3: {}", s3.bucket),
+            }
+        ),
+        Some("storage".to_string()),
+    );
+
+    let mailer = Arc::new(mailer::Mailer::from_config(&config).expect("Invalid mail configuration"));
+
+    tui_logger.log(
+        LogLevel::Info,
+        match &config.mail {
+            Some(mail) => format!("Email enabled via {}:{}", mail.smtp_host, mail.smtp_port),
+            None => "Email disabled (SMTP_HOST not set)".to_string(),
+        },
+        Some("mail".to_string()),
+    );
+
     // Create shared state
     let state = Arc::new(config);
 
@@ -86,6 +146,13 @@ async fn main() -> std::io::Result<()> {
     std::fs::create_dir_all("./logos").ok();
     std::fs::create_dir_all("./backgrounds").ok();
     std::fs::create_dir_all("./avatars").ok();
+    if let Err(e) = staging::purge_stale() {
+        tui_logger.log(
+            LogLevel::Warn,
+            format!("Failed to clear upload staging directory: {}", e),
+            Some("filesystem".to_string()),
+        );
+    }
     
     tui_logger.log(
         LogLevel::Debug,
@@ -93,8 +160,12 @@ async fn main() -> std::io::Result<()> {
         Some("filesystem".to_string()),
     );
 
+    // Files stored before deduplication move into the blob store
+    blobs::migrate_legacy_files(&db_pool, file_storage.as_ref()).await;
+
     // Start background cleanup task
     let cleanup_pool = db_pool.clone();
+    let cleanup_storage = Arc::clone(&file_storage);
     let cleanup_logger = Arc::clone(&tui_logger);
     tokio::spawn(async move {
         cleanup_logger.log(
@@ -102,9 +173,46 @@ async fn main() -> std::io::Result<()> {
             "Started background cleanup task".to_string(),
             Some("cleanup".to_string()),
         );
-        handlers::admin::cleanup_expired_uploads(cleanup_pool).await;
+        handlers::admin::cleanup_expired_uploads(cleanup_pool, cleanup_storage).await;
     });
 
+    // Start webhook delivery worker
+    tokio::spawn(webhooks::run_worker(db_pool.clone()));
+
+    // Rate limits follow the settings; admins saving them reconfigures these
+    let settings: models::Settings = sqlx::query_as("SELECT * FROM settings ORDER BY id LIMIT 1")
+        .fetch_optional(&db_pool)
+        .await
+        .expect("Failed to load settings")
+        .unwrap_or_default();
+    let rate_limits = Arc::new(rate_limit::RateLimits::new(&settings));
+
+    let pruned_limits = Arc::clone(&rate_limits);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(600));
+        loop {
+            interval.tick().await;
+            pruned_limits.retain_recent();
+        }
+    });
+
+    // Without an owner, whoever has the token printed here can claim the
+    // instance once logged in
+    let bootstrap = Arc::new(roles::Bootstrap::default());
+    if let Some(token) = bootstrap
+        .prepare(&db_pool)
+        .await
+        .expect("Failed to check for an owner")
+    {
+        let message = format!(
+            "No owner account exists. Sign in and POST {{\"token\": \"{}\"}} to /api/bootstrap to become the owner",
+            token
+        );
+        // Also goes to the plain log, where it is seen when there is no TUI
+        log::warn!("{}", message);
+        tui_logger.log(LogLevel::Warn, message, Some("auth".to_string()));
+    }
+
     // Clone logger for request handling
     let request_logger = Arc::clone(&tui_logger);
 
@@ -125,30 +233,62 @@ async fn main() -> std::io::Result<()> {
                 }
                 allowed.split(',').any(|o| o.trim() == origin.to_str().unwrap_or(""))
             })
-            .allowed_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS"])
+            .allowed_methods(vec!["GET", "POST", "PUT", "PATCH", "HEAD", "DELETE", "OPTIONS"])
             .allowed_headers(vec![
                 actix_web::http::header::ORIGIN,
                 actix_web::http::header::CONTENT_TYPE,
                 actix_web::http::header::ACCEPT,
                 actix_web::http::header::AUTHORIZATION,
+                HeaderName::from_static("tus-resumable"),
+                HeaderName::from_static("upload-length"),
+                HeaderName::from_static("upload-offset"),
+                HeaderName::from_static("upload-metadata"),
+            ])
+            .expose_headers(vec![
+                actix_web::http::header::LOCATION,
+                actix_web::http::header::RETRY_AFTER,
+                HeaderName::from_static("tus-resumable"),
+                HeaderName::from_static("tus-version"),
+                HeaderName::from_static("tus-extension"),
+                HeaderName::from_static("tus-max-size"),
+                HeaderName::from_static("upload-offset"),
+                HeaderName::from_static("upload-length"),
+                HeaderName::from_static("upload-expires"),
+                HeaderName::from_static("upload-download-url"),
             ])
             .supports_credentials()
             .max_age(3600);
 
         App::new()
+            .wrap(middleware::RateLimit)
             .wrap(TuiLogging::new(Arc::clone(&request_logger)))
             .wrap(Logger::default())
             .wrap(cors)
             .wrap(middleware::SecurityHeaders)
+            .wrap(middleware::Authentication)
             .app_data(web::Data::new(db_pool.clone()))
             .app_data(web::Data::from(state.clone()))
+            .app_data(web::Data::from(Arc::clone(&file_storage)))
+            .app_data(web::Data::from(Arc::clone(&mailer)))
+            .app_data(web::Data::from(Arc::clone(&rate_limits)))
+            .app_data(web::Data::from(Arc::clone(&bootstrap)))
             .service(
                 web::scope("/api")
                     // Auth routes
                     .route("/register", web::post().to(handlers::auth::register))
                     .route("/login", web::post().to(handlers::auth::login))
+                    .route("/login/2fa", web::post().to(handlers::auth::login_two_factor))
+                    .route("/auth/providers", web::get().to(handlers::oidc::get_providers))
+                    .route("/oidc/login", web::get().to(handlers::oidc::login))
+                    .route("/oidc/callback", web::get().to(handlers::oidc::callback))
+                    .route("/verify-email", web::post().to(handlers::auth::verify_email))
+                    .route("/verify-email/resend", web::post().to(handlers::auth::resend_verification))
+                    .route("/refresh", web::post().to(handlers::auth::refresh))
                     .route("/logout", web::post().to(handlers::auth::logout))
                     .route("/me", web::get().to(handlers::auth::me))
+                    .route("/password", web::put().to(handlers::password::change_password))
+                    .route("/password/forgot", web::post().to(handlers::password::forgot_password))
+                    .route("/password/reset", web::post().to(handlers::password::reset_password))
                     .route("/avatar", web::post().to(handlers::auth::upload_avatar))
                     // Upload routes
                     .route("/upload", web::post().to(handlers::upload::upload))
@@ -156,26 +296,76 @@ async fn main() -> std::io::Result<()> {
                     .route("/uploads/{id}", web::delete().to(handlers::upload::delete_upload))
                     .route("/uploads/{id}/availability", web::put().to(handlers::upload::toggle_availability))
                     .route("/uploads/{id}/expiration", web::put().to(handlers::upload::update_expiration))
+                    .route("/uploads/{id}/password", web::put().to(handlers::upload::update_password))
+                    .route("/uploads/{id}/activity", web::get().to(handlers::upload::get_activity))
+                    // Two-factor authentication routes
+                    .route("/2fa", web::get().to(handlers::two_factor::get_status))
+                    .route("/2fa/setup", web::post().to(handlers::two_factor::setup))
+                    .route("/2fa/confirm", web::post().to(handlers::two_factor::confirm))
+                    .route("/2fa/disable", web::post().to(handlers::two_factor::disable))
+                    .route("/2fa/recovery-codes", web::post().to(handlers::two_factor::regenerate_recovery_codes))
+                    // Session routes
+                    .route("/sessions", web::get().to(handlers::sessions::get_sessions))
+                    .route("/sessions/{id}", web::delete().to(handlers::sessions::revoke_session))
+                    // Personal access token routes
+                    .route("/api-tokens", web::get().to(handlers::api_tokens::get_tokens))
+                    .route("/api-tokens", web::post().to(handlers::api_tokens::create_token))
+                    .route("/api-tokens/{id}", web::delete().to(handlers::api_tokens::revoke_token))
+                    // Webhook routes
+                    .route("/webhooks", web::get().to(handlers::webhooks::get_webhooks))
+                    .route("/webhooks", web::post().to(handlers::webhooks::create_webhook))
+                    .route("/webhooks/{id}", web::put().to(handlers::webhooks::update_webhook))
+                    .route("/webhooks/{id}", web::delete().to(handlers::webhooks::delete_webhook))
+                    .route("/webhooks/{id}/deliveries", web::get().to(handlers::webhooks::get_deliveries))
+                    .route("/webhooks/{id}/deliveries/{delivery_id}/redeliver", web::post().to(handlers::webhooks::redeliver))
                     // Reverse share routes
                     .route("/reverse-tokens", web::post().to(handlers::reverse::create_token))
                     .route("/reverse-tokens", web::get().to(handlers::reverse::get_tokens))
                     .route("/reverse-tokens/{id}", web::delete().to(handlers::reverse::delete_token))
                     .route("/reverse-upload/{token}", web::post().to(handlers::reverse::reverse_upload))
+                    .route("/reverse-upload/{token}/tus", web::post().to(handlers::tus::create_reverse))
+                    // Resumable upload routes (tus 1.0.0)
+                    .route("/tus", web::method(Method::OPTIONS).to(handlers::tus::options))
+                    .route("/tus", web::post().to(handlers::tus::create))
+                    .route("/tus/{id}", web::head().to(handlers::tus::head))
+                    .route("/tus/{id}", web::patch().to(handlers::tus::patch))
+                    .route("/tus/{id}", web::delete().to(handlers::tus::terminate))
                     // Download routes
                     .route("/download/{id}", web::get().to(handlers::download::download))
+                    .route("/download/{id}/unlock", web::post().to(handlers::download::unlock))
                     .route("/file/{id}/{filename}", web::get().to(handlers::download::download_file))
                     .route("/files/{id}", web::get().to(handlers::download::get_file_metadata))
                     // Admin routes
+                    .route("/admin/settings", web::get().to(handlers::admin::get_settings))
                     .route("/admin/settings", web::post().to(handlers::admin::update_settings))
                     .route("/admin/stats", web::get().to(handlers::admin::get_stats))
                     .route("/admin/users", web::get().to(handlers::admin::get_users))
                     .route("/admin/users/{id}/block", web::post().to(handlers::admin::block_user))
-                    .route("/admin/users/{id}/promote", web::post().to(handlers::admin::promote_user))
+                    .route("/admin/users/{id}/unlock", web::post().to(handlers::admin::unlock_user))
+                    .route("/admin/users/{id}/role", web::put().to(handlers::admin::set_user_role))
+                    .route("/admin/users/{id}/sessions", web::delete().to(handlers::sessions::revoke_user_sessions))
+                    .route("/admin/users/{id}/quota", web::get().to(handlers::admin::get_user_quota))
+                    .route("/admin/users/{id}/quota", web::put().to(handlers::admin::set_user_quota))
+                    .route("/admin/uploads", web::get().to(handlers::moderation::get_all_uploads))
+                    .route("/admin/uploads/{id}", web::delete().to(handlers::moderation::delete_any_upload))
+                    .route("/admin/uploads/{id}/disable", web::post().to(handlers::moderation::disable_upload))
+                    .route("/admin/uploads/{id}/enable", web::post().to(handlers::moderation::enable_upload))
+                    .route("/admin/deletion-logs", web::get().to(handlers::deletion_logs::get_deletion_logs))
+                    .route("/admin/audit-events", web::get().to(handlers::audit::get_audit_events))
+                    .route("/admin/invites", web::get().to(handlers::invites::get_invites))
+                    .route("/admin/invites", web::post().to(handlers::invites::create_invite))
+                    .route("/admin/invites/{id}", web::delete().to(handlers::invites::revoke_invite))
+                    .route("/admin/transfer-ownership", web::post().to(handlers::admin::transfer_ownership))
                     .route("/admin/quick-settings", web::post().to(handlers::admin::quick_settings))
+                    .route("/admin/webhooks", web::get().to(handlers::webhooks::get_global_webhooks))
+                    .route("/admin/mail-templates", web::get().to(handlers::admin::get_mail_templates))
+                    .route("/admin/mail-templates/{name}", web::put().to(handlers::admin::update_mail_template))
+                    .route("/admin/mail-templates/{name}", web::delete().to(handlers::admin::reset_mail_template))
+                    .route("/admin/mail/test", web::post().to(handlers::admin::send_test_mail))
                     // Settings route (public)
                     .route("/settings", web::get().to(handlers::settings::get_settings))
-                    // Legacy admin promotion
-                    .route("/promote-first-admin", web::post().to(handlers::admin::promote_first_admin))
+                    // Claiming an instance that has no owner
+                    .route("/bootstrap", web::post().to(handlers::admin::bootstrap_owner))
             )
             // Serve static files
             .service(fs::Files::new("/logos", "./logos").show_files_listing())
@@ -186,3 +376,57 @@ async fn main() -> std::io::Result<()> {
     .run()
     .await
 }
+
+const MIGRATE_USAGE: &str = "Usage: pingo-share-backend migrate <status | up | down [version]>";
+
+/// `migrate status` lists every migration and whether it is applied,
+/// `migrate up` applies the pending ones, and `migrate down` reverts the
+/// latest one, or with a version every one after it.
+async fn migrate_command(args: &[String]) -> std::io::Result<()> {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+
+    let config = Config::from_env();
+    let db_pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&config.database_url)
+        .await
+        .map_err(std::io::Error::other)?;
+
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["status"] => {
+            let status = db::migration_status(&db_pool).await.map_err(std::io::Error::other)?;
+            println!("{:<8} {:<24} Description", "Version", "Applied");
+            for migration in status {
+                let applied = migration
+                    .installed_on
+                    .map(|on| on.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                    .unwrap_or_else(|| "pending".to_string());
+                let mut description = migration
+                    .description
+                    .unwrap_or_else(|| "(unknown to this build)".to_string());
+                if !migration.checksum_ok {
+                    description.push_str(" (changed since it was applied)");
+                }
+                println!("{:<8} {:<24} {}", migration.version, applied, description);
+            }
+        }
+        ["up"] => {
+            db::run_migrations(&db_pool).await.map_err(std::io::Error::other)?;
+        }
+        ["down"] | ["down", _] => {
+            let target = match args.get(1) {
+                Some(version) => Some(version.parse::<i64>().map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidInput, MIGRATE_USAGE)
+                })?),
+                None => None,
+            };
+            let version = db::revert_migrations(&db_pool, target)
+                .await
+                .map_err(std::io::Error::other)?;
+            println!("Database schema reverted to version {}", version);
+        }
+        _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, MIGRATE_USAGE)),
+    }
+
+    Ok(())
+}
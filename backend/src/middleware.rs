@@ -1,12 +1,21 @@
 use actix_web::{
-    body::MessageBody,
+    body::{EitherBody, MessageBody},
     dev::{ServiceRequest, ServiceResponse},
     Error,
 };
 use actix_web::dev::{Service, Transform};
+use actix_web::{web, HttpMessage};
 use futures_util::future::{ready, LocalBoxFuture, Ready};
+use sqlx::PgPool;
 use std::rc::Rc;
 
+use crate::api_tokens::{self, TOKEN_PREFIX};
+use crate::auth::validate_jwt;
+use crate::config::Config;
+use crate::rate_limit::{self, RateLimits};
+use crate::sessions::{self, AuthenticatedSession};
+use crate::utils::{self, request_token};
+
 // Security headers middleware
 pub struct SecurityHeaders;
 
@@ -73,3 +82,147 @@ where
         })
     }
 }
+
+// Resolves the request's credentials, which needs the database, before the
+// handler runs: a personal access token, or a JWT whose session is still
+// live. `utils::extract_user_id_from_request` then reads the result.
+pub struct Authentication;
+
+impl<S, B> Transform<S, ServiceRequest> for Authentication
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = AuthenticationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthenticationMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct AuthenticationMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthenticationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &self,
+        ctx: &mut core::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let srv = self.service.clone();
+        Box::pin(async move {
+            let token = request_token(req.request());
+            let pool = req.app_data::<web::Data<PgPool>>().cloned();
+            let config = req.app_data::<web::Data<Config>>().cloned();
+
+            if let (Some(token), Some(pool), Some(config)) = (token, pool, config) {
+                if token.starts_with(TOKEN_PREFIX) {
+                    match api_tokens::authenticate(&pool, &token).await {
+                        Ok(Some(api_token)) => {
+                            req.extensions_mut().insert(api_token);
+                        }
+                        Ok(None) => {}
+                        Err(e) => log::error!("Failed to look up API token: {}", e),
+                    }
+                } else if let Ok(claims) = validate_jwt(&token, &config.jwt_secret) {
+                    match sessions::is_active(&pool, &claims.jti, claims.user_id).await {
+                        Ok(true) => {
+                            req.extensions_mut().insert(AuthenticatedSession {
+                                user_id: claims.user_id,
+                                session_id: claims.jti,
+                            });
+                        }
+                        Ok(false) => {}
+                        Err(e) => log::error!("Failed to look up session: {}", e),
+                    }
+                }
+            }
+
+            srv.call(req).await
+        })
+    }
+}
+
+// Answers 429 to clients over the per-IP limit of a rate-limited route, see
+// `rate_limit::bucket_for`. Limits come from the `RateLimits` app data.
+pub struct RateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &self,
+        ctx: &mut core::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let srv = self.service.clone();
+        Box::pin(async move {
+            let bucket = req
+                .match_pattern()
+                .and_then(|pattern| rate_limit::bucket_for(req.method(), &pattern));
+            let limits = req.app_data::<web::Data<RateLimits>>().cloned();
+
+            if let (Some(bucket), Some(limits)) = (bucket, limits) {
+                // Forwarding headers only count behind a trusted proxy, so
+                // clients cannot pick a fresh key for every request
+                let ip = utils::client_ip(req.request()).unwrap_or_else(|| "unknown".to_string());
+
+                if let Err(retry_after) = limits.check(bucket, &ip) {
+                    let response = rate_limit::too_many_requests(retry_after, "Too many requests, please slow down");
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            }
+
+            srv.call(req).await.map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
@@ -9,7 +9,7 @@ pub struct User {
     pub email: String,
     #[serde(skip_serializing)]
     pub password_hash: String,
-    pub is_admin: bool,
+    pub role: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub is_blocked: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -17,6 +17,10 @@ pub struct User {
     pub created_at: DateTime<Utc>,
 }
 
+pub const REGISTRATION_OPEN: &str = "open";
+pub const REGISTRATION_VERIFY_EMAIL: &str = "verify-email";
+pub const REGISTRATION_INVITE_ONLY: &str = "invite-only";
+
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct Settings {
     pub id: i32,
@@ -37,6 +41,33 @@ pub struct Settings {
     pub allow_registration: bool,
     #[serde(rename = "expirationAction")]
     pub expiration_action: String,
+    /// Storage each user may use unless they have their own quota; 0 means
+    /// unlimited.
+    #[serde(rename = "defaultStorageQuota")]
+    pub default_storage_quota: i64,
+    /// Admins without 2FA have no admin access while this is set.
+    #[serde(rename = "requireAdminTwoFactor")]
+    pub require_admin_two_factor: bool,
+    /// "open", "verify-email" or "invite-only"; only applies while
+    /// registration is allowed at all.
+    #[serde(rename = "registrationMode")]
+    pub registration_mode: String,
+    /// Requests per minute per IP to logins, registration and the password
+    /// emails; 0 turns the limit off, as for the other limits.
+    #[serde(rename = "authRateLimit")]
+    pub auth_rate_limit: i32,
+    /// Requests per minute per IP to share downloads and reverse uploads.
+    #[serde(rename = "publicRateLimit")]
+    pub public_rate_limit: i32,
+    /// Login attempts per minute per account.
+    #[serde(rename = "accountRateLimit")]
+    pub account_rate_limit: i32,
+    /// Failed logins in a row after which an account is locked.
+    #[serde(rename = "lockoutThreshold")]
+    pub lockout_threshold: i32,
+    /// Length of the first lockout; each further failure doubles it.
+    #[serde(rename = "lockoutMinutes")]
+    pub lockout_minutes: i32,
 }
 
 impl Default for Settings {
@@ -52,6 +83,53 @@ impl Default for Settings {
             max_validity: "7days".to_string(),
             allow_registration: true,
             expiration_action: "unavailable".to_string(),
+            default_storage_quota: 0,
+            require_admin_two_factor: false,
+            registration_mode: REGISTRATION_OPEN.to_string(),
+            auth_rate_limit: 10,
+            public_rate_limit: 120,
+            account_rate_limit: 20,
+            lockout_threshold: 5,
+            lockout_minutes: 1,
+        }
+    }
+}
+
+/// The settings anyone may read, for the login, registration and upload
+/// pages; the rest are only shown to admins.
+#[derive(Debug, Serialize, FromRow)]
+pub struct PublicSettings {
+    pub theme: String,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "logo")]
+    pub logo_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "backgroundImage")]
+    pub background_path: Option<String>,
+    #[serde(rename = "navbarTitle")]
+    pub navbar_title: String,
+    #[serde(rename = "maxUploadSize")]
+    pub max_upload_size: i64,
+    #[serde(rename = "blurIntensity")]
+    pub blur_intensity: i32,
+    #[serde(rename = "maxValidity")]
+    pub max_validity: String,
+    #[serde(rename = "allowRegistration")]
+    pub allow_registration: bool,
+    #[serde(rename = "registrationMode")]
+    pub registration_mode: String,
+}
+
+impl From<Settings> for PublicSettings {
+    fn from(settings: Settings) -> Self {
+        Self {
+            theme: settings.theme,
+            logo_path: settings.logo_path,
+            background_path: settings.background_path,
+            navbar_title: settings.navbar_title,
+            max_upload_size: settings.max_upload_size,
+            blur_intensity: settings.blur_intensity,
+            max_validity: settings.max_validity,
+            allow_registration: settings.allow_registration,
+            registration_mode: settings.registration_mode,
         }
     }
 }
@@ -73,6 +151,109 @@ pub struct Upload {
     pub is_deleted: bool,
     pub deleted_at: Option<DateTime<Utc>>,
     pub deletion_reason: Option<String>,
+    pub removed_by_admin: bool,
+    pub is_password_protected: bool,
+    pub max_downloads: Option<i32>,
+    pub download_count: i32,
+    pub last_downloaded_at: Option<DateTime<Utc>>,
+}
+
+/// An upload as moderators see it, with its owner.
+#[derive(Debug, Serialize, FromRow)]
+pub struct AdminUpload {
+    pub upload_id: String,
+    pub user_id: i32,
+    pub username: String,
+    pub files: String,
+    pub total_size: i64,
+    pub download_url: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub is_available: bool,
+    pub is_reverse: bool,
+    pub is_deleted: bool,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub deletion_reason: Option<String>,
+    pub removed_by_admin: bool,
+    pub is_password_protected: bool,
+    pub download_count: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct UploadFile {
+    pub id: i32,
+    pub upload_id: String,
+    pub position: i32,
+    pub original_name: String,
+    pub stored_name: String,
+    pub size: i64,
+    pub mime_type: String,
+    pub sha256: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A download of a share, or of one of its files when `file_id` is set.
+#[derive(Debug, Serialize, FromRow)]
+pub struct DownloadEvent {
+    pub id: i64,
+    pub file_id: Option<i32>,
+    pub file_name: Option<String>,
+    pub ip_address: String,
+    pub user_agent: Option<String>,
+    pub bytes_served: i64,
+    pub downloaded_at: DateTime<Utc>,
+}
+
+/// A personal access token as shown to its owner; the token itself is only
+/// returned when it is created.
+#[derive(Debug, Serialize, FromRow)]
+pub struct ApiTokenInfo {
+    pub id: i32,
+    pub name: String,
+    pub token_prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A live login session as shown to its user. `current` marks the session
+/// making the request.
+#[derive(Debug, Serialize, FromRow)]
+pub struct SessionInfo {
+    pub id: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub current: bool,
+}
+
+/// A webhook registration. `user_id` is `None` for admin-level webhooks.
+#[derive(Debug, Serialize, FromRow)]
+pub struct Webhook {
+    pub id: i32,
+    pub user_id: Option<i32>,
+    pub url: String,
+    pub events: Vec<String>,
+    pub is_active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub event: String,
+    pub payload: String,
+    pub status: String,
+    pub attempts: i32,
+    pub last_status_code: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_attempt_at: Option<DateTime<Utc>>,
+    pub next_attempt_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
@@ -87,6 +268,35 @@ pub struct ReverseShareToken {
     pub expires_at: Option<DateTime<Utc>>,
 }
 
+/// A registration invite; `max_uses` of `None` means unlimited and empty
+/// `email_domains` means any address.
+#[derive(Debug, Serialize, FromRow)]
+pub struct Invite {
+    pub id: i32,
+    pub code: String,
+    pub created_by: Option<i32>,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub email_domains: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct TusUpload {
+    pub id: String,
+    pub user_id: i32,
+    pub reverse_token: Option<String>,
+    pub filename: String,
+    pub upload_length: i64,
+    pub upload_offset: i64,
+    pub email: Option<String>,
+    pub validity: String,
+    pub upload_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct DeletionLog {
     pub id: i32,
@@ -111,6 +321,8 @@ pub struct RegisterRequest {
     pub username: String,
     pub email: String,
     pub password: String,
+    /// Required while registration is invite-only.
+    pub invite_code: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -123,14 +335,79 @@ pub struct LoginRequest {
 pub struct AuthResponse {
     pub message: String,
     pub token: String,
+    pub refresh_token: String,
+    /// Seconds until `token` expires.
+    pub expires_in: i64,
     pub user: UserInfo,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct TwoFactorLoginRequest {
+    pub challenge_token: String,
+    /// A TOTP code or a recovery code.
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TwoFactorCodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DisableTwoFactorRequest {
+    pub password: String,
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResendVerificationRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+/// What the identity provider appends to the redirect back to us.
+#[derive(Debug, Deserialize)]
+pub struct OidcCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+/// Browsers send the refresh token as a cookie instead.
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: Option<String>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct UserInfo {
     pub id: i32,
     pub username: String,
     pub email: String,
+    pub role: String,
+    /// Owners and admins, for clients that predate roles.
     pub is_admin: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub avatar: Option<String>,
@@ -154,6 +431,17 @@ pub struct ExpirationRequest {
     pub validity: String,
 }
 
+/// A missing or empty password removes the protection.
+#[derive(Debug, Deserialize)]
+pub struct SharePasswordRequest {
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnlockRequest {
+    pub password: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CreateTokenRequest {
     pub name: String,
@@ -168,9 +456,144 @@ pub struct BlockUserRequest {
     pub blocked: bool,
 }
 
+/// Filters for the deletion logs. `username` and `reason` match any part,
+/// ignoring case. `from` and `to` take a timestamp or a date; `to` is
+/// exclusive, but a date includes that whole day.
+#[derive(Debug, Deserialize)]
+pub struct DeletionLogQuery {
+    pub user_id: Option<i32>,
+    pub username: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub reverse_only: bool,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+    /// `json` (the default) or `csv`, which returns every match at once.
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub actor_id: Option<i32>,
+    pub actor_name: Option<String>,
+    pub action: String,
+    pub target: Option<String>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Filters for the audit log. `action` matches an action or a whole group
+/// such as `user`; `target` is exact; `from` and `to` work like in
+/// `DeletionLogQuery`.
+#[derive(Debug, Deserialize)]
+pub struct AuditEventQuery {
+    pub actor_id: Option<i32>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+/// Search over every user's uploads. `filename` and `owner` match any part
+/// of a file or user name, ignoring case; sizes take values such as
+/// `"10MB"`; `from` and `to` bound the upload date like `DeletionLogQuery`.
+#[derive(Debug, Deserialize)]
+pub struct AdminUploadQuery {
+    pub filename: Option<String>,
+    pub owner: Option<String>,
+    pub min_size: Option<String>,
+    pub max_size: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    #[serde(default)]
+    pub include_deleted: bool,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+/// Why a share is taken down; kept in the deletion log and shown to the
+/// owner.
+#[derive(Debug, Deserialize)]
+pub struct ModerationRequest {
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetRoleRequest {
+    pub role: String,
+}
+
+/// The owner confirms handing over the instance with their password.
+#[derive(Debug, Deserialize)]
+pub struct TransferOwnershipRequest {
+    pub user_id: i32,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BootstrapRequest {
+    pub token: String,
+}
+
+/// `storage_quota` takes a size such as `"50GB"`; `"0"` means unlimited and
+/// `null` falls back to the default from settings.
+#[derive(Debug, Deserialize)]
+pub struct StorageQuotaRequest {
+    pub storage_quota: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiTokenRequest {
+    pub name: String,
+    pub scopes: Vec<String>,
+    /// A validity such as `30d` or `1year`; never expires when absent.
+    #[serde(default)]
+    pub expires_in: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateInviteRequest {
+    /// Defaults to a single use; 0 means unlimited.
+    pub max_uses: Option<i32>,
+    #[serde(default)]
+    pub email_domains: Vec<String>,
+    /// A validity such as "7d"; defaults to never expiring.
+    pub expires_in: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    /// Defaults to every event.
+    pub events: Option<Vec<String>>,
+    /// Admin-level webhook receiving events for all uploads; admins only.
+    #[serde(default)]
+    pub global: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateWebhookRequest {
+    pub url: Option<String>,
+    pub events: Option<Vec<String>>,
+    pub is_active: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MailTemplateRequest {
+    pub subject: String,
+    pub body: String,
+}
+
 #[derive(Debug, Deserialize)]
-pub struct PromoteUserRequest {
-    pub promote: bool,
+pub struct TestMailRequest {
+    pub to: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -184,6 +607,8 @@ pub struct AdminStats {
     pub total_users: i64,
     pub total_uploads: i64,
     pub storage_used: i64,
+    pub logical_storage_used: i64,
+    pub physical_storage_used: i64,
 }
 
 #[derive(Debug, Serialize, FromRow)]
@@ -192,10 +617,14 @@ pub struct AdminUser {
     pub username: String,
     pub email: String,
     pub avatar: Option<String>,
+    pub role: String,
     pub is_admin: bool,
     pub is_blocked: bool,
     pub upload_count: i64,
     pub storage_used: i64,
+    pub storage_quota: Option<i64>,
+    /// Set while the account is locked after failed logins.
+    pub locked_until: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub last_activity: Option<DateTime<Utc>>,
 }
@@ -204,6 +633,7 @@ pub struct AdminUser {
 pub struct FileInfo {
     pub name: String,
     pub size: u64,
+    pub mime_type: String,
     pub url: String,
 }
 
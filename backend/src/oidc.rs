@@ -0,0 +1,419 @@
+use openidconnect::core::{
+    CoreAuthenticationFlow, CoreClient, CoreGenderClaim, CoreJweContentEncryptionAlgorithm,
+    CoreJwsSigningAlgorithm, CoreProviderMetadata,
+};
+use openidconnect::{
+    AdditionalClaims, AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndpointMaybeSet, EndpointNotSet,
+    EndpointSet, IdToken, IssuerUrl, Nonce, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope,
+    TokenResponse,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::config::OidcConfig;
+
+/// Every claim of the ID token, so that the configured groups claim can be
+/// read whatever it is called.
+#[derive(Debug, Deserialize, Serialize)]
+struct OtherClaims(HashMap<String, serde_json::Value>);
+
+impl AdditionalClaims for OtherClaims {}
+
+type ClaimsIdToken = IdToken<OtherClaims, CoreGenderClaim, CoreJweContentEncryptionAlgorithm, CoreJwsSigningAlgorithm>;
+
+/// A client built from discovery: the authorization endpoint is always known,
+/// the token and user info endpoints may be missing.
+type DiscoveredClient =
+    CoreClient<EndpointSet, EndpointNotSet, EndpointNotSet, EndpointNotSet, EndpointMaybeSet, EndpointMaybeSet>;
+
+/// A login in progress, kept until the provider redirects back.
+pub struct PendingLogin {
+    pub authorize_url: String,
+    pub state: String,
+    pub pkce_verifier: String,
+    pub nonce: String,
+}
+
+/// The user as the provider vouches for them.
+pub struct Identity {
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub username: Option<String>,
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    Discovery(String),
+    Exchange(String),
+    MissingIdToken,
+    InvalidIdToken(String),
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OidcError::Discovery(e) => write!(f, "Failed to reach the identity provider: {}", e),
+            OidcError::Exchange(e) => write!(f, "Failed to exchange the authorization code: {}", e),
+            OidcError::MissingIdToken => write!(f, "The identity provider returned no ID token"),
+            OidcError::InvalidIdToken(e) => write!(f, "Invalid ID token: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OidcError {}
+
+fn http_client() -> Result<reqwest::Client, OidcError> {
+    reqwest::Client::builder()
+        // Following redirects would let a provider send requests anywhere
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .map_err(|e| OidcError::Discovery(e.to_string()))
+}
+
+/// Discovers the provider and builds a client for it. Done for every login,
+/// so that rotated signing keys are picked up.
+async fn client(config: &OidcConfig, http_client: &reqwest::Client) -> Result<DiscoveredClient, OidcError> {
+    let issuer_url = IssuerUrl::new(config.issuer_url.clone()).map_err(|e| OidcError::Discovery(e.to_string()))?;
+    let redirect_url = RedirectUrl::new(config.redirect_url.clone()).map_err(|e| OidcError::Discovery(e.to_string()))?;
+
+    let metadata = CoreProviderMetadata::discover_async(issuer_url, http_client)
+        .await
+        .map_err(|e| OidcError::Discovery(e.to_string()))?;
+
+    Ok(CoreClient::from_provider_metadata(
+        metadata,
+        ClientId::new(config.client_id.clone()),
+        config.client_secret.clone().map(ClientSecret::new),
+    )
+    .set_redirect_uri(redirect_url))
+}
+
+/// Starts an authorization code flow with PKCE.
+pub async fn begin_login(config: &OidcConfig) -> Result<PendingLogin, OidcError> {
+    let http_client = http_client()?;
+    let client = client(config, &http_client).await?;
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+
+    let mut request = client
+        .authorize_url(CoreAuthenticationFlow::AuthorizationCode, CsrfToken::new_random, Nonce::new_random)
+        .set_pkce_challenge(pkce_challenge);
+    for scope in config.scopes.iter().filter(|s| *s != "openid") {
+        request = request.add_scope(Scope::new(scope.clone()));
+    }
+    let (authorize_url, state, nonce) = request.url();
+
+    Ok(PendingLogin {
+        authorize_url: authorize_url.to_string(),
+        state: state.secret().clone(),
+        pkce_verifier: pkce_verifier.secret().clone(),
+        nonce: nonce.secret().clone(),
+    })
+}
+
+/// Trades the code the provider sent back for a verified identity.
+pub async fn finish_login(
+    config: &OidcConfig,
+    code: &str,
+    pkce_verifier: &str,
+    nonce: &str,
+) -> Result<Identity, OidcError> {
+    let http_client = http_client()?;
+    let client = client(config, &http_client).await?;
+
+    let token_response = client
+        .exchange_code(AuthorizationCode::new(code.to_string()))
+        .map_err(|e| OidcError::Exchange(e.to_string()))?
+        .set_pkce_verifier(PkceCodeVerifier::new(pkce_verifier.to_string()))
+        .request_async(&http_client)
+        .await
+        .map_err(|e| OidcError::Exchange(e.to_string()))?;
+
+    let id_token = token_response.id_token().ok_or(OidcError::MissingIdToken)?;
+
+    // Parsed again with every claim kept, then verified as usual
+    let id_token = ClaimsIdToken::from_str(&id_token.to_string())
+        .map_err(|e| OidcError::InvalidIdToken(e.to_string()))?;
+    let claims = id_token
+        .claims(&client.id_token_verifier(), &Nonce::new(nonce.to_string()))
+        .map_err(|e| OidcError::InvalidIdToken(e.to_string()))?;
+
+    let groups = match claims.additional_claims().0.get(&config.groups_claim) {
+        Some(serde_json::Value::Array(groups)) => groups
+            .iter()
+            .filter_map(|g| g.as_str().map(str::to_string))
+            .collect(),
+        Some(serde_json::Value::String(group)) => vec![group.clone()],
+        _ => Vec::new(),
+    };
+
+    Ok(Identity {
+        subject: claims.subject().to_string(),
+        email: claims.email().map(|e| e.to_string()),
+        email_verified: claims.email_verified().unwrap_or(false),
+        username: claims.preferred_username().map(|u| u.to_string()),
+        groups,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use sha2::{Digest, Sha256};
+    use std::sync::{Arc, Mutex};
+
+    const CLIENT_ID: &str = "rootdrop";
+    const KEY_PEM: &str = include_str!("../tests/fixtures/oidc_test_key.pem");
+    const KEY_N: &str = "mjCkYfZkuUCWI2chSeFVBxGLsbJwWqjC1E3NPp65Y0w-i70Q2hogF4WStb5wPbrj_V3H8G9LW8X15INvfRCpi19tdl7jd_BJinL7F_Tb7SddvWrEpq3xe29RouaT_iKCyXwU22OB1cZWm59uM9_fQsLb2XARsw7rxzmi-7gXhrJgECvWiJZX3W1i5Jh50pZuO74C2elTxbM5UgrrBrFM_NMuJ5q8bYiPYXXdRI66gzYMuKTYzV8-GwaPY7VCZr818auP_fzmItlvFO6EtMwdh725NvBLxc5xrDB-CigtOHApbpfNx3Gl1lLyuh1Leqg55UYyQXxSzzBeoYj3ndmnpw";
+
+    /// Codes the mock provider has handed out, with the PKCE challenge they
+    /// were issued for and the claims of their ID token.
+    type Codes = Arc<Mutex<HashMap<String, (String, serde_json::Value)>>>;
+
+    /// A minimal identity provider: discovery, signing keys and the token
+    /// endpoint. The authorization step is done by `authorize`.
+    struct MockIdp {
+        issuer: String,
+        codes: Codes,
+    }
+
+    impl MockIdp {
+        async fn start() -> MockIdp {
+            let codes: Codes = Arc::default();
+            let server_codes = codes.clone();
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(web::Data::new(server_codes.clone()))
+                    .route("/.well-known/openid-configuration", web::get().to(discovery))
+                    .route("/jwks", web::get().to(jwks))
+                    .route("/token", web::post().to(token))
+            })
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+            let issuer = format!("http://{}", server.addrs()[0]);
+            actix_web::rt::spawn(server.run());
+
+            MockIdp { issuer, codes }
+        }
+
+        fn config(&self) -> OidcConfig {
+            OidcConfig {
+                issuer_url: self.issuer.clone(),
+                client_id: CLIENT_ID.to_string(),
+                client_secret: Some("secret".to_string()),
+                redirect_url: "http://localhost:8080/api/oidc/callback".to_string(),
+                scopes: vec!["openid".to_string(), "email".to_string(), "profile".to_string()],
+                display_name: "Test".to_string(),
+                groups_claim: "groups".to_string(),
+                admin_group: None,
+                disable_local_registration: false,
+            }
+        }
+
+        /// What the provider does when the user signs in: returns a code for
+        /// an ID token with `claims`, plus the standard ones for this login.
+        fn authorize(&self, pending: &PendingLogin, claims: serde_json::Value) -> String {
+            let url = reqwest::Url::parse(&pending.authorize_url).unwrap();
+            let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+            assert_eq!(params["state"], pending.state);
+            assert_eq!(params["code_challenge_method"], "S256");
+
+            let now = chrono::Utc::now().timestamp();
+            let mut claims = claims;
+            let standard = serde_json::json!({
+                "iss": self.issuer,
+                "aud": CLIENT_ID,
+                "iat": now,
+                "exp": now + 300,
+                "nonce": params["nonce"],
+            });
+            for (name, value) in standard.as_object().unwrap() {
+                claims.as_object_mut().unwrap().entry(name.clone()).or_insert(value.clone());
+            }
+
+            let code = uuid::Uuid::new_v4().simple().to_string();
+            self.codes
+                .lock()
+                .unwrap()
+                .insert(code.clone(), (params["code_challenge"].clone(), claims));
+            code
+        }
+    }
+
+    async fn discovery(req: actix_web::HttpRequest) -> HttpResponse {
+        let issuer = format!("http://{}", req.connection_info().host());
+        HttpResponse::Ok().json(serde_json::json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+            "response_types_supported": ["code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"]
+        }))
+    }
+
+    async fn jwks() -> HttpResponse {
+        HttpResponse::Ok().json(serde_json::json!({
+            "keys": [{ "kty": "RSA", "kid": "test", "use": "sig", "alg": "RS256", "n": KEY_N, "e": "AQAB" }]
+        }))
+    }
+
+    async fn token(codes: web::Data<Codes>, form: web::Form<HashMap<String, String>>) -> HttpResponse {
+        let invalid_grant = || HttpResponse::BadRequest().json(serde_json::json!({ "error": "invalid_grant" }));
+
+        let Some((challenge, claims)) = form.get("code").and_then(|code| codes.lock().unwrap().remove(code)) else {
+            return invalid_grant();
+        };
+        let verifier = form.get("code_verifier").map(String::as_str).unwrap_or_default();
+        if URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) != challenge {
+            return invalid_grant();
+        }
+
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("test".to_string());
+        let id_token = jsonwebtoken::encode(&header, &claims, &EncodingKey::from_rsa_pem(KEY_PEM.as_bytes()).unwrap())
+            .unwrap();
+
+        HttpResponse::Ok().json(serde_json::json!({
+            "access_token": "access",
+            "token_type": "Bearer",
+            "expires_in": 300,
+            "id_token": id_token
+        }))
+    }
+
+    fn user_claims() -> serde_json::Value {
+        serde_json::json!({
+            "sub": "user-1",
+            "email": "ada@example.com",
+            "email_verified": true,
+            "preferred_username": "ada",
+            "groups": ["staff", "rootdrop-admins"]
+        })
+    }
+
+    #[actix_web::test]
+    async fn login_yields_the_verified_identity() {
+        let idp = MockIdp::start().await;
+        let config = idp.config();
+
+        let pending = begin_login(&config).await.unwrap();
+        let code = idp.authorize(&pending, user_claims());
+        let identity = finish_login(&config, &code, &pending.pkce_verifier, &pending.nonce)
+            .await
+            .unwrap();
+
+        assert_eq!(identity.subject, "user-1");
+        assert_eq!(identity.email.as_deref(), Some("ada@example.com"));
+        assert!(identity.email_verified);
+        assert_eq!(identity.username.as_deref(), Some("ada"));
+        assert_eq!(identity.groups, vec!["staff", "rootdrop-admins"]);
+    }
+
+    #[actix_web::test]
+    async fn groups_claim_can_be_renamed_and_a_single_string() {
+        let idp = MockIdp::start().await;
+        let config = OidcConfig {
+            groups_claim: "roles".to_string(),
+            ..idp.config()
+        };
+
+        let pending = begin_login(&config).await.unwrap();
+        let code = idp.authorize(&pending, serde_json::json!({ "sub": "user-2", "roles": "rootdrop-admins" }));
+        let identity = finish_login(&config, &code, &pending.pkce_verifier, &pending.nonce)
+            .await
+            .unwrap();
+
+        assert_eq!(identity.groups, vec!["rootdrop-admins"]);
+        assert_eq!(identity.email, None);
+        assert!(!identity.email_verified);
+    }
+
+    #[actix_web::test]
+    async fn wrong_pkce_verifier_is_refused() {
+        let idp = MockIdp::start().await;
+        let config = idp.config();
+
+        let pending = begin_login(&config).await.unwrap();
+        let other = begin_login(&config).await.unwrap();
+        let code = idp.authorize(&pending, user_claims());
+
+        let result = finish_login(&config, &code, &other.pkce_verifier, &pending.nonce).await;
+        assert!(matches!(result, Err(OidcError::Exchange(_))));
+    }
+
+    #[actix_web::test]
+    async fn code_is_single_use() {
+        let idp = MockIdp::start().await;
+        let config = idp.config();
+
+        let pending = begin_login(&config).await.unwrap();
+        let code = idp.authorize(&pending, user_claims());
+        finish_login(&config, &code, &pending.pkce_verifier, &pending.nonce)
+            .await
+            .unwrap();
+
+        let replay = finish_login(&config, &code, &pending.pkce_verifier, &pending.nonce).await;
+        assert!(matches!(replay, Err(OidcError::Exchange(_))));
+    }
+
+    #[actix_web::test]
+    async fn nonce_of_another_login_is_refused() {
+        let idp = MockIdp::start().await;
+        let config = idp.config();
+
+        let pending = begin_login(&config).await.unwrap();
+        let other = begin_login(&config).await.unwrap();
+        let code = idp.authorize(&pending, user_claims());
+
+        let result = finish_login(&config, &code, &pending.pkce_verifier, &other.nonce).await;
+        assert!(matches!(result, Err(OidcError::InvalidIdToken(_))));
+    }
+
+    #[actix_web::test]
+    async fn token_for_another_client_is_refused() {
+        let idp = MockIdp::start().await;
+        let config = idp.config();
+
+        let pending = begin_login(&config).await.unwrap();
+        let mut claims = user_claims();
+        claims["aud"] = serde_json::json!("someone-else");
+        let code = idp.authorize(&pending, claims);
+
+        let result = finish_login(&config, &code, &pending.pkce_verifier, &pending.nonce).await;
+        assert!(matches!(result, Err(OidcError::InvalidIdToken(_))));
+    }
+
+    #[actix_web::test]
+    async fn token_from_another_issuer_is_refused() {
+        let idp = MockIdp::start().await;
+        let config = idp.config();
+
+        let pending = begin_login(&config).await.unwrap();
+        let mut claims = user_claims();
+        claims["iss"] = serde_json::json!("http://evil.example");
+        let code = idp.authorize(&pending, claims);
+
+        let result = finish_login(&config, &code, &pending.pkce_verifier, &pending.nonce).await;
+        assert!(matches!(result, Err(OidcError::InvalidIdToken(_))));
+    }
+
+    #[actix_web::test]
+    async fn unreachable_provider_fails_discovery() {
+        let config = OidcConfig {
+            issuer_url: "http://127.0.0.1:9".to_string(),
+            ..MockIdp::start().await.config()
+        };
+
+        assert!(matches!(begin_login(&config).await, Err(OidcError::Discovery(_))));
+    }
+}
@@ -0,0 +1,77 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::api_tokens::hash_token;
+
+/// How long an emailed reset link works.
+pub const RESET_TOKEN_HOURS: i64 = 1;
+
+/// Creates a reset token for a user and returns it with its expiry. Links
+/// sent earlier stop working, so only the latest email counts.
+pub async fn issue(pool: &PgPool, user_id: i32) -> Result<(String, DateTime<Utc>), sqlx::Error> {
+    let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let expires_at = Utc::now() + Duration::hours(RESET_TOKEN_HOURS);
+
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("DELETE FROM password_resets WHERE user_id = $1 AND used_at IS NULL")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("INSERT INTO password_resets (token_hash, user_id, expires_at) VALUES ($1, $2, $3)")
+        .bind(hash_token(&token))
+        .bind(user_id)
+        .bind(expires_at)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok((token, expires_at))
+}
+
+/// Sets a new password with a reset token, which is used up in the process.
+/// Returns the user whose password changed, or `None` if the token is
+/// unknown, used or expired.
+pub async fn redeem(pool: &PgPool, token: &str, password_hash: &str) -> Result<Option<i32>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let found: Option<(i32,)> = sqlx::query_as(
+        r#"
+        UPDATE password_resets SET used_at = NOW()
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+        RETURNING user_id
+        "#,
+    )
+    .bind(hash_token(token))
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some((user_id,)) = found else {
+        return Ok(None);
+    };
+
+    // A new password also lifts any lockout from failed logins
+    sqlx::query("UPDATE users SET password_hash = $1, failed_logins = 0, locked_until = NULL WHERE id = $2")
+        .bind(password_hash)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(Some(user_id))
+}
+
+/// Drops reset tokens that were used or expired more than a day ago.
+pub async fn prune(pool: &PgPool) {
+    let pruned = sqlx::query(
+        "DELETE FROM password_resets WHERE COALESCE(used_at, expires_at) < NOW() - INTERVAL '1 day'"
+    )
+    .execute(pool)
+    .await;
+
+    if let Err(e) = pruned {
+        log::error!("Failed to prune password resets: {}", e);
+    }
+}
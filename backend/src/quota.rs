@@ -0,0 +1,77 @@
+use serde::Serialize;
+use sqlx::{PgConnection, PgExecutor};
+
+/// How much storage a user's live uploads take up and how much they may use.
+/// A quota of `None` means unlimited.
+#[derive(Debug, Serialize)]
+pub struct StorageUsage {
+    pub storage_used: i64,
+    pub storage_quota: Option<i64>,
+    /// The user's own override; `None` when the global default applies.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota_override: Option<i64>,
+}
+
+impl StorageUsage {
+    pub fn remaining(&self) -> Option<i64> {
+        self.storage_quota.map(|quota| (quota - self.storage_used).max(0))
+    }
+
+    pub fn allows(&self, size: i64) -> bool {
+        self.remaining().map(|remaining| size <= remaining).unwrap_or(true)
+    }
+
+    pub fn exceeded_message(&self, size: i64) -> String {
+        format!(
+            "Storage quota exceeded: this upload needs {} bytes but only {} of {} bytes are left",
+            size,
+            self.remaining().unwrap_or(0),
+            self.storage_quota.unwrap_or(0),
+        )
+    }
+}
+
+/// Loads a user's usage and effective quota: the per-user override if set,
+/// the default from settings otherwise. Zero in either place means no limit.
+pub async fn storage_usage<'e>(executor: impl PgExecutor<'e>, user_id: i32) -> Result<StorageUsage, sqlx::Error> {
+    let (storage_used, quota_override, default_quota): (i64, Option<i64>, Option<i64>) = sqlx::query_as(
+        r#"
+        SELECT
+            (SELECT COALESCE(SUM(total_size), 0) FROM uploads WHERE user_id = $1 AND is_deleted = FALSE)::BIGINT,
+            (SELECT storage_quota FROM users WHERE id = $1),
+            (SELECT default_storage_quota FROM settings ORDER BY id LIMIT 1)
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(executor)
+    .await?;
+
+    let storage_quota = quota_override.or(default_quota).filter(|quota| *quota > 0);
+
+    Ok(StorageUsage {
+        storage_used,
+        storage_quota,
+        quota_override,
+    })
+}
+
+/// Checks that `size` more bytes fit in the user's quota as part of the
+/// transaction on `conn`. The user's row stays locked until the transaction
+/// ends, so concurrent uploads by the same user are checked one at a time.
+pub async fn reserve(
+    conn: &mut PgConnection,
+    user_id: i32,
+    size: i64,
+) -> Result<Result<(), StorageUsage>, sqlx::Error> {
+    sqlx::query("SELECT id FROM users WHERE id = $1 FOR UPDATE")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+
+    let usage = storage_usage(&mut *conn, user_id).await?;
+    if usage.allows(size) {
+        Ok(Ok(()))
+    } else {
+        Ok(Err(usage))
+    }
+}
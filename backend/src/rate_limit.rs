@@ -0,0 +1,182 @@
+use actix_web::http::{header, Method};
+use actix_web::HttpResponse;
+use governor::clock::{Clock, DefaultClock};
+use governor::{DefaultKeyedRateLimiter, Quota};
+use sqlx::PgPool;
+use std::num::NonZeroU32;
+use std::sync::RwLock;
+
+use crate::models::Settings;
+
+/// Groups of routes that share a limit.
+#[derive(Debug, Clone, Copy)]
+pub enum Bucket {
+    /// Logins, registration, password changes and the password and
+    /// verification emails, per client IP.
+    Auth,
+    /// Share downloads and reverse uploads, per client IP.
+    Public,
+    /// Login attempts per account, whichever IP they come from.
+    Account,
+}
+
+/// The bucket a route is limited by per client IP, by method and matched
+/// route pattern.
+pub fn bucket_for(method: &Method, pattern: &str) -> Option<Bucket> {
+    match (method.as_str(), pattern) {
+        ("POST", "/api/login")
+        | ("POST", "/api/login/2fa")
+        | ("POST", "/api/register")
+        | ("PUT", "/api/password")
+        | ("POST", "/api/password/forgot")
+        | ("POST", "/api/password/reset")
+        | ("POST", "/api/verify-email/resend") => Some(Bucket::Auth),
+        ("GET", "/api/download/{id}")
+        | ("POST", "/api/download/{id}/unlock")
+        | ("GET", "/api/file/{id}/{filename}")
+        | ("POST", "/api/reverse-upload/{token}")
+        | ("POST", "/api/reverse-upload/{token}/tus") => Some(Bucket::Public),
+        _ => None,
+    }
+}
+
+/// A limiter and the requests per minute it was built for.
+type Limiter = Option<(u32, DefaultKeyedRateLimiter<String>)>;
+
+#[derive(Default)]
+struct Limiters {
+    auth: Limiter,
+    public: Limiter,
+    account: Limiter,
+    lockout_threshold: i32,
+    lockout_minutes: i32,
+}
+
+/// In-memory rate limits, configured from `Settings` at startup and again
+/// whenever an admin saves them.
+#[derive(Default)]
+pub struct RateLimits {
+    limiters: RwLock<Limiters>,
+}
+
+/// Rebuilds a limiter if its limit changed, which also forgets what it
+/// counted so far. A limit of 0 turns it off.
+fn reconfigure(limiter: &mut Limiter, per_minute: i32) {
+    let per_minute = u32::try_from(per_minute).unwrap_or(0);
+    if limiter.as_ref().map(|(current, _)| *current) == Some(per_minute) {
+        return;
+    }
+
+    *limiter = NonZeroU32::new(per_minute)
+        .map(|n| (per_minute, DefaultKeyedRateLimiter::keyed(Quota::per_minute(n))));
+}
+
+impl RateLimits {
+    pub fn new(settings: &Settings) -> Self {
+        let limits = Self::default();
+        limits.configure(settings);
+        limits
+    }
+
+    pub fn configure(&self, settings: &Settings) {
+        let mut limiters = self.limiters.write().unwrap_or_else(|e| e.into_inner());
+        reconfigure(&mut limiters.auth, settings.auth_rate_limit);
+        reconfigure(&mut limiters.public, settings.public_rate_limit);
+        reconfigure(&mut limiters.account, settings.account_rate_limit);
+        limiters.lockout_threshold = settings.lockout_threshold;
+        limiters.lockout_minutes = settings.lockout_minutes;
+    }
+
+    /// Counts a request against a bucket. Returns the seconds to wait if it
+    /// is over the limit.
+    pub fn check(&self, bucket: Bucket, key: &str) -> Result<(), u64> {
+        let limiters = self.limiters.read().unwrap_or_else(|e| e.into_inner());
+        let limiter = match bucket {
+            Bucket::Auth => &limiters.auth,
+            Bucket::Public => &limiters.public,
+            Bucket::Account => &limiters.account,
+        };
+
+        let Some((_, limiter)) = limiter else {
+            return Ok(());
+        };
+
+        limiter
+            .check_key(&key.to_string())
+            .map_err(|not_until| not_until.wait_time_from(DefaultClock::default().now()).as_secs() + 1)
+    }
+
+    /// Forgets keys that are back to a full allowance, so memory does not
+    /// grow with every client ever seen.
+    pub fn retain_recent(&self) {
+        let limiters = self.limiters.read().unwrap_or_else(|e| e.into_inner());
+        for (_, limiter) in [&limiters.auth, &limiters.public, &limiters.account].into_iter().flatten() {
+            limiter.retain_recent();
+        }
+    }
+
+    /// The failed logins after which an account is locked, and for how many
+    /// minutes the first lockout lasts; 0 failures means never.
+    fn lockout(&self) -> (i32, i32) {
+        let limiters = self.limiters.read().unwrap_or_else(|e| e.into_inner());
+        (limiters.lockout_threshold, limiters.lockout_minutes)
+    }
+}
+
+pub fn too_many_requests(retry_after: u64, message: &str) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+        .json(serde_json::json!({
+            "error": message,
+            "retry_after": retry_after
+        }))
+}
+
+/// Seconds until a locked account can try again, if it is locked.
+pub async fn locked_for(pool: &PgPool, user_id: i32) -> Result<Option<u64>, sqlx::Error> {
+    let locked: Option<(f64,)> = sqlx::query_as(
+        "SELECT EXTRACT(EPOCH FROM locked_until - NOW())::FLOAT8 FROM users WHERE id = $1 AND locked_until > NOW()"
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(locked.map(|(seconds,)| seconds.ceil() as u64))
+}
+
+/// Counts a failed login. From the threshold on, each failure locks the
+/// account, twice as long as the one before, up to a day.
+pub async fn record_failed_login(pool: &PgPool, limits: &RateLimits, user_id: i32) -> Result<(), sqlx::Error> {
+    let (threshold, minutes) = limits.lockout();
+
+    sqlx::query(
+        r#"
+        UPDATE users SET
+            failed_logins = failed_logins + 1,
+            locked_until = CASE
+                WHEN $2 > 0 AND failed_logins + 1 >= $2 THEN NOW() + make_interval(
+                    secs => LEAST($3 * 60 * POWER(2, LEAST(failed_logins + 1 - $2, 20)), 86400)
+                )
+                ELSE locked_until
+            END
+        WHERE id = $1
+        "#,
+    )
+    .bind(user_id)
+    .bind(threshold)
+    .bind(minutes.max(1))
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Resets the failure count after a successful login or a password reset.
+pub async fn clear_failed_logins(pool: &PgPool, user_id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE users SET failed_logins = 0, locked_until = NULL WHERE id = $1 AND failed_logins > 0")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
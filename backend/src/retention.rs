@@ -0,0 +1,125 @@
+use sqlx::{PgConnection, PgPool};
+
+use crate::blobs;
+use crate::files::load_upload_files;
+use crate::models::Settings;
+use crate::storage::Storage;
+use crate::webhooks;
+
+/// Records an upload in `deletion_logs`, copying its details as they are now.
+pub async fn log_deletion(conn: &mut PgConnection, upload_id: &str, reason: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO deletion_logs (user_id, username, upload_id, files, total_size, email, download_url, uploaded_at, expires_at, is_reverse, reverse_token, deletion_reason)
+        SELECT up.user_id, u.username, up.upload_id, up.files, up.total_size, u.email, up.download_url,
+               up.created_at, up.expires_at, up.is_reverse, up.reverse_token, $2
+        FROM uploads up
+        JOIN users u ON u.id = up.user_id
+        WHERE up.upload_id = $1
+        "#,
+    )
+    .bind(upload_id)
+    .bind(reason)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Soft-deletes an upload and drops its blob references. Returns the blobs
+/// left unreferenced, or `None` if the upload was already deleted.
+pub async fn mark_deleted(
+    conn: &mut PgConnection,
+    upload_id: &str,
+    reason: &str,
+) -> Result<Option<Vec<String>>, sqlx::Error> {
+    let deleted = sqlx::query(
+        "UPDATE uploads SET is_deleted = TRUE, deleted_at = NOW(), deletion_reason = $2 WHERE upload_id = $1 AND is_deleted = FALSE"
+    )
+    .bind(upload_id)
+    .bind(reason)
+    .execute(&mut *conn)
+    .await?;
+
+    if deleted.rows_affected() == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(blobs::release_upload(conn, upload_id).await?))
+}
+
+/// Removes what a deleted upload leaves in storage: blobs no other upload
+/// shares, and files never moved into the blob store. Call after the
+/// transaction that marked the upload deleted has committed.
+pub async fn remove_contents(pool: &PgPool, storage: &dyn Storage, upload_id: &str, unreferenced: &[String]) {
+    blobs::purge(pool, storage, unreferenced).await;
+
+    let files = match load_upload_files(pool, upload_id).await {
+        Ok(files) => files,
+        Err(e) => {
+            log::error!("Failed to load files for upload {}: {}", upload_id, e);
+            return;
+        }
+    };
+
+    for file in files.iter().filter(|f| !blobs::is_blob_key(&f.stored_name)) {
+        if let Err(e) = storage.delete(&file.stored_name).await {
+            log::warn!("Failed to delete file {}: {}", file.stored_name, e);
+        } else {
+            log::info!("Deleted file: {}", file.stored_name);
+        }
+    }
+}
+
+/// Retires a share whose last allowed download has been served, following
+/// the configured expiration action just like an expired share.
+pub async fn apply_download_limit(pool: &PgPool, storage: &dyn Storage, upload_id: &str) {
+    if let Err(e) = retire_share(pool, storage, upload_id).await {
+        log::error!("Failed to retire upload {} after its download limit: {}", upload_id, e);
+    }
+}
+
+async fn retire_share(pool: &PgPool, storage: &dyn Storage, upload_id: &str) -> Result<(), sqlx::Error> {
+    const REASON: &str = "Download limit reached";
+
+    let settings: Settings = sqlx::query_as("SELECT * FROM settings ORDER BY id LIMIT 1")
+        .fetch_optional(pool)
+        .await?
+        .unwrap_or_default();
+
+    let mut tx = pool.begin().await?;
+
+    if settings.expiration_action == "delete" {
+        log_deletion(&mut tx, upload_id, REASON).await?;
+        let unreferenced = match mark_deleted(&mut tx, upload_id, REASON).await? {
+            Some(unreferenced) => unreferenced,
+            None => return Ok(()),
+        };
+        tx.commit().await?;
+
+        remove_contents(pool, storage, upload_id, &unreferenced).await;
+        log::info!("Deleted upload {}: download limit reached", upload_id);
+        webhooks::notify(pool, webhooks::UPLOAD_EXPIRED, upload_id, serde_json::json!({
+            "reason": REASON
+        }));
+    } else {
+        let updated = sqlx::query("UPDATE uploads SET is_available = FALSE WHERE upload_id = $1 AND is_available = TRUE")
+            .bind(upload_id)
+            .execute(&mut *tx)
+            .await?;
+
+        if updated.rows_affected() == 0 {
+            return Ok(());
+        }
+
+        log_deletion(&mut tx, upload_id, REASON).await?;
+        tx.commit().await?;
+
+        log::info!("Marked upload {} unavailable: download limit reached", upload_id);
+        webhooks::notify(pool, webhooks::UPLOAD_EXPIRED, upload_id, serde_json::json!({
+            "reason": REASON
+        }));
+    }
+
+    Ok(())
+}
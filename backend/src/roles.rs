@@ -0,0 +1,157 @@
+use sqlx::PgPool;
+use std::sync::Mutex;
+
+use crate::api_tokens::hash_token;
+
+pub const OWNER: &str = "owner";
+pub const ADMIN: &str = "admin";
+pub const MODERATOR: &str = "moderator";
+pub const USER: &str = "user";
+pub const GUEST: &str = "guest";
+
+/// Every role, most privileged first.
+pub const ROLES: &[&str] = &[OWNER, ADMIN, MODERATOR, USER, GUEST];
+
+/// What a route needs from the caller's role.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Site settings, mail templates, invites and global webhooks.
+    ManageSettings,
+    /// Listing, blocking and unlocking users, their sessions and quotas.
+    ManageUsers,
+    /// Giving users a role below one's own.
+    ManageRoles,
+    /// Disabling and deleting other users' shares.
+    ModerateShares,
+    ViewDeletionLogs,
+    ViewAuditLog,
+    ViewStats,
+    /// Uploading and creating reverse share links; guests are read-only.
+    CreateShares,
+}
+
+impl Permission {
+    pub const ALL: &'static [Permission] = &[
+        Permission::ManageSettings,
+        Permission::ManageUsers,
+        Permission::ManageRoles,
+        Permission::ModerateShares,
+        Permission::ViewDeletionLogs,
+        Permission::ViewAuditLog,
+        Permission::ViewStats,
+        Permission::CreateShares,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Permission::ManageSettings => "manage_settings",
+            Permission::ManageUsers => "manage_users",
+            Permission::ManageRoles => "manage_roles",
+            Permission::ModerateShares => "moderate_shares",
+            Permission::ViewDeletionLogs => "view_deletion_logs",
+            Permission::ViewAuditLog => "view_audit_log",
+            Permission::ViewStats => "view_stats",
+            Permission::CreateShares => "create_shares",
+        }
+    }
+}
+
+pub fn is_valid(role: &str) -> bool {
+    ROLES.contains(&role)
+}
+
+/// Owners and admins; these are the accounts the admin 2FA requirement
+/// applies to.
+pub fn is_admin(role: &str) -> bool {
+    role == OWNER || role == ADMIN
+}
+
+/// Higher outranks lower. Users only manage users they outrank.
+pub fn rank(role: &str) -> usize {
+    ROLES.iter().rev().position(|r| *r == role).unwrap_or(0)
+}
+
+pub fn grants(role: &str, permission: Permission) -> bool {
+    match role {
+        OWNER | ADMIN => true,
+        MODERATOR => matches!(
+            permission,
+            Permission::ModerateShares | Permission::ViewDeletionLogs | Permission::CreateShares
+        ),
+        USER => permission == Permission::CreateShares,
+        _ => false,
+    }
+}
+
+pub fn permissions(role: &str) -> Vec<&'static str> {
+    Permission::ALL
+        .iter()
+        .filter(|p| grants(role, **p))
+        .map(|p| p.name())
+        .collect()
+}
+
+/// The role a user acts with: their own, except that owners and admins
+/// without 2FA act as plain users while the settings require it.
+pub async fn effective_role(pool: &PgPool, user_id: i32) -> Result<String, sqlx::Error> {
+    let (role,): (String,) = sqlx::query_as(
+        r#"
+        SELECT CASE
+            WHEN role IN ('owner', 'admin') AND NOT totp_enabled
+                 AND COALESCE((SELECT require_admin_two_factor FROM settings ORDER BY id LIMIT 1), FALSE)
+            THEN 'user'
+            ELSE role
+        END
+        FROM users WHERE id = $1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(role)
+}
+
+/// Whether a user's role, as it currently applies, is above another role.
+pub async fn outranks(pool: &PgPool, user_id: i32, role: &str) -> Result<bool, sqlx::Error> {
+    let own = effective_role(pool, user_id).await?;
+    Ok(rank(&own) > rank(role))
+}
+
+/// A one-time token that lets a logged-in user claim ownership of an
+/// instance that has no owner, such as a database from before roles where
+/// nobody was an admin. It only lives in memory and is printed at startup.
+#[derive(Default)]
+pub struct Bootstrap {
+    token_hash: Mutex<Option<String>>,
+}
+
+impl Bootstrap {
+    /// Returns a fresh token if the instance has users but no owner.
+    pub async fn prepare(&self, pool: &PgPool) -> Result<Option<String>, sqlx::Error> {
+        let (needed,): (bool,) = sqlx::query_as(
+            "SELECT EXISTS(SELECT 1 FROM users) AND NOT EXISTS(SELECT 1 FROM users WHERE role = 'owner')"
+        )
+        .fetch_one(pool)
+        .await?;
+
+        if !needed {
+            return Ok(None);
+        }
+
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        *self.token_hash.lock().unwrap_or_else(|e| e.into_inner()) = Some(hash_token(&token));
+        Ok(Some(token))
+    }
+
+    /// Uses up the token if it matches.
+    pub fn redeem(&self, token: &str) -> bool {
+        let mut token_hash = self.token_hash.lock().unwrap_or_else(|e| e.into_inner());
+        if token_hash.as_deref() == Some(hash_token(token).as_str()) {
+            *token_hash = None;
+            true
+        } else {
+            false
+        }
+    }
+}
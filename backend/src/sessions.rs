@@ -0,0 +1,233 @@
+use actix_web::HttpRequest;
+use chrono::{Duration, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::api_tokens::hash_token;
+use crate::auth::generate_jwt;
+use crate::config::Config;
+use crate::utils;
+
+/// The login session that authenticated the current request, stored in the
+/// request extensions by `middleware::Authentication`.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedSession {
+    pub user_id: i32,
+    pub session_id: String,
+}
+
+/// Credentials for a session: a short-lived JWT and the single-use token
+/// that gets the next pair.
+pub struct SessionTokens {
+    pub access_token: String,
+    pub refresh_token: String,
+}
+
+/// Stores a new refresh token for a session and returns it.
+async fn issue_refresh_token(
+    tx: &mut Transaction<'_, Postgres>,
+    session_id: &str,
+) -> Result<String, sqlx::Error> {
+    let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+
+    sqlx::query("INSERT INTO refresh_tokens (token_hash, session_id) VALUES ($1, $2)")
+        .bind(hash_token(&token))
+        .bind(session_id)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(token)
+}
+
+/// Records a new session for a user who just logged in and returns its
+/// first tokens.
+pub async fn start(
+    pool: &PgPool,
+    config: &Config,
+    user_id: i32,
+    req: &HttpRequest,
+) -> Result<SessionTokens, Box<dyn std::error::Error>> {
+    let session_id = Uuid::new_v4().to_string();
+    let expires_at = Utc::now() + Duration::days(config.refresh_token_days);
+
+    let ip_address = utils::client_ip(req);
+    let user_agent = req
+        .headers()
+        .get(actix_web::http::header::USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_string);
+
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO sessions (id, user_id, expires_at, ip_address, user_agent)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+    )
+    .bind(&session_id)
+    .bind(user_id)
+    .bind(expires_at)
+    .bind(&ip_address)
+    .bind(&user_agent)
+    .execute(&mut *tx)
+    .await?;
+
+    let refresh_token = issue_refresh_token(&mut tx, &session_id).await?;
+    tx.commit().await?;
+
+    Ok(SessionTokens {
+        access_token: generate_jwt(user_id, &session_id, &config.jwt_secret, config.access_token_minutes)?,
+        refresh_token,
+    })
+}
+
+/// Trades a refresh token for a new pair and extends the session. Each
+/// refresh token works once: presenting one that was already used means it
+/// leaked, so the whole session is revoked. Returns `None` if the token is
+/// not accepted.
+pub async fn refresh(
+    pool: &PgPool,
+    config: &Config,
+    refresh_token: &str,
+) -> Result<Option<SessionTokens>, Box<dyn std::error::Error>> {
+    let mut tx = pool.begin().await?;
+
+    let found: Option<(String, i32, bool)> = sqlx::query_as(
+        r#"
+        SELECT s.id, s.user_id, r.used_at IS NOT NULL
+        FROM refresh_tokens r
+        JOIN sessions s ON s.id = r.session_id
+        JOIN users u ON u.id = s.user_id
+        WHERE r.token_hash = $1 AND s.revoked_at IS NULL AND s.expires_at > NOW()
+          AND u.is_blocked IS NOT TRUE
+        FOR UPDATE OF r
+        "#,
+    )
+    .bind(hash_token(refresh_token))
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some((session_id, user_id, already_used)) = found else {
+        return Ok(None);
+    };
+
+    if already_used {
+        sqlx::query("UPDATE sessions SET revoked_at = NOW() WHERE id = $1")
+            .bind(&session_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        log::warn!(
+            "Refresh token reused for session {} of user {}, session revoked",
+            session_id,
+            user_id
+        );
+        return Ok(None);
+    }
+
+    sqlx::query("UPDATE refresh_tokens SET used_at = NOW() WHERE token_hash = $1")
+        .bind(hash_token(refresh_token))
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("UPDATE sessions SET expires_at = $2, last_seen_at = NOW() WHERE id = $1")
+        .bind(&session_id)
+        .bind(Utc::now() + Duration::days(config.refresh_token_days))
+        .execute(&mut *tx)
+        .await?;
+
+    let refresh_token = issue_refresh_token(&mut tx, &session_id).await?;
+    tx.commit().await?;
+
+    Ok(Some(SessionTokens {
+        access_token: generate_jwt(user_id, &session_id, &config.jwt_secret, config.access_token_minutes)?,
+        refresh_token,
+    }))
+}
+
+/// Whether a session is still live, i.e. neither revoked nor expired. Its
+/// last activity is refreshed at most once a minute.
+pub async fn is_active(pool: &PgPool, session_id: &str, user_id: i32) -> Result<bool, sqlx::Error> {
+    let stale: Option<(bool,)> = sqlx::query_as(
+        r#"
+        SELECT last_seen_at < NOW() - INTERVAL '1 minute'
+        FROM sessions
+        WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL AND expires_at > NOW()
+        "#,
+    )
+    .bind(session_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    let Some((stale,)) = stale else {
+        return Ok(false);
+    };
+
+    if stale {
+        sqlx::query("UPDATE sessions SET last_seen_at = NOW() WHERE id = $1")
+            .bind(session_id)
+            .execute(pool)
+            .await?;
+    }
+
+    Ok(true)
+}
+
+/// Revokes one of a user's sessions. Returns false if there was no such live
+/// session.
+pub async fn revoke(pool: &PgPool, user_id: i32, session_id: &str) -> Result<bool, sqlx::Error> {
+    let revoked = sqlx::query(
+        "UPDATE sessions SET revoked_at = NOW() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL"
+    )
+    .bind(session_id)
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+
+    Ok(revoked.rows_affected() > 0)
+}
+
+/// Revokes the session a refresh token belongs to, for logging out once the
+/// access token has already expired.
+pub async fn revoke_by_refresh_token(pool: &PgPool, refresh_token: &str) -> Result<bool, sqlx::Error> {
+    let revoked = sqlx::query(
+        r#"
+        UPDATE sessions SET revoked_at = NOW()
+        WHERE revoked_at IS NULL
+          AND id = (SELECT session_id FROM refresh_tokens WHERE token_hash = $1)
+        "#,
+    )
+    .bind(hash_token(refresh_token))
+    .execute(pool)
+    .await?;
+
+    Ok(revoked.rows_affected() > 0)
+}
+
+/// Ends every session of a user; returns how many were live.
+pub async fn revoke_all(pool: &PgPool, user_id: i32) -> Result<u64, sqlx::Error> {
+    let revoked = sqlx::query(
+        "UPDATE sessions SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > NOW()"
+    )
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+
+    Ok(revoked.rows_affected())
+}
+
+/// Drops sessions that ended more than 30 days ago.
+pub async fn prune(pool: &PgPool) {
+    let pruned = sqlx::query(
+        "DELETE FROM sessions WHERE COALESCE(revoked_at, expires_at) < NOW() - INTERVAL '30 days'"
+    )
+    .execute(pool)
+    .await;
+
+    if let Err(e) = pruned {
+        log::error!("Failed to prune sessions: {}", e);
+    }
+}
@@ -0,0 +1,190 @@
+use actix_multipart::Field;
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
+use sqlx::PgConnection;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+use crate::blobs::{self, blob_key};
+use crate::files::{guess_mime_type, NewUploadFile};
+use crate::storage::{self, Storage};
+
+pub const STAGING_DIR: &str = "./uploads/.tmp";
+
+#[derive(Debug)]
+pub enum StageError {
+    /// The running total of the request went over the allowed size.
+    LimitExceeded { limit: i64 },
+    /// The running total went over what is left of the user's quota.
+    QuotaExceeded { remaining: i64 },
+    Payload(actix_multipart::MultipartError),
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for StageError {
+    fn from(e: std::io::Error) -> Self {
+        StageError::Io(e)
+    }
+}
+
+pub struct StagedFile {
+    pub original_name: String,
+    pub size: i64,
+    pub mime_type: String,
+    pub sha256: String,
+    temp_path: PathBuf,
+}
+
+/// Collects the files of a single upload request in a private temporary
+/// directory. Nothing is handed to the storage backend until `persist` is
+/// called; if the staging is dropped before that (request error, client
+/// disconnect, validation failure) the temporary files are removed.
+pub struct UploadStaging {
+    upload_id: String,
+    dir: PathBuf,
+    files: Vec<StagedFile>,
+    total_size: i64,
+    max_size: i64,
+    quota_remaining: Option<i64>,
+    persisted: bool,
+}
+
+impl UploadStaging {
+    pub async fn new(upload_id: &str, max_size: i64, quota_remaining: Option<i64>) -> std::io::Result<Self> {
+        let dir = Path::new(STAGING_DIR).join(upload_id);
+        tokio::fs::create_dir_all(&dir).await?;
+
+        Ok(Self {
+            upload_id: upload_id.to_string(),
+            dir,
+            files: Vec::new(),
+            total_size: 0,
+            max_size,
+            quota_remaining,
+            persisted: false,
+        })
+    }
+
+    /// Streams a multipart field to disk chunk by chunk, failing as soon as the
+    /// request total goes over `max_size` or the remaining quota.
+    pub async fn stage_field(
+        &mut self,
+        field: &mut Field,
+        original_name: String,
+    ) -> Result<(), StageError> {
+        let temp_path = self.dir.join(format!("{}.part", self.files.len()));
+        let mut file = tokio::fs::File::create(&temp_path).await?;
+        let mut hasher = Sha256::new();
+        let mut size: i64 = 0;
+
+        while let Some(chunk) = field.next().await {
+            let data = chunk.map_err(StageError::Payload)?;
+            size += data.len() as i64;
+
+            if self.total_size + size > self.max_size {
+                return Err(StageError::LimitExceeded { limit: self.max_size });
+            }
+
+            if let Some(remaining) = self.quota_remaining {
+                if self.total_size + size > remaining {
+                    return Err(StageError::QuotaExceeded { remaining });
+                }
+            }
+
+            hasher.update(&data);
+            file.write_all(&data).await?;
+        }
+
+        file.flush().await?;
+
+        self.total_size += size;
+        self.files.push(StagedFile {
+            mime_type: guess_mime_type(&original_name),
+            original_name,
+            size,
+            sha256: hex::encode(hasher.finalize()),
+            temp_path,
+        });
+
+        Ok(())
+    }
+
+    pub fn total_size(&self) -> i64 {
+        self.total_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn original_names(&self) -> Vec<String> {
+        self.files.iter().map(|f| f.original_name.clone()).collect()
+    }
+
+    /// The `upload_files` rows describing the staged files, in upload order.
+    pub fn file_records(&self) -> Vec<NewUploadFile> {
+        self.files
+            .iter()
+            .map(|f| NewUploadFile {
+                original_name: f.original_name.clone(),
+                stored_name: blob_key(&f.sha256),
+                size: f.size,
+                mime_type: f.mime_type.clone(),
+                sha256: Some(f.sha256.clone()),
+            })
+            .collect()
+    }
+
+    /// Adds every staged file to the blob store as part of the transaction on
+    /// `conn`, writing only content the store does not have yet. Returns the
+    /// keys of the objects written; if any file fails those are removed again.
+    pub async fn persist(
+        mut self,
+        conn: &mut PgConnection,
+        storage: &dyn Storage,
+    ) -> std::io::Result<Vec<String>> {
+        let mut stored = Vec::with_capacity(self.files.len());
+
+        for file in &self.files {
+            match blobs::store(conn, storage, &file.sha256, file.size, &file.temp_path).await {
+                Ok(Some(key)) => stored.push(key),
+                Ok(None) => {}
+                Err(e) => {
+                    storage::discard(storage, &stored).await;
+                    return Err(e);
+                }
+            }
+        }
+
+        self.persisted = true;
+        tokio::fs::remove_dir_all(&self.dir).await.ok();
+
+        Ok(stored)
+    }
+}
+
+impl Drop for UploadStaging {
+    fn drop(&mut self) {
+        if self.persisted {
+            return;
+        }
+
+        if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::warn!("Failed to clean up staging dir {}: {}", self.dir.display(), e);
+            }
+        } else {
+            log::info!("Discarded partial upload {}", self.upload_id);
+        }
+    }
+}
+
+/// Clears staging leftovers from a previous run that was killed mid-upload.
+pub fn purge_stale() -> std::io::Result<()> {
+    match std::fs::remove_dir_all(STAGING_DIR) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    std::fs::create_dir_all(STAGING_DIR)
+}
@@ -0,0 +1,144 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use super::{ByteRange, ObjectInfo, ObjectStream, Storage};
+
+/// Files in a directory on the local disk, `./uploads` by default.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: &str) -> std::io::Result<Self> {
+        std::fs::create_dir_all(root)?;
+        Ok(Self {
+            root: PathBuf::from(root),
+        })
+    }
+
+    fn path_for(&self, key: &str) -> std::io::Result<PathBuf> {
+        let relative = Path::new(key);
+        let is_plain = relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+
+        if key.is_empty() || !is_plain {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid storage key: {}", key),
+            ));
+        }
+
+        Ok(self.root.join(relative))
+    }
+}
+
+fn object_info(key: String, metadata: &std::fs::Metadata) -> ObjectInfo {
+    ObjectInfo {
+        key,
+        size: metadata.len(),
+        last_modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, source: &Path) -> std::io::Result<()> {
+        let target = self.path_for(key)?;
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // A rename is atomic; it only fails across filesystems, where the
+        // file is copied next to the target first and then renamed in place
+        if tokio::fs::rename(source, &target).await.is_ok() {
+            return Ok(());
+        }
+
+        let mut partial = target.clone().into_os_string();
+        partial.push(".partial");
+        tokio::fs::copy(source, &partial).await?;
+        tokio::fs::rename(&partial, &target).await?;
+        tokio::fs::remove_file(source).await.ok();
+        Ok(())
+    }
+
+    async fn get(&self, key: &str, range: Option<ByteRange>) -> std::io::Result<ObjectStream> {
+        let mut file = tokio::fs::File::open(self.path_for(key)?).await?;
+        let size = file.metadata().await?.len();
+
+        match range {
+            Some(range) => {
+                file.seek(std::io::SeekFrom::Start(range.start)).await?;
+                let length = range.length().min(size.saturating_sub(range.start));
+                Ok(ObjectStream {
+                    stream: Box::pin(ReaderStream::new(file.take(length))),
+                    length,
+                })
+            }
+            None => Ok(ObjectStream {
+                stream: Box::pin(ReaderStream::new(file)),
+                length: size,
+            }),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> std::io::Result<()> {
+        match tokio::fs::remove_file(self.path_for(key)?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> std::io::Result<Vec<ObjectInfo>> {
+        let root = self.root.clone();
+        let prefix = prefix.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            let mut objects = Vec::new();
+            let mut pending = vec![root.clone()];
+
+            while let Some(dir) = pending.pop() {
+                for entry in std::fs::read_dir(&dir)?.flatten() {
+                    // Dot entries are scratch space (staging, tus sessions)
+                    if entry.file_name().to_string_lossy().starts_with('.') {
+                        continue;
+                    }
+
+                    let metadata = entry.metadata()?;
+                    if metadata.is_dir() {
+                        pending.push(entry.path());
+                        continue;
+                    }
+
+                    let key = entry
+                        .path()
+                        .strip_prefix(&root)
+                        .map(|p| p.to_string_lossy().replace('\\', "/"))
+                        .unwrap_or_default();
+
+                    if key.starts_with(&prefix) {
+                        objects.push(object_info(key, &metadata));
+                    }
+                }
+            }
+
+            Ok(objects)
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    }
+
+    async fn stat(&self, key: &str) -> std::io::Result<Option<ObjectInfo>> {
+        match tokio::fs::metadata(self.path_for(key)?).await {
+            Ok(metadata) if metadata.is_file() => Ok(Some(object_info(key.to_string(), &metadata))),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -0,0 +1,128 @@
+mod local;
+mod s3;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::Stream;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::config::StorageConfig;
+
+pub use local::LocalStorage;
+pub use s3::S3Storage;
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>;
+
+/// An inclusive byte range, as used by HTTP `Range` headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn length(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+pub struct ObjectStream {
+    pub stream: ByteStream,
+    /// Number of bytes the stream will yield.
+    pub length: u64,
+}
+
+/// Where uploaded file contents live. Keys are the `stored_name`s recorded in
+/// `upload_files`; all request bookkeeping (staging, tus sessions) stays on
+/// local disk and only finished files are handed to the backend.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Stores the local file at `source` under `key`. The source file is
+    /// consumed: it is moved or removed once the object has been written.
+    async fn put(&self, key: &str, source: &Path) -> std::io::Result<()>;
+
+    /// Streams an object, or the given part of it.
+    async fn get(&self, key: &str, range: Option<ByteRange>) -> std::io::Result<ObjectStream>;
+
+    /// Removes an object. Deleting a missing key is not an error.
+    async fn delete(&self, key: &str) -> std::io::Result<()>;
+
+    async fn list(&self, prefix: &str) -> std::io::Result<Vec<ObjectInfo>>;
+
+    async fn stat(&self, key: &str) -> std::io::Result<Option<ObjectInfo>>;
+}
+
+pub async fn from_config(config: &StorageConfig) -> std::io::Result<Arc<dyn Storage>> {
+    match config {
+        StorageConfig::Local { root } => Ok(Arc::new(LocalStorage::new(root)?)),
+        StorageConfig::S3(s3_config) => Ok(Arc::new(S3Storage::new(s3_config).await?)),
+    }
+}
+
+/// Removes objects that were written during a request whose database side
+/// failed afterwards.
+pub async fn discard(storage: &dyn Storage, keys: &[String]) {
+    for key in keys {
+        if let Err(e) = storage.delete(key).await {
+            log::warn!("Failed to remove object {}: {}", key, e);
+        }
+    }
+}
+
+/// Parses a single-range `Range: bytes=...` header against an object of
+/// `size` bytes. `Ok(None)` means the header should be ignored and the whole
+/// object served; `Err(())` means the range cannot be satisfied.
+pub fn parse_range_header(header: &str, size: u64) -> Result<Option<ByteRange>, ()> {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) => spec.trim(),
+        None => return Ok(None),
+    };
+
+    // Multipart range responses are not supported; fall back to the full body
+    if spec.contains(',') {
+        return Ok(None);
+    }
+
+    let (start, end) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+
+    let range = match (start.trim(), end.trim()) {
+        ("", "") => return Ok(None),
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().map_err(|_| ())?;
+            if suffix == 0 || size == 0 {
+                return Err(());
+            }
+            ByteRange {
+                start: size.saturating_sub(suffix),
+                end: size - 1,
+            }
+        }
+        (start, end) => {
+            let start: u64 = start.parse().map_err(|_| ())?;
+            let end: u64 = if end.is_empty() {
+                size.saturating_sub(1)
+            } else {
+                end.parse::<u64>().map_err(|_| ())?.min(size.saturating_sub(1))
+            };
+            if start >= size || start > end {
+                return Err(());
+            }
+            ByteRange { start, end }
+        }
+    };
+
+    Ok(Some(range))
+}
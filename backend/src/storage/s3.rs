@@ -0,0 +1,310 @@
+use async_trait::async_trait;
+use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::primitives::{ByteStream as S3ByteStream, Length};
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use chrono::{DateTime, Utc};
+use std::io::ErrorKind;
+use std::path::Path;
+use tokio_util::io::ReaderStream;
+
+use super::{ByteRange, ObjectInfo, ObjectStream, Storage};
+use crate::config::S3Config;
+
+/// Files bigger than this are sent with a multipart upload, one part of this
+/// size at a time (S3 caps single PUTs at 5 GB).
+const MULTIPART_PART_SIZE: u64 = 64 * 1024 * 1024;
+
+/// An S3-compatible bucket (AWS, MinIO, Garage, ...).
+pub struct S3Storage {
+    client: Client,
+    bucket: String,
+    prefix: String,
+}
+
+fn io_error<E>(e: E) -> std::io::Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    std::io::Error::other(e)
+}
+
+fn to_utc(timestamp: Option<&aws_sdk_s3::primitives::DateTime>) -> Option<DateTime<Utc>> {
+    timestamp.and_then(|t| DateTime::from_timestamp(t.secs(), t.subsec_nanos()))
+}
+
+impl S3Storage {
+    pub async fn new(config: &S3Config) -> std::io::Result<Self> {
+        let credentials = Credentials::new(
+            &config.access_key_id,
+            &config.secret_access_key,
+            None,
+            None,
+            "rootdrop-config",
+        );
+
+        let mut builder = aws_sdk_s3::config::Builder::new()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new(config.region.clone()))
+            .credentials_provider(credentials)
+            .force_path_style(config.force_path_style);
+
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder.endpoint_url(endpoint);
+        }
+
+        let storage = Self {
+            client: Client::from_conf(builder.build()),
+            bucket: config.bucket.clone(),
+            prefix: config.prefix.trim_matches('/').to_string(),
+        };
+
+        // Fail at startup rather than on the first upload
+        storage
+            .client
+            .head_bucket()
+            .bucket(&storage.bucket)
+            .send()
+            .await
+            .map_err(|e| {
+                std::io::Error::other(format!(
+                    "Cannot access bucket {}: {}",
+                    storage.bucket,
+                    e.into_service_error()
+                ))
+            })?;
+
+        Ok(storage)
+    }
+
+    fn object_key(&self, key: &str) -> String {
+        if self.prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}/{}", self.prefix, key)
+        }
+    }
+
+    fn strip_prefix<'a>(&self, object_key: &'a str) -> &'a str {
+        if self.prefix.is_empty() {
+            object_key
+        } else {
+            object_key
+                .strip_prefix(&self.prefix)
+                .and_then(|k| k.strip_prefix('/'))
+                .unwrap_or(object_key)
+        }
+    }
+
+    async fn put_multipart(&self, object_key: &str, source: &Path, size: u64) -> std::io::Result<()> {
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(object_key)
+            .send()
+            .await
+            .map_err(|e| io_error(e.into_service_error()))?;
+
+        let upload_id = upload
+            .upload_id()
+            .ok_or_else(|| std::io::Error::other("Missing multipart upload id"))?
+            .to_string();
+
+        let result = self.upload_parts(object_key, &upload_id, source, size).await;
+
+        if result.is_err() {
+            self.client
+                .abort_multipart_upload()
+                .bucket(&self.bucket)
+                .key(object_key)
+                .upload_id(&upload_id)
+                .send()
+                .await
+                .ok();
+        }
+
+        result
+    }
+
+    async fn upload_parts(
+        &self,
+        object_key: &str,
+        upload_id: &str,
+        source: &Path,
+        size: u64,
+    ) -> std::io::Result<()> {
+        let mut parts = Vec::new();
+        let mut offset = 0;
+        let mut part_number = 1;
+
+        while offset < size {
+            let length = MULTIPART_PART_SIZE.min(size - offset);
+            let body = S3ByteStream::read_from()
+                .path(source)
+                .offset(offset)
+                .length(Length::Exact(length))
+                .build()
+                .await
+                .map_err(io_error)?;
+
+            let part = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(object_key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(body)
+                .send()
+                .await
+                .map_err(|e| io_error(e.into_service_error()))?;
+
+            parts.push(
+                CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(part.e_tag().map(str::to_string))
+                    .build(),
+            );
+
+            offset += length;
+            part_number += 1;
+        }
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(object_key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(|e| io_error(e.into_service_error()))?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, source: &Path) -> std::io::Result<()> {
+        let object_key = self.object_key(key);
+        let size = tokio::fs::metadata(source).await?.len();
+
+        if size > MULTIPART_PART_SIZE {
+            self.put_multipart(&object_key, source, size).await?;
+        } else {
+            let body = S3ByteStream::from_path(source).await.map_err(io_error)?;
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(&object_key)
+                .body(body)
+                .send()
+                .await
+                .map_err(|e| io_error(e.into_service_error()))?;
+        }
+
+        tokio::fs::remove_file(source).await.ok();
+        Ok(())
+    }
+
+    async fn get(&self, key: &str, range: Option<ByteRange>) -> std::io::Result<ObjectStream> {
+        let mut request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key));
+
+        if let Some(range) = range {
+            request = request.range(format!("bytes={}-{}", range.start, range.end));
+        }
+
+        let output = request.send().await.map_err(|e| match e {
+            SdkError::ServiceError(ref service) if service.err().is_no_such_key() => {
+                std::io::Error::new(ErrorKind::NotFound, format!("No such object: {}", key))
+            }
+            e => io_error(e.into_service_error()),
+        })?;
+
+        let length = output.content_length().unwrap_or(0).max(0) as u64;
+
+        Ok(ObjectStream {
+            stream: Box::pin(ReaderStream::new(output.body.into_async_read())),
+            length,
+        })
+    }
+
+    async fn delete(&self, key: &str) -> std::io::Result<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .send()
+            .await
+            .map_err(|e| io_error(e.into_service_error()))?;
+
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> std::io::Result<Vec<ObjectInfo>> {
+        let mut objects = Vec::new();
+        let mut continuation: Option<String> = None;
+
+        loop {
+            let output = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(self.object_key(prefix))
+                .set_continuation_token(continuation.take())
+                .send()
+                .await
+                .map_err(|e| io_error(e.into_service_error()))?;
+
+            for object in output.contents() {
+                if let Some(object_key) = object.key() {
+                    objects.push(ObjectInfo {
+                        key: self.strip_prefix(object_key).to_string(),
+                        size: object.size().unwrap_or(0).max(0) as u64,
+                        last_modified: to_utc(object.last_modified()),
+                    });
+                }
+            }
+
+            match output.next_continuation_token() {
+                Some(token) if output.is_truncated().unwrap_or(false) => {
+                    continuation = Some(token.to_string());
+                }
+                _ => break,
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn stat(&self, key: &str) -> std::io::Result<Option<ObjectInfo>> {
+        let result = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .send()
+            .await;
+
+        match result {
+            Ok(output) => Ok(Some(ObjectInfo {
+                key: key.to_string(),
+                size: output.content_length().unwrap_or(0).max(0) as u64,
+                last_modified: to_utc(output.last_modified()),
+            })),
+            Err(SdkError::ServiceError(service)) if service.err().is_not_found() => Ok(None),
+            Err(e) => Err(io_error(e.into_service_error())),
+        }
+    }
+}
@@ -28,7 +28,6 @@ pub enum LogLevel {
     Warn,
     Error,
     Debug,
-    Trace,
 }
 
 impl LogLevel {
@@ -38,7 +37,6 @@ impl LogLevel {
             LogLevel::Warn => Color::Yellow,
             LogLevel::Error => Color::Red,
             LogLevel::Debug => Color::Magenta,
-            LogLevel::Trace => Color::Gray,
         }
     }
 
@@ -48,7 +46,6 @@ impl LogLevel {
             LogLevel::Warn => "⚠",
             LogLevel::Error => "✗",
             LogLevel::Debug => "🔍",
-            LogLevel::Trace => "→",
         }
     }
 }
@@ -121,7 +118,6 @@ pub struct App {
 #[derive(Default)]
 struct Stats {
     total_requests: u64,
-    active_connections: u64,
     total_uploads: u64,
     total_downloads: u64,
     uptime: Duration,
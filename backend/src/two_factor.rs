@@ -0,0 +1,138 @@
+use sqlx::PgPool;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::api_tokens::hash_token;
+
+/// One-time codes handed out when 2FA is enabled, for a lost authenticator.
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Codes from the previous and next 30-second step are accepted too, to
+/// allow for clock drift.
+const SKEW_STEPS: i64 = 1;
+const STEP_SECS: i64 = 30;
+
+/// New random secret, base32 encoded as authenticator apps expect it.
+pub fn generate_secret() -> String {
+    match Secret::generate_secret().to_encoded() {
+        Secret::Encoded(secret) => secret,
+        Secret::Raw(_) => unreachable!("to_encoded always returns an encoded secret"),
+    }
+}
+
+fn totp(secret: &str, issuer: &str, account: &str) -> Option<TOTP> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes().ok()?;
+
+    // ':' separates issuer and account in the provisioning URI
+    Some(TOTP::new_unchecked(
+        Algorithm::SHA1,
+        6,
+        0,
+        STEP_SECS as u64,
+        secret,
+        Some(issuer.replace(':', "")),
+        account.replace(':', ""),
+    ))
+}
+
+/// `otpauth://` URI for the QR code scanned by authenticator apps.
+pub fn provisioning_uri(secret: &str, issuer: &str, account: &str) -> Option<String> {
+    totp(secret, issuer, account).map(|t| t.get_url())
+}
+
+/// Checks a TOTP code against a secret and returns the time step it belongs
+/// to.
+fn matching_step(secret: &str, code: &str) -> Option<i64> {
+    let totp = totp(secret, "", "")?;
+    let now = chrono::Utc::now().timestamp() / STEP_SECS;
+
+    (now - SKEW_STEPS..=now + SKEW_STEPS).find(|step| totp.check(code, (step * STEP_SECS) as u64))
+}
+
+/// Checks a TOTP code for a user and remembers its time step, so that the
+/// same code cannot be used twice.
+pub async fn verify_totp(pool: &PgPool, user_id: i32, secret: &str, code: &str) -> Result<bool, sqlx::Error> {
+    let Some(step) = matching_step(secret, code.trim()) else {
+        return Ok(false);
+    };
+
+    let accepted = sqlx::query(
+        "UPDATE users SET totp_last_step = $2 WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)"
+    )
+    .bind(user_id)
+    .bind(step)
+    .execute(pool)
+    .await?;
+
+    Ok(accepted.rows_affected() > 0)
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Marks an unused recovery code as used. Returns false if the user has no
+/// such code.
+pub async fn use_recovery_code(pool: &PgPool, user_id: i32, code: &str) -> Result<bool, sqlx::Error> {
+    let used = sqlx::query(
+        "UPDATE recovery_codes SET used_at = NOW() WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL"
+    )
+    .bind(user_id)
+    .bind(hash_token(&normalize_recovery_code(code)))
+    .execute(pool)
+    .await?;
+
+    Ok(used.rows_affected() > 0)
+}
+
+/// Checks a second factor for a user with 2FA enabled: a current TOTP code
+/// or one of their recovery codes.
+pub async fn verify(pool: &PgPool, user_id: i32, code: &str) -> Result<bool, sqlx::Error> {
+    let secret: Option<(Option<String>,)> =
+        sqlx::query_as("SELECT totp_secret FROM users WHERE id = $1 AND totp_enabled = TRUE")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?;
+
+    let Some((Some(secret),)) = secret else {
+        return Ok(false);
+    };
+
+    let code = code.trim();
+    if code.len() == 6 && code.chars().all(|c| c.is_ascii_digit()) {
+        verify_totp(pool, user_id, &secret, code).await
+    } else {
+        use_recovery_code(pool, user_id, code).await
+    }
+}
+
+/// Replaces a user's recovery codes with a new set and returns them. Only
+/// their hashes are stored.
+pub async fn replace_recovery_codes(pool: &PgPool, user_id: i32) -> Result<Vec<String>, sqlx::Error> {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let raw = uuid::Uuid::new_v4().simple().to_string();
+            format!("{}-{}", &raw[..5], &raw[5..10])
+        })
+        .collect();
+
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    for code in &codes {
+        sqlx::query("INSERT INTO recovery_codes (user_id, code_hash) VALUES ($1, $2)")
+            .bind(user_id)
+            .bind(hash_token(&normalize_recovery_code(code)))
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+    Ok(codes)
+}
@@ -1,9 +1,14 @@
-use actix_web::{error::ErrorUnauthorized, Error, HttpRequest};
-use chrono::{DateTime, Utc};
+use actix_web::error::{ErrorForbidden, ErrorUnauthorized};
+use actix_web::{web, Error, HttpMessage, HttpRequest};
+use std::net::IpAddr;
+use chrono::{DateTime, Days, NaiveDate, Utc};
 use sanitize_filename::sanitize;
 
+use crate::api_tokens::{required_scope, ApiToken};
 use crate::auth::{extract_token_from_header, validate_jwt};
-use crate::config::Config;
+use crate::config::{Config, ProxyRange};
+use crate::roles::{self, Permission};
+use crate::sessions::AuthenticatedSession;
 
 pub fn sanitize_filename_safe(filename: &str) -> String {
     let sanitized = sanitize(filename);
@@ -20,6 +25,43 @@ pub fn is_allowed_file_type(filename: &str) -> bool {
     !blocked_extensions.iter().any(|ext| lowercase.ends_with(ext))
 }
 
+/// The address of the client behind a request. X-Forwarded-For is only
+/// believed when the connection comes from one of `TRUSTED_PROXIES`, and
+/// then only up to the first address that is not a trusted proxy itself, so
+/// clients cannot choose the address they are known by.
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    let peer = req.peer_addr()?.ip();
+    let trusted: &[_] = req
+        .app_data::<web::Data<Config>>()
+        .map(|config| config.trusted_proxies.as_slice())
+        .unwrap_or_default();
+    let forwarded: Vec<&str> = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    Some(forwarded_client(peer, &forwarded, trusted).to_string())
+}
+
+/// Walks X-Forwarded-For back from the connecting peer for as long as the
+/// addresses belong to trusted proxies.
+fn forwarded_client(peer: IpAddr, forwarded: &[&str], trusted: &[ProxyRange]) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|proxy| proxy.contains(ip));
+
+    let mut client = peer;
+    for address in forwarded.iter().rev() {
+        if !is_trusted(client) {
+            break;
+        }
+        match address.trim().parse() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+    }
+    client
+}
+
 pub fn calculate_expiry_time(validity: &str) -> Option<DateTime<Utc>> {
     let now = Utc::now();
     
@@ -63,6 +105,18 @@ pub fn is_validity_allowed(requested: &str, max: &str) -> bool {
     }
 }
 
+/// Parses a `from` or `to` search bound: a full timestamp, or a bare date.
+/// As an end bound, a date covers that whole day.
+pub fn parse_date_bound(value: &str, end_of_day: bool) -> Option<DateTime<Utc>> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Some(timestamp.with_timezone(&Utc));
+    }
+
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    let date = if end_of_day { date.checked_add_days(Days::new(1))? } else { date };
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc())
+}
+
 pub fn parse_size(size_str: &str) -> Result<i64, String> {
     let mut num_str = String::new();
     let mut unit_str = String::new();
@@ -84,6 +138,7 @@ pub fn parse_size(size_str: &str) -> Result<i64, String> {
         "KB" => 1024,
         "MB" => 1024 * 1024,
         "GB" => 1024 * 1024 * 1024,
+        "TB" => 1024 * 1024 * 1024 * 1024,
         "" => 1,
         _ => return Err(format!("Unsupported unit: {}", unit)),
     };
@@ -91,8 +146,40 @@ pub fn parse_size(size_str: &str) -> Result<i64, String> {
     Ok(num * multiplier)
 }
 
+/// Authenticates a request by its login session, or by a personal access
+/// token that has the scope the matched route requires. Both are resolved
+/// beforehand by `middleware::Authentication`.
 pub fn extract_user_id_from_request(req: &HttpRequest, config: &Config) -> Result<i32, Error> {
-    let token = extract_token_from_header(
+    if let Some(api_token) = req.extensions().get::<ApiToken>() {
+        let scope = req
+            .match_pattern()
+            .and_then(|pattern| required_scope(req.method(), &pattern))
+            .ok_or_else(|| ErrorForbidden("API tokens cannot be used for this request"))?;
+
+        if !api_token.has_scope(scope) {
+            return Err(ErrorForbidden(format!("API token lacks the '{}' scope", scope)));
+        }
+
+        return Ok(api_token.user_id);
+    }
+
+    if let Some(session) = req.extensions().get::<AuthenticatedSession>() {
+        return Ok(session.user_id);
+    }
+
+    // A well-formed token without a live session was logged out or revoked
+    match request_token(req) {
+        Some(token) if validate_jwt(&token, &config.jwt_secret).is_ok() => {
+            Err(ErrorUnauthorized("Session has ended, please log in again"))
+        }
+        Some(_) => Err(ErrorUnauthorized("Invalid token")),
+        None => Err(ErrorUnauthorized("No authorization token provided")),
+    }
+}
+
+/// The bearer token or `auth_token` cookie a request carries, if any.
+pub fn request_token(req: &HttpRequest) -> Option<String> {
+    extract_token_from_header(
         req.headers()
             .get(actix_web::http::header::AUTHORIZATION)
             .and_then(|h| h.to_str().ok()),
@@ -101,24 +188,16 @@ pub fn extract_user_id_from_request(req: &HttpRequest, config: &Config) -> Resul
         req.cookie("auth_token")
             .map(|c| c.value().to_string())
     })
-    .ok_or_else(|| ErrorUnauthorized("No authorization token provided"))?;
-
-    let claims = validate_jwt(&token, &config.jwt_secret)
-        .map_err(|_| ErrorUnauthorized("Invalid token"))?;
-
-    Ok(claims.user_id)
 }
 
-pub async fn check_is_admin(
+/// Whether the user's role, as it currently applies, grants a permission.
+pub async fn check_permission(
     user_id: i32,
     pool: &sqlx::PgPool,
+    permission: Permission,
 ) -> Result<bool, sqlx::Error> {
-    let result: (bool,) = sqlx::query_as("SELECT is_admin FROM users WHERE id = $1")
-        .bind(user_id)
-        .fetch_one(pool)
-        .await?;
-    
-    Ok(result.0)
+    let role = roles::effective_role(pool, user_id).await?;
+    Ok(roles::grants(&role, permission))
 }
 
 pub async fn check_is_blocked(
@@ -132,3 +211,57 @@ pub async fn check_is_blocked(
     
     Ok(result.0.unwrap_or(false))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxies(ranges: &[&str]) -> Vec<ProxyRange> {
+        ranges.iter().map(|r| ProxyRange::parse(r).unwrap()).collect()
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_without_trusted_proxies() {
+        let client = forwarded_client(ip("203.0.113.7"), &["10.0.0.1"], &[]);
+        assert_eq!(client, ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let trusted = proxies(&["172.18.0.0/16"]);
+        let client = forwarded_client(ip("203.0.113.7"), &["10.0.0.1"], &trusted);
+        assert_eq!(client, ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn client_is_the_nearest_untrusted_address() {
+        let trusted = proxies(&["172.18.0.0/16", "192.0.2.10"]);
+        // The client sent a forged first entry; the proxies appended the rest
+        let forwarded = ["1.2.3.4", " 198.51.100.23", "192.0.2.10"];
+        let client = forwarded_client(ip("172.18.0.5"), &forwarded, &trusted);
+        assert_eq!(client, ip("198.51.100.23"));
+    }
+
+    #[test]
+    fn unparsable_entries_stop_the_walk() {
+        let trusted = proxies(&["172.18.0.5"]);
+        let client = forwarded_client(ip("172.18.0.5"), &["1.2.3.4", "garbage"], &trusted);
+        assert_eq!(client, ip("172.18.0.5"));
+    }
+
+    #[test]
+    fn proxy_ranges() {
+        let range = ProxyRange::parse("10.0.0.0/8").unwrap();
+        assert!(range.contains(ip("10.200.3.4")));
+        assert!(range.contains(ip("::ffff:10.1.1.1")));
+        assert!(!range.contains(ip("11.0.0.1")));
+        assert!(ProxyRange::parse("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+        assert!(ProxyRange::parse("fd00::/8").unwrap().contains(ip("fd12::1")));
+        assert!(ProxyRange::parse("10.0.0.0/33").is_none());
+        assert!(ProxyRange::parse("proxy").is_none());
+    }
+}
@@ -0,0 +1,437 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::Url;
+use sqlx::PgPool;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+pub const UPLOAD_CREATED: &str = "upload.created";
+pub const REVERSE_UPLOAD_RECEIVED: &str = "reverse_upload.received";
+pub const UPLOAD_DOWNLOADED: &str = "upload.downloaded";
+pub const UPLOAD_EXPIRED: &str = "upload.expired";
+pub const UPLOAD_DELETED: &str = "upload.deleted";
+
+pub const EVENTS: &[&str] = &[
+    UPLOAD_CREATED,
+    REVERSE_UPLOAD_RECEIVED,
+    UPLOAD_DOWNLOADED,
+    UPLOAD_EXPIRED,
+    UPLOAD_DELETED,
+];
+
+/// Attempts before a delivery is given up on.
+const MAX_ATTEMPTS: i32 = 6;
+/// Wait before the first retry; doubled after every failed attempt.
+const RETRY_BASE_SECS: i64 = 30;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Deliveries are also picked up on this interval, for retries and for
+/// anything queued while the worker was busy.
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+static WAKE_WORKER: Notify = Notify::const_new();
+
+/// Whether a webhook may be sent to this address. Loopback, private,
+/// link-local and other non-routable addresses are refused, so that webhooks
+/// cannot be used to reach services on the server's own network.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(mapped) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(mapped));
+            }
+            // NAT64, 64:ff9b::/96, reaches the IPv4 address in its last 32 bits
+            let segments = ip.segments();
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [.., a, b, c, d] = ip.octets();
+                return is_public(IpAddr::V4([a, b, c, d].into()));
+            }
+            let first = segments[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local, fc00::/7, and link-local, fe80::/10
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Checks that a webhook URL is http or https and that its host only
+/// resolves to public addresses. Done when a webhook is saved and again
+/// before every delivery.
+pub async fn check_url(url: &str) -> Result<(), String> {
+    let parsed = Url::parse(url).map_err(|e| format!("Invalid webhook URL: {}", e))?;
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        return Err("Webhook URL must use http or https".to_string());
+    }
+
+    let port = parsed.port_or_known_default().unwrap_or(80);
+    let host = match parsed.host_str() {
+        Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => return Err("Webhook URL must have a host".to_string()),
+    };
+    let addresses: Vec<IpAddr> = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| format!("Cannot resolve {}: {}", host, e))?
+            .map(|addr| addr.ip())
+            .collect(),
+    };
+
+    if addresses.is_empty() || !addresses.into_iter().all(is_public) {
+        return Err("Webhook URL must point to a public address".to_string());
+    }
+    Ok(())
+}
+
+/// Resolves host names for the delivery client, keeping only public
+/// addresses, so that a host cannot be pointed at an internal address
+/// between `check_url` and the request.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Random secret used to sign a webhook's payloads.
+pub fn generate_secret() -> String {
+    format!("whsec_{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple())
+}
+
+/// `sha256=<hex HMAC of body>`, sent as `X-RootDrop-Signature`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// (user_id, upload_id, files, total_size, download_url, created_at,
+/// expires_at, is_reverse, is_available, is_deleted)
+type UploadRow = (
+    i32,
+    String,
+    String,
+    i64,
+    String,
+    DateTime<Utc>,
+    Option<DateTime<Utc>>,
+    bool,
+    bool,
+    bool,
+);
+
+/// Queues `event` for an upload with every active webhook subscribed to it:
+/// the upload owner's own and the admin-level ones. `details` is merged into
+/// the payload's `upload` object. Runs in the background.
+pub fn notify(pool: &PgPool, event: &'static str, upload_id: &str, details: serde_json::Value) {
+    let pool = pool.clone();
+    let upload_id = upload_id.to_string();
+
+    // Also called from background tasks outside actix's local task set
+    tokio::spawn(async move {
+        if let Err(e) = enqueue(&pool, event, &upload_id, details).await {
+            log::error!("Failed to queue {} webhook for upload {}: {}", event, upload_id, e);
+        }
+    });
+}
+
+async fn enqueue(pool: &PgPool, event: &str, upload_id: &str, details: serde_json::Value) -> Result<(), sqlx::Error> {
+    let upload: Option<UploadRow> = sqlx::query_as(
+        r#"
+        SELECT user_id, upload_id, files, total_size, download_url, created_at, expires_at,
+               is_reverse, is_available, is_deleted
+        FROM uploads
+        WHERE upload_id = $1
+        "#,
+    )
+    .bind(upload_id)
+    .fetch_optional(pool)
+    .await?;
+
+    let Some((user_id, upload_id, files, total_size, download_url, created_at, expires_at, is_reverse, is_available, is_deleted)) =
+        upload
+    else {
+        return Ok(());
+    };
+
+    let mut upload = serde_json::json!({
+        "upload_id": upload_id,
+        "files": serde_json::from_str::<Vec<String>>(&files).unwrap_or_default(),
+        "total_size": total_size,
+        "download_url": download_url,
+        "created_at": created_at,
+        "expires_at": expires_at,
+        "is_reverse": is_reverse,
+        "is_available": is_available,
+        "is_deleted": is_deleted,
+    });
+    if let (Some(upload), serde_json::Value::Object(details)) = (upload.as_object_mut(), details) {
+        upload.extend(details);
+    }
+
+    let payload = serde_json::json!({
+        "event": event,
+        "occurred_at": Utc::now(),
+        "upload": upload,
+    })
+    .to_string();
+
+    let queued = sqlx::query(
+        r#"
+        INSERT INTO webhook_deliveries (webhook_id, event, payload)
+        SELECT id, $1, $2
+        FROM webhooks
+        WHERE is_active = TRUE AND $1 = ANY(events) AND (user_id = $3 OR user_id IS NULL)
+        "#,
+    )
+    .bind(event)
+    .bind(&payload)
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+
+    if queued.rows_affected() > 0 {
+        WAKE_WORKER.notify_one();
+    }
+
+    Ok(())
+}
+
+/// (delivery id, event, payload, attempts, url, secret)
+type DueDelivery = (i64, String, String, i32, String, String);
+
+/// Sends queued deliveries, retrying failures with exponential backoff until
+/// `MAX_ATTEMPTS` is reached. Every attempt's outcome is kept on the
+/// delivery row.
+pub async fn run_worker(pool: PgPool) {
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        // A redirect could lead anywhere, including to internal addresses
+        .redirect(reqwest::redirect::Policy::none())
+        .dns_resolver(Arc::new(PublicResolver))
+        .build();
+    let client = match client {
+        Ok(client) => client,
+        Err(e) => {
+            log::error!("Webhooks disabled, failed to create HTTP client: {}", e);
+            return;
+        }
+    };
+
+    loop {
+        loop {
+            match deliver_next(&pool, &client).await {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(e) => {
+                    log::error!("Webhook delivery failed: {}", e);
+                    break;
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = WAKE_WORKER.notified() => {}
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+    }
+}
+
+/// Attempts the oldest due delivery. Returns false when none is due.
+async fn deliver_next(pool: &PgPool, client: &reqwest::Client) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // Locked until the attempt is recorded, so a second worker skips it
+    let due: Option<DueDelivery> = sqlx::query_as(
+        r#"
+        SELECT d.id, d.event, d.payload, d.attempts, w.url, w.secret
+        FROM webhook_deliveries d
+        JOIN webhooks w ON w.id = d.webhook_id
+        WHERE d.status = 'pending' AND d.next_attempt_at <= NOW()
+        ORDER BY d.next_attempt_at, d.id
+        LIMIT 1
+        FOR UPDATE OF d SKIP LOCKED
+        "#,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some((id, event, payload, attempts, url, secret)) = due else {
+        return Ok(false);
+    };
+
+    let result = match check_url(&url).await {
+        Ok(()) => client
+            .post(&url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(reqwest::header::USER_AGENT, "RootDrop-Webhooks")
+            .header("X-RootDrop-Event", &event)
+            .header("X-RootDrop-Delivery", id.to_string())
+            .header("X-RootDrop-Signature", sign(&secret, payload.as_bytes()))
+            .body(payload)
+            .send()
+            .await
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e),
+    };
+
+    let (status_code, error) = match result {
+        Ok(response) if response.status().is_success() => (Some(response.status().as_u16() as i32), None),
+        Ok(response) => (
+            Some(response.status().as_u16() as i32),
+            Some(format!("Endpoint responded with {}", response.status())),
+        ),
+        Err(e) => (None, Some(e)),
+    };
+
+    let attempts = attempts + 1;
+    if error.is_none() {
+        sqlx::query(
+            r#"
+            UPDATE webhook_deliveries
+            SET status = 'delivered', attempts = $2, last_status_code = $3, last_error = NULL,
+                last_attempt_at = NOW(), delivered_at = NOW()
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .bind(attempts)
+        .bind(status_code)
+        .execute(&mut *tx)
+        .await?;
+    } else {
+        let status = if attempts >= MAX_ATTEMPTS { "failed" } else { "pending" };
+        let retry_in = RETRY_BASE_SECS << (attempts - 1).min(10);
+
+        sqlx::query(
+            r#"
+            UPDATE webhook_deliveries
+            SET status = $2, attempts = $3, last_status_code = $4, last_error = $5,
+                last_attempt_at = NOW(), next_attempt_at = NOW() + make_interval(secs => $6)
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .bind(status)
+        .bind(attempts)
+        .bind(status_code)
+        .bind(&error)
+        .bind(retry_in as f64)
+        .execute(&mut *tx)
+        .await?;
+
+        log::warn!(
+            "Webhook delivery {} to {} failed (attempt {}): {}",
+            id,
+            url,
+            attempts,
+            error.unwrap_or_default()
+        );
+    }
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Queues a delivery for another round of attempts. Returns false if the
+/// webhook has no such delivery.
+pub async fn redeliver(pool: &PgPool, webhook_id: i32, delivery_id: i64) -> Result<bool, sqlx::Error> {
+    let updated = sqlx::query(
+        "UPDATE webhook_deliveries SET status = 'pending', attempts = 0, next_attempt_at = NOW() WHERE id = $1 AND webhook_id = $2"
+    )
+    .bind(delivery_id)
+    .bind(webhook_id)
+    .execute(pool)
+    .await?;
+
+    if updated.rows_affected() > 0 {
+        WAKE_WORKER.notify_one();
+    }
+
+    Ok(updated.rows_affected() > 0)
+}
+
+/// Drops finished deliveries older than 30 days.
+pub async fn prune_deliveries(pool: &PgPool) {
+    let pruned = sqlx::query(
+        "DELETE FROM webhook_deliveries WHERE status <> 'pending' AND created_at < NOW() - INTERVAL '30 days'"
+    )
+    .execute(pool)
+    .await;
+
+    match pruned {
+        Ok(result) if result.rows_affected() > 0 => {
+            log::info!("Pruned {} old webhook deliveries", result.rows_affected());
+        }
+        Ok(_) => {}
+        Err(e) => log::error!("Failed to prune webhook deliveries: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_addresses_are_refused() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "100.64.0.1",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{} should be refused", ip);
+        }
+        for ip in ["93.184.216.34", "1.1.1.1", "2606:4700::1111", "64:ff9b::1.1.1.1"] {
+            assert!(is_public(ip.parse().unwrap()), "{} should be allowed", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn urls_to_internal_hosts_are_refused() {
+        assert!(check_url("http://127.0.0.1:8080/hook").await.is_err());
+        assert!(check_url("http://[::1]/hook").await.is_err());
+        assert!(check_url("http://localhost/hook").await.is_err());
+        assert!(check_url("ftp://93.184.216.34/hook").await.is_err());
+        assert!(check_url("https://93.184.216.34/hook").await.is_ok());
+    }
+}
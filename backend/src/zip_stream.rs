@@ -0,0 +1,367 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::io::Write;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const ZIP64_END_SIGNATURE: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+const END_SIGNATURE: u32 = 0x0605_4b50;
+
+/// Sizes and CRC follow the data; names are UTF-8.
+const FLAGS: u16 = 0x0008 | 0x0800;
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+/// Every entry has a ZIP64 extra field, see `start_entry`.
+const VERSION_ZIP64: u16 = 45;
+/// Unix host, so the external attributes below are read as a file mode.
+const VERSION_MADE_BY: u16 = (3 << 8) | VERSION_ZIP64;
+const FILE_MODE: u32 = 0o100644;
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+
+struct Entry {
+    name: String,
+    method: u16,
+    dos_time: u16,
+    dos_date: u16,
+    header_offset: u64,
+    crc: u32,
+    compressed_size: u64,
+    uncompressed_size: u64,
+}
+
+
+struct OpenEntry {
+    entry: Entry,
+    hasher: crc32fast::Hasher,
+    encoder: Option<DeflateEncoder<Vec<u8>>>,
+}
+
+/// Produces a ZIP archive as a sequence of chunks without ever seeking back,
+/// so it can be sent while it is being built. Every entry is followed by a
+/// data descriptor, and ZIP64 records are added where sizes or offsets need
+/// them.
+pub struct ZipStream {
+    offset: u64,
+    entries: Vec<Entry>,
+    current: Option<OpenEntry>,
+    /// Sizes and offsets from this one on need ZIP64 records.
+    zip64_limit: u64,
+}
+
+impl Default for ZipStream {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            entries: Vec::new(),
+            current: None,
+            zip64_limit: u32::MAX as u64,
+        }
+    }
+}
+
+fn dos_date_time(timestamp: DateTime<Utc>) -> (u16, u16) {
+    // DOS dates start in 1980
+    if timestamp.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+
+    let time = (timestamp.hour() << 11) | (timestamp.minute() << 5) | (timestamp.second() / 2);
+    let date = (((timestamp.year() - 1980) as u32).min(127) << 9) | (timestamp.month() << 5) | timestamp.day();
+    (time as u16, date as u16)
+}
+
+impl ZipStream {
+    fn emit(&mut self, buffer: BytesMut) -> Bytes {
+        self.offset += buffer.len() as u64;
+        buffer.freeze()
+    }
+
+    /// Starts a new entry and returns its local header. `compress` selects
+    /// deflate; already-compressed content is better stored as is.
+    pub fn start_entry(&mut self, name: &str, modified: DateTime<Utc>, compress: bool) -> std::io::Result<Bytes> {
+        if self.current.is_some() {
+            return Err(std::io::Error::other("Previous ZIP entry was not finished"));
+        }
+
+        let (dos_time, dos_date) = dos_date_time(modified);
+        let entry = Entry {
+            name: name.to_string(),
+            method: if compress { METHOD_DEFLATED } else { METHOD_STORED },
+            dos_time,
+            dos_date,
+            header_offset: self.offset,
+            crc: 0,
+            compressed_size: 0,
+            uncompressed_size: 0,
+        };
+
+        // The size is not known yet and may turn out to need ZIP64, so the
+        // header always has the ZIP64 extra field, with zero sizes, and the
+        // data descriptor always has 8-byte sizes to match
+        let mut header = BytesMut::with_capacity(30 + name.len() + 20);
+        header.put_u32_le(LOCAL_HEADER_SIGNATURE);
+        header.put_u16_le(VERSION_ZIP64);
+        header.put_u16_le(FLAGS);
+        header.put_u16_le(entry.method);
+        header.put_u16_le(entry.dos_time);
+        header.put_u16_le(entry.dos_date);
+        // CRC and sizes are in the data descriptor
+        header.put_u32_le(0);
+        header.put_u32_le(u32::MAX);
+        header.put_u32_le(u32::MAX);
+        header.put_u16_le(name.len() as u16);
+        header.put_u16_le(20);
+        header.put_slice(name.as_bytes());
+        header.put_u16_le(ZIP64_EXTRA_ID);
+        header.put_u16_le(16);
+        header.put_u64_le(0);
+        header.put_u64_le(0);
+
+        self.current = Some(OpenEntry {
+            entry,
+            hasher: crc32fast::Hasher::new(),
+            encoder: compress.then(|| DeflateEncoder::new(Vec::new(), Compression::default())),
+        });
+
+        Ok(self.emit(header))
+    }
+
+    /// Feeds content of the current entry and returns the archive bytes it
+    /// produced, which may be empty while the compressor is buffering.
+    pub fn write(&mut self, data: Bytes) -> std::io::Result<Bytes> {
+        let current = self
+            .current
+            .as_mut()
+            .ok_or_else(|| std::io::Error::other("No ZIP entry started"))?;
+
+        current.hasher.update(&data);
+        current.entry.uncompressed_size += data.len() as u64;
+
+        let output = match current.encoder.as_mut() {
+            Some(encoder) => {
+                encoder.write_all(&data)?;
+                Bytes::from(std::mem::take(encoder.get_mut()))
+            }
+            None => data,
+        };
+
+        current.entry.compressed_size += output.len() as u64;
+        self.offset += output.len() as u64;
+        Ok(output)
+    }
+
+    /// Ends the current entry, returning any remaining compressed data and
+    /// its data descriptor.
+    pub fn finish_entry(&mut self) -> std::io::Result<Bytes> {
+        let OpenEntry {
+            mut entry,
+            hasher,
+            encoder,
+        } = self
+            .current
+            .take()
+            .ok_or_else(|| std::io::Error::other("No ZIP entry started"))?;
+
+        let mut output = BytesMut::new();
+        if let Some(encoder) = encoder {
+            let rest = encoder.finish()?;
+            entry.compressed_size += rest.len() as u64;
+            output.put_slice(&rest);
+        }
+        entry.crc = hasher.finalize();
+
+        output.put_u32_le(DATA_DESCRIPTOR_SIGNATURE);
+        output.put_u32_le(entry.crc);
+        output.put_u64_le(entry.compressed_size);
+        output.put_u64_le(entry.uncompressed_size);
+
+        self.entries.push(entry);
+        Ok(self.emit(output))
+    }
+
+    /// Writes the central directory and end records.
+    pub fn finish(mut self) -> std::io::Result<Bytes> {
+        if self.current.is_some() {
+            return Err(std::io::Error::other("Last ZIP entry was not finished"));
+        }
+
+        let directory_offset = self.offset;
+        let mut output = BytesMut::new();
+
+        let limit = self.zip64_limit;
+        for entry in &self.entries {
+            let large_sizes = entry.compressed_size >= limit || entry.uncompressed_size >= limit;
+            let large_offset = entry.header_offset >= limit;
+
+            let mut extra = BytesMut::new();
+            if large_sizes || large_offset {
+                let mut fields = BytesMut::new();
+                if large_sizes {
+                    fields.put_u64_le(entry.uncompressed_size);
+                    fields.put_u64_le(entry.compressed_size);
+                }
+                if large_offset {
+                    fields.put_u64_le(entry.header_offset);
+                }
+                extra.put_u16_le(ZIP64_EXTRA_ID);
+                extra.put_u16_le(fields.len() as u16);
+                extra.put_slice(&fields);
+            }
+
+            output.put_u32_le(CENTRAL_HEADER_SIGNATURE);
+            output.put_u16_le(VERSION_MADE_BY);
+            output.put_u16_le(VERSION_ZIP64);
+            output.put_u16_le(FLAGS);
+            output.put_u16_le(entry.method);
+            output.put_u16_le(entry.dos_time);
+            output.put_u16_le(entry.dos_date);
+            output.put_u32_le(entry.crc);
+            if large_sizes {
+                output.put_u32_le(u32::MAX);
+                output.put_u32_le(u32::MAX);
+            } else {
+                output.put_u32_le(entry.compressed_size as u32);
+                output.put_u32_le(entry.uncompressed_size as u32);
+            }
+            output.put_u16_le(entry.name.len() as u16);
+            output.put_u16_le(extra.len() as u16);
+            output.put_u16_le(0); // comment length
+            output.put_u16_le(0); // disk number
+            output.put_u16_le(0); // internal attributes
+            output.put_u32_le(FILE_MODE << 16);
+            output.put_u32_le(if large_offset { u32::MAX } else { entry.header_offset as u32 });
+            output.put_slice(entry.name.as_bytes());
+            output.put_slice(&extra);
+        }
+
+        let directory_size = output.len() as u64;
+        let count = self.entries.len() as u64;
+
+        if count >= u16::MAX as u64 || directory_offset >= limit || directory_size >= limit {
+            let zip64_end_offset = directory_offset + directory_size;
+
+            output.put_u32_le(ZIP64_END_SIGNATURE);
+            output.put_u64_le(44); // size of the rest of this record
+            output.put_u16_le(VERSION_MADE_BY);
+            output.put_u16_le(VERSION_ZIP64);
+            output.put_u32_le(0); // this disk
+            output.put_u32_le(0); // disk with the directory
+            output.put_u64_le(count);
+            output.put_u64_le(count);
+            output.put_u64_le(directory_size);
+            output.put_u64_le(directory_offset);
+
+            output.put_u32_le(ZIP64_LOCATOR_SIGNATURE);
+            output.put_u32_le(0);
+            output.put_u64_le(zip64_end_offset);
+            output.put_u32_le(1); // total disks
+        }
+
+        output.put_u32_le(END_SIGNATURE);
+        output.put_u16_le(0);
+        output.put_u16_le(0);
+        output.put_u16_le(count.min(u16::MAX as u64) as u16);
+        output.put_u16_le(count.min(u16::MAX as u64) as u16);
+        output.put_u32_le(if directory_size >= limit { u32::MAX } else { directory_size as u32 });
+        output.put_u32_le(if directory_offset >= limit { u32::MAX } else { directory_offset as u32 });
+        output.put_u16_le(0); // comment length
+
+        Ok(self.emit(output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    fn build(mut zip: ZipStream, files: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let modified = DateTime::parse_from_rfc3339("2024-05-06T07:08:10Z").unwrap().to_utc();
+        let mut archive = Vec::new();
+        for (name, content, compress) in files {
+            archive.extend_from_slice(&zip.start_entry(name, modified, *compress).unwrap());
+            for chunk in content.chunks(1000) {
+                archive.extend_from_slice(&zip.write(Bytes::copy_from_slice(chunk)).unwrap());
+            }
+            archive.extend_from_slice(&zip.finish_entry().unwrap());
+        }
+        archive.extend_from_slice(&zip.finish().unwrap());
+        archive
+    }
+
+    fn assert_round_trip(archive: Vec<u8>, files: &[(&str, &[u8], bool)]) {
+        let mut reader = zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(reader.len(), files.len());
+        for (name, content, compress) in files {
+            let mut file = reader.by_name(name).unwrap();
+            let method = if *compress { zip::CompressionMethod::Deflated } else { zip::CompressionMethod::Stored };
+            assert_eq!(file.compression(), method);
+            assert_eq!(file.size(), content.len() as u64);
+            assert_eq!(file.unix_mode(), Some(FILE_MODE));
+            assert_eq!(file.last_modified().map(|t| (t.hour(), t.minute(), t.second())), Some((7, 8, 10)));
+            let mut read = Vec::new();
+            file.read_to_end(&mut read).unwrap();
+            assert_eq!(&read, content);
+        }
+    }
+
+    fn sample() -> Vec<u8> {
+        (0..50_000u32).flat_map(|i| (i % 251).to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn archives_read_back() {
+        let content = sample();
+        let files: &[(&str, &[u8], bool)] = &[
+            ("notes.txt", b"hello, world\n", true),
+            ("images/photo.jpg", &content, false),
+            ("data/большой.bin", &content, true),
+            ("empty", b"", true),
+        ];
+        assert_round_trip(build(ZipStream::default(), files), files);
+    }
+
+    #[test]
+    fn zip64_archives_read_back() {
+        // Every size and offset then takes the ZIP64 records that files over
+        // 4 GiB would need
+        let zip = ZipStream {
+            zip64_limit: 0,
+            ..ZipStream::default()
+        };
+        let content = sample();
+        let files: &[(&str, &[u8], bool)] = &[
+            ("first.bin", &content, true),
+            ("second.bin", &content, false),
+            ("empty", b"", false),
+        ];
+        assert_round_trip(build(zip, files), files);
+    }
+
+    #[test]
+    fn local_headers_match_the_central_directory() {
+        let archive = build(ZipStream::default(), &[("a.txt", b"abc", false)]);
+
+        // Local header: version 45, sizes deferred to the ZIP64 extra field
+        assert_eq!(&archive[0..4], &LOCAL_HEADER_SIGNATURE.to_le_bytes());
+        assert_eq!(u16::from_le_bytes([archive[4], archive[5]]), VERSION_ZIP64);
+        assert_eq!(&archive[18..26], &[0xff; 8]);
+        assert_eq!(u16::from_le_bytes([archive[28], archive[29]]), 20);
+        assert_eq!(&archive[35..39], &[0x01, 0x00, 0x10, 0x00]);
+
+        // Data descriptor after the content, with 8-byte sizes
+        let descriptor = &archive[55 + 3..];
+        assert_eq!(&descriptor[0..4], &DATA_DESCRIPTOR_SIGNATURE.to_le_bytes());
+        assert_eq!(&descriptor[8..16], &3u64.to_le_bytes());
+        assert_eq!(&descriptor[16..24], &3u64.to_le_bytes());
+
+        // Central directory entry needs the same version
+        let central = &descriptor[24..];
+        assert_eq!(&central[0..4], &CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+        assert_eq!(u16::from_le_bytes([central[6], central[7]]), VERSION_ZIP64);
+    }
+}
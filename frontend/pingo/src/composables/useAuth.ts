@@ -7,6 +7,7 @@ interface User {
   id: number
   username: string
   email: string
+  role?: string
   is_admin?: boolean
   avatar?: string
   created_at?: string
@@ -41,6 +42,35 @@ const uploads = ref<Upload[]>([])
 const adminUsers = ref<AdminUser[]>([])
 const isInitializing = ref(false)
 const initPromise = ref<Promise<boolean> | null>(null)
+let refreshPromise: Promise<boolean> | null = null
+let refreshTimer: ReturnType<typeof setTimeout> | null = null
+
+const saveAccessToken = (newToken: string, expiresIn?: number) => {
+  localStorage.setItem('auth_token', newToken)
+  token.value = newToken
+
+  // Renew a minute early so pages calling fetch() directly keep a valid token
+  if (refreshTimer) clearTimeout(refreshTimer)
+  if (expiresIn) {
+    refreshTimer = setTimeout(() => { refreshSession() }, Math.max(expiresIn - 60, 10) * 1000)
+  }
+}
+
+// Access tokens are short-lived; the refresh token travels as an HttpOnly
+// cookie. Concurrent callers share one request, since a refresh token only
+// works once.
+const refreshSession = (): Promise<boolean> => {
+  if (!refreshPromise) {
+    refreshPromise = axios.post(getApiUrl('/refresh'))
+      .then((response) => {
+        saveAccessToken(response.data.token, response.data.expires_in)
+        return true
+      })
+      .catch(() => false)
+      .finally(() => { refreshPromise = null })
+  }
+  return refreshPromise
+}
 
 // Setup axios defaults
 axios.defaults.withCredentials = true
@@ -61,7 +91,16 @@ axios.interceptors.request.use(
 // Add response interceptor to handle auth errors
 axios.interceptors.response.use(
   (response) => response,
-  (error) => {
+  async (error) => {
+    // Retry once with a fresh access token
+    const original = error.config
+    if (error.response?.status === 401 && original && !original._retried &&
+        !original.url?.includes('/login') && !original.url?.includes('/refresh') &&
+        await refreshSession()) {
+      original._retried = true
+      return axios(original)
+    }
+
     // Only clear auth state for specific 401 errors from auth endpoints
     if (error.response?.status === 401 && 
         (error.config?.url?.includes('/me') || 
@@ -91,29 +130,31 @@ export const useAuth = () => {
     }
   }
 
-  const saveTokenToStorage = (newToken: string) => {
-    localStorage.setItem('auth_token', newToken)
-    token.value = newToken
-  }
-
   const clearAuth = () => {
+    if (refreshTimer) clearTimeout(refreshTimer)
     user.value = null
     token.value = null
     uploads.value = []
     localStorage.removeItem('auth_token')
   }
 
-  const register = async (username: string, email: string, password: string) => {
+  const register = async (username: string, email: string, password: string, inviteCode?: string) => {
     try {
       isLoading.value = true
       const response = await axios.post(getApiUrl('/register'), {
         username,
         email,
-        password
+        password,
+        invite_code: inviteCode
       })
+
+      // The account stays inactive until the emailed link is opened
+      if (response.data.verification_required) {
+        return { success: true, verificationRequired: true, message: response.data.message }
+      }
       
-      const { token: newToken, user: userData } = response.data
-      saveTokenToStorage(newToken)
+      const { token: newToken, expires_in: expiresIn, user: userData } = response.data
+      saveAccessToken(newToken, expiresIn)
       user.value = userData
       
       return { success: true, message: 'Registration successful!' }
@@ -132,9 +173,19 @@ export const useAuth = () => {
         username_or_email: usernameOrEmail,
         password
       })
+
+      // Accounts with 2FA need a code before they get a session
+      if (response.data.two_factor_required) {
+        return {
+          success: false,
+          twoFactorRequired: true,
+          challengeToken: response.data.challenge_token,
+          message: 'Enter your authentication code'
+        }
+      }
       
-      const { token: newToken, user: userData } = response.data
-      saveTokenToStorage(newToken)
+      const { token: newToken, expires_in: expiresIn, user: userData } = response.data
+      saveAccessToken(newToken, expiresIn)
       user.value = userData
       
       return { success: true, message: 'Login successful!' }
@@ -146,6 +197,27 @@ export const useAuth = () => {
     }
   }
 
+  const completeTwoFactorLogin = async (challengeToken: string, code: string) => {
+    try {
+      isLoading.value = true
+      const response = await axios.post(getApiUrl('/login/2fa'), {
+        challenge_token: challengeToken,
+        code
+      })
+
+      const { token: newToken, expires_in: expiresIn, user: userData } = response.data
+      saveAccessToken(newToken, expiresIn)
+      user.value = userData
+
+      return { success: true, message: 'Login successful!' }
+    } catch (error: any) {
+      const message = error.response?.data?.error || 'Invalid authentication code'
+      return { success: false, message }
+    } finally {
+      isLoading.value = false
+    }
+  }
+
   const logout = async () => {
     try {
       await axios.post(getApiUrl('/logout'))
@@ -166,8 +238,9 @@ export const useAuth = () => {
       return initPromise.value
     }
 
-    // If no token, don't make a request
-    if (!token.value) {
+    // Without a token only a refresh cookie can help, e.g. right after a
+    // single sign-on login, which hands out cookies but no token
+    if (!token.value && !(await refreshSession())) {
       return false
     }
 
@@ -175,6 +248,8 @@ export const useAuth = () => {
     initPromise.value = (async () => {
       try {
         isInitializing.value = true
+        // The stored access token has likely expired since the last visit
+        await refreshSession()
         const response = await axios.get(getApiUrl('/me'))
         user.value = response.data.user
         return true
@@ -245,6 +320,15 @@ export const useAuth = () => {
     }
   }
 
+  const getAdminSettings = async () => {
+    try {
+      const response = await axios.get(getApiUrl('/admin/settings'))
+      return response.data
+    } catch (error: any) {
+      throw new Error(error.response?.data?.error || 'Failed to fetch settings')
+    }
+  }
+
   const fetchAdminUsers = async () => {
     try {
       isLoading.value = true
@@ -256,6 +340,7 @@ export const useAuth = () => {
         username: u.username,
         email: u.email,
         avatar: u.avatar,
+        role: u.role,
         is_admin: u.is_admin,
         isBlocked: u.is_blocked ?? false,
         uploadCount: Number(u.upload_count || 0),
@@ -288,17 +373,18 @@ export const useAuth = () => {
     }
   }
 
-  const promoteUser = async (userId: number, promote: boolean) => {
+  const setUserRole = async (userId: number, role: string) => {
     try {
-      await axios.post(getApiUrl(`/admin/users/${userId}/promote`), { promote })
+      const response = await axios.put(getApiUrl(`/admin/users/${userId}/role`), { role })
       // Update local state
       const userIndex = adminUsers.value.findIndex(u => u.id === userId)
       if (userIndex !== -1) {
-        adminUsers.value[userIndex].is_admin = promote
+        adminUsers.value[userIndex].role = role
+        adminUsers.value[userIndex].is_admin = role === 'owner' || role === 'admin'
       }
-      return { success: true, message: `User ${promote ? 'promoted to admin' : 'demoted from admin'} successfully` }
+      return { success: true, message: response.data.message }
     } catch (error: any) {
-      const message = error.response?.data?.error || 'Failed to update user admin status'
+      const message = error.response?.data?.error || 'Failed to update user role'
       return { success: false, message }
     }
   }
@@ -316,14 +402,16 @@ export const useAuth = () => {
     isLoading: computed(() => isLoading.value),
     register,
     login,
+    completeTwoFactorLogin,
     logout,
     fetchCurrentUser,
     fetchUploads,
     deleteUpload,
     saveAdminSettings,
     getSettings,
+    getAdminSettings,
     fetchAdminUsers,
     toggleUserBlock,
-    promoteUser
+    setUserRole
   }
 }
@@ -35,6 +35,18 @@ const routes = [
     component: AuthPage,
     meta: { requiresGuest: true }
   },
+  // Password reset, reached from the emailed link (public)
+  {
+    path: '/reset-password',
+    name: 'ResetPassword',
+    component: () => import('../pages/auth/ResetPassword.vue'),
+  },
+  // Email verification, reached from the link mailed at registration (public)
+  {
+    path: '/verify-email',
+    name: 'VerifyEmail',
+    component: () => import('../pages/auth/VerifyEmail.vue'),
+  },
   // Redirect all unknown routes to auth
   {
     path: '/:pathMatch(.*)*',